@@ -0,0 +1,206 @@
+// NSS module resolving the organization members ghauthd serves on its NSS socket, enabled with
+// `passwd: files github` in /etc/nsswitch.conf. Users come and go with the organization, so
+// nothing is cached here and every lookup asks ghauthd
+use std::ffi::CStr;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::ptr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use libc::{c_char, c_int, passwd, size_t, uid_t};
+
+// enum nss_status
+const NSS_STATUS_TRYAGAIN: c_int = -2;
+const NSS_STATUS_UNAVAIL: c_int = -1;
+const NSS_STATUS_NOTFOUND: c_int = 0;
+const NSS_STATUS_SUCCESS: c_int = 1;
+
+const SOCKET: &str = "/run/ssh_github_auth/nss.sock";
+// Every process resolving users waits for this when ghauthd hangs
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+struct Entry {
+    name: String,
+    uid: uid_t,
+    gid: u32,
+    gecos: String,
+    dir: String,
+    shell: String,
+}
+
+impl Entry {
+    fn parse(line: &str) -> Option<Self> {
+        let fields: Vec<&str> = line.split(':').collect();
+        let [name, _, uid, gid, gecos, dir, shell] = fields[..] else {
+            return None;
+        };
+        Some(Entry {
+            name: name.to_string(),
+            uid: uid.parse().ok()?,
+            gid: gid.parse().ok()?,
+            gecos: gecos.to_string(),
+            dir: dir.to_string(),
+            shell: shell.to_string(),
+        })
+    }
+
+    // Copies the strings into the caller's buffer, None when it is too small
+    unsafe fn fill(&self, pwd: *mut passwd, buf: *mut c_char, buflen: size_t) -> Option<()> {
+        let mut used = 0;
+        let mut copy = |value: &str| -> Option<*mut c_char> {
+            let len = value.len() + 1;
+            if value.contains('\0') || used + len > buflen {
+                return None;
+            }
+            unsafe {
+                let dest = buf.add(used);
+                ptr::copy_nonoverlapping(value.as_ptr() as *const c_char, dest, value.len());
+                *dest.add(value.len()) = 0;
+                used += len;
+                Some(dest)
+            }
+        };
+        let name = copy(&self.name)?;
+        let password = copy("x")?;
+        let gecos = copy(&self.gecos)?;
+        let dir = copy(&self.dir)?;
+        let shell = copy(&self.shell)?;
+        unsafe {
+            (*pwd).pw_name = name;
+            (*pwd).pw_passwd = password;
+            (*pwd).pw_uid = self.uid;
+            (*pwd).pw_gid = self.gid;
+            (*pwd).pw_gecos = gecos;
+            (*pwd).pw_dir = dir;
+            (*pwd).pw_shell = shell;
+        }
+        Some(())
+    }
+}
+
+// Sends one request to ghauthd and reads the passwd lines up to the empty line ending the reply
+fn query(request: &str) -> Option<Vec<Entry>> {
+    let stream = UnixStream::connect(SOCKET).ok()?;
+    stream.set_read_timeout(Some(TIMEOUT)).ok()?;
+    stream.set_write_timeout(Some(TIMEOUT)).ok()?;
+    (&stream).write_all(format!("{}\n", request).as_bytes()).ok()?;
+
+    let mut entries = Vec::new();
+    for line in BufReader::new(stream).lines() {
+        let line = line.ok()?;
+        if line.is_empty() {
+            return Some(entries);
+        }
+        entries.extend(Entry::parse(&line));
+    }
+    // The connection closed before the reply was complete
+    None
+}
+
+unsafe fn lookup(request: &str, pwd: *mut passwd, buf: *mut c_char, buflen: size_t, errnop: *mut c_int) -> c_int {
+    let Some(entries) = query(request) else {
+        return NSS_STATUS_UNAVAIL;
+    };
+    let Some(entry) = entries.first() else {
+        unsafe { *errnop = libc::ENOENT };
+        return NSS_STATUS_NOTFOUND;
+    };
+    match unsafe { entry.fill(pwd, buf, buflen) } {
+        Some(()) => NSS_STATUS_SUCCESS,
+        None => {
+            unsafe { *errnop = libc::ERANGE };
+            NSS_STATUS_TRYAGAIN
+        }
+    }
+}
+
+/// # Safety
+/// Called by glibc with a valid name, result, buffer and errno
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn _nss_github_getpwnam_r(
+    name: *const c_char,
+    pwd: *mut passwd,
+    buf: *mut c_char,
+    buflen: size_t,
+    errnop: *mut c_int,
+) -> c_int {
+    let name = unsafe { CStr::from_ptr(name) }.to_string_lossy();
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return NSS_STATUS_NOTFOUND;
+    }
+    unsafe { lookup(&format!("name {}", name), pwd, buf, buflen, errnop) }
+}
+
+/// # Safety
+/// Called by glibc with a valid result, buffer and errno
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn _nss_github_getpwuid_r(
+    uid: uid_t,
+    pwd: *mut passwd,
+    buf: *mut c_char,
+    buflen: size_t,
+    errnop: *mut c_int,
+) -> c_int {
+    unsafe { lookup(&format!("uid {}", uid), pwd, buf, buflen, errnop) }
+}
+
+// Entries of a getpwent() enumeration and the position in it
+static ENUMERATION: Mutex<Option<(Vec<Entry>, usize)>> = Mutex::new(None);
+
+#[unsafe(no_mangle)]
+pub extern "C" fn _nss_github_setpwent(_stayopen: c_int) -> c_int {
+    let Ok(mut enumeration) = ENUMERATION.lock() else {
+        return NSS_STATUS_UNAVAIL;
+    };
+    match query("list") {
+        Some(entries) => {
+            *enumeration = Some((entries, 0));
+            NSS_STATUS_SUCCESS
+        }
+        None => {
+            *enumeration = None;
+            NSS_STATUS_UNAVAIL
+        }
+    }
+}
+
+/// # Safety
+/// Called by glibc with a valid result, buffer and errno
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn _nss_github_getpwent_r(
+    pwd: *mut passwd,
+    buf: *mut c_char,
+    buflen: size_t,
+    errnop: *mut c_int,
+) -> c_int {
+    let Ok(mut enumeration) = ENUMERATION.lock() else {
+        return NSS_STATUS_UNAVAIL;
+    };
+    let Some((entries, next)) = enumeration.as_mut() else {
+        return NSS_STATUS_UNAVAIL;
+    };
+    let Some(entry) = entries.get(*next) else {
+        unsafe { *errnop = libc::ENOENT };
+        return NSS_STATUS_NOTFOUND;
+    };
+    // The same entry is returned again once the caller retries with a larger buffer
+    match unsafe { entry.fill(pwd, buf, buflen) } {
+        Some(()) => {
+            *next += 1;
+            NSS_STATUS_SUCCESS
+        }
+        None => {
+            unsafe { *errnop = libc::ERANGE };
+            NSS_STATUS_TRYAGAIN
+        }
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn _nss_github_endpwent() -> c_int {
+    if let Ok(mut enumeration) = ENUMERATION.lock() {
+        *enumeration = None;
+    }
+    NSS_STATUS_SUCCESS
+}
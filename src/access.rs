@@ -0,0 +1,352 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::net::IpAddr;
+
+use chrono::{Datelike, NaiveDateTime, Timelike};
+
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+// Days and time of day logins are allowed, e.g. `mon-fri:08:00-20:00`. A window ending before
+// it starts runs past midnight, e.g. `sat:22:00-06:00`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Window {
+    days: [bool; 7],
+    // Minutes since midnight, the end is excluded and may be 24:00
+    start: u32,
+    end: u32,
+    text: String,
+}
+
+impl Window {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid access hours: {}", value);
+        let (days, times) = value.split_once(':').ok_or_else(invalid)?;
+        let (start, end) = times.split_once('-').ok_or_else(invalid)?;
+        Ok(Window {
+            days: parse_days(days).ok_or_else(invalid)?,
+            start: parse_time(start).ok_or_else(invalid)?,
+            end: parse_time(end).ok_or_else(invalid)?,
+            text: value.to_string(),
+        })
+    }
+
+    pub fn contains(&self, at: NaiveDateTime) -> bool {
+        let day = at.weekday().num_days_from_monday() as usize;
+        let minute = at.hour() * 60 + at.minute();
+        if self.start < self.end {
+            self.days[day] && (self.start..self.end).contains(&minute)
+        } else {
+            // The part after midnight belongs to the window of the previous day
+            (self.days[day] && minute >= self.start) || (self.days[(day + 6) % 7] && minute < self.end)
+        }
+    }
+}
+
+impl fmt::Display for Window {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+// `mon`, or a range like `mon-fri` or `fri-mon`
+fn parse_days(value: &str) -> Option<[bool; 7]> {
+    let day = |name: &str| DAYS.iter().position(|d| name.eq_ignore_ascii_case(d));
+    let (first, last) = match value.split_once('-') {
+        Some((first, last)) => (day(first)?, day(last)?),
+        None => (day(value)?, day(value)?),
+    };
+    let mut days = [false; 7];
+    let mut i = first;
+    loop {
+        days[i] = true;
+        if i == last {
+            return Some(days);
+        }
+        i = (i + 1) % 7;
+    }
+}
+
+fn parse_time(value: &str) -> Option<u32> {
+    let (hours, minutes) = value.split_once(':')?;
+    let (hours, minutes) = (hours.parse::<u32>().ok()?, minutes.parse::<u32>().ok()?);
+    match (hours, minutes) {
+        (24, 0) => Some(24 * 60),
+        (0..=23, 0..=59) => Some(hours * 60 + minutes),
+        _ => None,
+    }
+}
+
+// When logins are allowed. Windows prefixed with `team/` replace the other windows for the
+// team's members, and members of exempt teams, e.g. on-call, can log in at any time
+#[derive(Debug, Default)]
+pub struct AccessHours {
+    windows: Vec<Window>,
+    team_windows: Vec<(String, Window)>,
+    exempt_teams: Vec<String>,
+}
+
+impl AccessHours {
+    pub fn from_args(args: &HashMap<String, String>) -> Result<Option<Self>, String> {
+        let Some(value) = args.get("access_hours") else {
+            return Ok(None);
+        };
+        let mut hours = AccessHours::default();
+        for entry in value.split(',').map(|e| e.trim()).filter(|e| !e.is_empty()) {
+            match entry.split_once('/') {
+                Some((team, window)) => hours.team_windows.push((team.to_string(), Window::parse(window)?)),
+                None => hours.windows.push(Window::parse(entry)?),
+            }
+        }
+        if let Some(teams) = args.get("access_hours_exempt_teams") {
+            hours.exempt_teams = teams
+                .split(',')
+                .map(|t| t.trim().to_string())
+                .filter(|t| !t.is_empty())
+                .collect();
+        }
+        Ok(Some(hours))
+    }
+
+    // Teams whose membership changes the windows of a user
+    pub fn teams(&self) -> Vec<&str> {
+        let mut teams: Vec<&str> = self.exempt_teams.iter().map(|t| t.as_str()).collect();
+        for (team, _) in &self.team_windows {
+            if !teams.contains(&team.as_str()) {
+                teams.push(team);
+            }
+        }
+        teams
+    }
+
+    // Windows that apply to a member of the given teams
+    pub fn windows_for(&self, member_of: &[String]) -> Vec<&Window> {
+        let own: Vec<&Window> = self
+            .team_windows
+            .iter()
+            .filter(|(team, _)| member_of.iter().any(|t| t.eq_ignore_ascii_case(team)))
+            .map(|(_, window)| window)
+            .collect();
+        if own.is_empty() { self.windows.iter().collect() } else { own }
+    }
+
+    pub fn allows(&self, member_of: &[String], at: NaiveDateTime) -> bool {
+        if member_of.iter().any(|t| self.exempt_teams.iter().any(|e| e.eq_ignore_ascii_case(t))) {
+            return true;
+        }
+        self.windows_for(member_of).iter().any(|window| window.contains(at))
+    }
+}
+
+// A network like `10.0.0.0/8`, a bare address is a single host
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u32,
+}
+
+impl Cidr {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid CIDR: {}", value);
+        let (network, prefix) = match value.split_once('/') {
+            Some((network, prefix)) => (network, Some(prefix)),
+            None => (value, None),
+        };
+        let network: IpAddr = network.parse().map_err(|_| invalid())?;
+        let bits = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u32>().ok().filter(|p| *p <= bits).ok_or_else(invalid)?,
+            None => bits,
+        };
+        Ok(Cidr { network, prefix })
+    }
+
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        // sshd reports IPv4 clients of dual-stack listeners as `::ffff:a.b.c.d`
+        match (self.network, addr.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(network) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(network) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+// What happens to connections from outside `allow_cidr`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outside {
+    // Left to the other modules of the stack
+    Ignore,
+    Deny,
+}
+
+#[derive(Debug)]
+pub struct SourcePolicy {
+    networks: Vec<Cidr>,
+    pub outside: Outside,
+}
+
+impl SourcePolicy {
+    pub fn from_args(args: &HashMap<String, String>) -> Result<Option<Self>, String> {
+        let Some(value) = args.get("allow_cidr") else {
+            return Ok(None);
+        };
+        let networks = value
+            .split(',')
+            .map(|n| n.trim())
+            .filter(|n| !n.is_empty())
+            .map(Cidr::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+        let outside = match args.get("outside_cidr").map(|o| o.as_str()) {
+            None | Some("ignore") => Outside::Ignore,
+            Some("deny") => Outside::Deny,
+            Some(other) => return Err(format!("Invalid outside_cidr policy: {}", other)),
+        };
+        Ok(Some(SourcePolicy { networks, outside }))
+    }
+
+    // Requests without a remote host are local, e.g. sudo or the console. A host name, which
+    // sshd reports with UseDNS, is never trusted
+    pub fn allows(&self, rhost: Option<&str>) -> bool {
+        match rhost.filter(|r| !r.is_empty()) {
+            None => true,
+            Some(rhost) => match rhost.parse::<IpAddr>() {
+                Ok(addr) => self.networks.iter().any(|network| network.contains(addr)),
+                Err(_) => false,
+            },
+        }
+    }
+}
+
+// GitHub logins refused before GitHub is asked anything, from `deny_users` and the lines of
+// `deny_users_file`, which is read again on every login so edits apply at once
+#[derive(Debug, Default)]
+pub struct DenyList {
+    logins: Vec<String>,
+}
+
+impl DenyList {
+    pub fn from_args(args: &HashMap<String, String>) -> Result<Self, String> {
+        let mut logins: Vec<String> = args
+            .get("deny_users")
+            .map(|users| users.split(',').map(|u| u.trim().to_ascii_lowercase()).filter(|u| !u.is_empty()).collect())
+            .unwrap_or_default();
+        // An unreadable file fails the login rather than letting denied users through
+        if let Some(path) = args.get("deny_users_file") {
+            let content = fs::read_to_string(path).map_err(|e| format!("Failed to read deny_users_file {}: {}", path, e))?;
+            logins.extend(
+                content
+                    .lines()
+                    .map(|line| line.split('#').next().unwrap_or_default().trim().to_ascii_lowercase())
+                    .filter(|line| !line.is_empty()),
+            );
+        }
+        Ok(DenyList { logins })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.logins.is_empty()
+    }
+
+    pub fn denies(&self, login: &str) -> bool {
+        self.logins.iter().any(|denied| denied.eq_ignore_ascii_case(login))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    // 2024-01-01 is a Monday
+    fn at(day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 1, day).unwrap().and_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn windows_parse_day_ranges() {
+        let window = Window::parse("mon-fri:08:00-20:00").unwrap();
+        assert_eq!(window.days, [true, true, true, true, true, false, false]);
+        assert_eq!((window.start, window.end), (8 * 60, 20 * 60));
+        assert_eq!(Window::parse("fri-mon:00:00-24:00").unwrap().days, [true, false, false, false, true, true, true]);
+        assert_eq!(Window::parse("Sun:09:30-10:00").unwrap().days, [false, false, false, false, false, false, true]);
+
+        for value in ["mon", "mon-fri", "mon-fri:08:00", "mon-fry:08:00-20:00", "mon:8-20", "mon:24:01-25:00", "mon:08:60-09:00"] {
+            assert_eq!(Window::parse(value), Err(format!("Invalid access hours: {}", value)));
+        }
+    }
+
+    #[test]
+    fn windows_exclude_their_end() {
+        let window = Window::parse("mon-fri:08:00-20:00").unwrap();
+        assert!(window.contains(at(1, 8, 0)));
+        assert!(window.contains(at(5, 19, 59)));
+        assert!(!window.contains(at(1, 7, 59)));
+        assert!(!window.contains(at(1, 20, 0)));
+        assert!(!window.contains(at(6, 12, 0)));
+
+        let window = Window::parse("sun:00:00-24:00").unwrap();
+        assert!(window.contains(at(7, 0, 0)) && window.contains(at(7, 23, 59)));
+        assert!(!window.contains(at(1, 0, 0)));
+    }
+
+    #[test]
+    fn overnight_windows_run_into_the_next_day() {
+        let window = Window::parse("sat:22:00-02:00").unwrap();
+        assert!(window.contains(at(6, 22, 0)));
+        assert!(window.contains(at(6, 23, 59)));
+        assert!(window.contains(at(7, 1, 30)));
+        assert!(!window.contains(at(7, 2, 0)));
+        // Saturday morning belongs to Friday night, which isn't allowed
+        assert!(!window.contains(at(6, 1, 30)));
+        assert!(!window.contains(at(7, 22, 30)));
+
+        // The window from Sunday night runs into Monday
+        let window = Window::parse("sun:23:00-01:00").unwrap();
+        assert!(window.contains(at(8, 0, 30)));
+        assert!(!window.contains(at(7, 0, 30)));
+    }
+
+    #[test]
+    fn cidrs_match_by_prefix() {
+        let ip = |value: &str| value.parse::<IpAddr>().unwrap();
+        let cidr = Cidr::parse("10.0.0.0/8").unwrap();
+        assert!(cidr.contains(ip("10.255.1.2")));
+        assert!(!cidr.contains(ip("11.0.0.1")));
+
+        let cidr = Cidr::parse("192.168.1.0/23").unwrap();
+        assert!(cidr.contains(ip("192.168.0.7")));
+        assert!(!cidr.contains(ip("192.168.2.1")));
+
+        assert!(Cidr::parse("0.0.0.0/0").unwrap().contains(ip("203.0.113.9")));
+        let host = Cidr::parse("203.0.113.9").unwrap();
+        assert_eq!(host.prefix, 32);
+        assert!(host.contains(ip("203.0.113.9")));
+        assert!(!host.contains(ip("203.0.113.10")));
+
+        let cidr = Cidr::parse("2001:db8::/32").unwrap();
+        assert!(cidr.contains(ip("2001:db8:ffff::1")));
+        assert!(!cidr.contains(ip("2001:db9::1")));
+        assert!(Cidr::parse("::/0").unwrap().contains(ip("::1")));
+    }
+
+    #[test]
+    fn cidrs_match_mapped_ipv4_clients() {
+        let ip = |value: &str| value.parse::<IpAddr>().unwrap();
+        let cidr = Cidr::parse("10.0.0.0/8").unwrap();
+        assert!(cidr.contains(ip("::ffff:10.1.2.3")));
+        assert!(!cidr.contains(ip("::ffff:11.1.2.3")));
+        // Other IPv6 clients never match an IPv4 network, or the other way around
+        assert!(!cidr.contains(ip("::a01:203")));
+        assert!(!Cidr::parse("::/0").unwrap().contains(ip("10.1.2.3")));
+
+        for value in ["10.0.0.0/33", "::/129", "10.0.0.0/x", "10.0.0.0/", "example.com", "10.0.0/8"] {
+            assert_eq!(Cidr::parse(value), Err(format!("Invalid CIDR: {}", value)));
+        }
+    }
+}
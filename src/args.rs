@@ -0,0 +1,507 @@
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+
+// Every option the module understands
+const OPTIONS: &[&str] = &[
+    "access_hours",
+    "access_hours_exempt_teams",
+    "account_expiry_days",
+    "allow_cidr",
+    "allow_import_keys",
+    "allow_pending",
+    "allow_users",
+    "allow_users_mode",
+    "app_id",
+    "app_private_key",
+    "audit_log",
+    "auditd",
+    "auth_mode",
+    "authorized_keys_path",
+    "auto_create_user",
+    "banner",
+    "bind_github_id",
+    "ca_bundle",
+    "cache_ttl",
+    "client_id",
+    "client_secret",
+    "client_secret_file",
+    "config",
+    "connect_timeout",
+    "debug",
+    "deny_users",
+    "deny_users_file",
+    "deprovision",
+    "dry_run",
+    "enterprise",
+    "ephemeral_user",
+    "extra_users",
+    "fix_permissions",
+    "github_url",
+    "groups",
+    "helper_socket",
+    "host_group",
+    "http_timeout",
+    "https_proxy",
+    "include_child_teams",
+    "key_max_age_days",
+    "key_options",
+    "key_title_pattern",
+    "key_ttl",
+    "lockout_attempts",
+    "lockout_window",
+    "log_backend",
+    "max_retries",
+    "metrics_dir",
+    "min_permission",
+    "no_conversation",
+    "no_proxy",
+    "oidc_audience",
+    "oidc_issuer",
+    "oidc_ref",
+    "oidc_repository",
+    "oidc_user",
+    "on_unreachable",
+    "org",
+    "open_browser",
+    "outside_cidr",
+    "owner_token_file",
+    "pin_sha256",
+    "policy_path",
+    "policy_repo",
+    "principal_teams",
+    "principals_cache_ttl",
+    "priv_helper",
+    "prune_stale_keys",
+    "repo",
+    "require_2fa",
+    "require_email_domain",
+    "require_role",
+    "return_codes",
+    "revoke_token_after_auth",
+    "role_group_map",
+    "scopes",
+    "show_qr",
+    "skip_if_publickey",
+    "soft_fail",
+    "store_token",
+    "sudo_grace",
+    "sudo_group",
+    "sudoer_teams",
+    "sudoers_template",
+    "sync_keys_on_session",
+    "team",
+    "team_policy",
+    "totp_secret_file",
+    "uid_offset",
+    "use_graphql",
+    "user_agent",
+    "user_map",
+    "verbose_errors",
+];
+
+// Options taking comma-separated lists, repeating one of them adds to the list
+const LIST_OPTIONS: &[&str] = &[
+    "access_hours",
+    "access_hours_exempt_teams",
+    "allow_cidr",
+    "allow_users",
+    "deny_users",
+    "extra_users",
+    "groups",
+    "no_proxy",
+    "oidc_ref",
+    "oidc_repository",
+    "oidc_user",
+    "org",
+    "pin_sha256",
+    "principal_teams",
+    "require_email_domain",
+    "return_codes",
+    "role_group_map",
+    "scopes",
+    "sudoer_teams",
+    "team",
+];
+
+// Options, or option values, that need a cargo feature left out of minimal builds
+const FEATURE_OPTIONS: &[(&str, &str)] = &[
+    ("account_expiry_days", "accounts"),
+    ("allow_import_keys", "keys"),
+    ("authorized_keys_path", "keys"),
+    ("auto_create_user", "accounts"),
+    ("cache_ttl", "cache"),
+    ("deprovision", "accounts"),
+    ("ephemeral_user", "accounts"),
+    ("groups", "accounts"),
+    ("metrics_dir", "metrics"),
+    ("on_unreachable=allow_cached", "cache"),
+    ("prune_stale_keys", "keys"),
+    ("role_group_map", "accounts"),
+    ("skip_if_publickey", "keys"),
+    ("store_token", "cache"),
+    ("sudo_group", "accounts"),
+    ("sudoer_teams", "accounts"),
+    ("sudoers_template", "accounts"),
+    ("sync_keys_on_session", "keys"),
+    ("uid_offset", "accounts"),
+];
+
+// Options, or option values, needing GitHub requests that ghauthd does not make for the module
+const DIRECT_OPTIONS: &[&str] = &[
+    "auth_mode=actions_oidc",
+    "banner",
+    "enterprise",
+    "extra_users",
+    "key_max_age_days",
+    "key_title_pattern",
+    "policy_repo",
+    "require_2fa",
+    "require_email_domain",
+    "revoke_token_after_auth",
+    "use_graphql",
+];
+
+pub fn is_known(key: &str) -> bool {
+    OPTIONS.contains(&key)
+}
+
+const FEATURES: &[(&str, bool)] = &[
+    ("accounts", cfg!(feature = "accounts")),
+    ("keys", cfg!(feature = "keys")),
+    ("cache", cfg!(feature = "cache")),
+    ("metrics", cfg!(feature = "metrics")),
+];
+
+// Rejects options this build can't honor, rather than silently ignoring them
+pub fn check_features(options: &HashMap<String, String>) -> Result<(), String> {
+    for (option, feature) in FEATURE_OPTIONS {
+        if is_given(options, option) && FEATURES.contains(&(*feature, false)) {
+            return Err(format!(
+                "{} needs the `{}` feature, which this build of the module was compiled without",
+                option, feature
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Rejects options ghauthd can't serve with helper_socket, before the user goes through the flow
+pub fn check_helper(options: &HashMap<String, String>) -> Result<(), String> {
+    if !options.contains_key("helper_socket") {
+        return Ok(());
+    }
+    match DIRECT_OPTIONS.iter().find(|option| is_given(options, option)) {
+        Some(option) => Err(format!(
+            "{} needs requests ghauthd does not make for the module and can't be combined with helper_socket",
+            option
+        )),
+        None => Ok(()),
+    }
+}
+
+// `key` or `key=value`, the latter only when the option has that value
+fn is_given(options: &HashMap<String, String>, option: &str) -> bool {
+    match option.split_once('=') {
+        Some((key, value)) => options.get(key).is_some_and(|v| v == value),
+        None => options.contains_key(option),
+    }
+}
+
+// Parses `key=value` options and bare flags. A value may be quoted to hold spaces,
+// e.g. `key="a b"`, which PAM hands over split into several arguments. Within quotes, \" and
+// \\ stand for a quote and a backslash.
+// Returns the options and a description of every argument that was ignored or looked wrong
+pub fn parse<I, A>(args: I) -> (HashMap<String, String>, Vec<String>)
+where
+    I: IntoIterator<Item = A>,
+    A: AsRef<[u8]>,
+{
+    parse_known(args, is_known)
+}
+
+// Like parse, for programs that take options of their own besides the module's
+pub fn parse_known<I, A>(args: I, known: fn(&str) -> bool) -> (HashMap<String, String>, Vec<String>)
+where
+    I: IntoIterator<Item = A>,
+    A: AsRef<[u8]>,
+{
+    let mut options = HashMap::new();
+    let mut problems = Vec::new();
+    let mut pending: Option<String> = None;
+
+    for arg in args {
+        let arg = match std::str::from_utf8(arg.as_ref()) {
+            Ok(arg) => arg,
+            Err(_) => {
+                problems.push(format!(
+                    "Ignoring argument that is not valid UTF-8: {}",
+                    String::from_utf8_lossy(arg.as_ref())
+                ));
+                continue;
+            }
+        };
+        let arg = match pending.take() {
+            Some(mut quoted) => {
+                quoted.push(' ');
+                quoted.push_str(arg);
+                quoted
+            }
+            None => arg.to_string(),
+        };
+        // An odd number of quotes means the value continues in the next argument
+        if unescaped_quotes(&arg) % 2 == 1 {
+            pending = Some(arg);
+            continue;
+        }
+        add_option(&mut options, &mut problems, known, &arg);
+    }
+    if let Some(arg) = pending {
+        problems.push(format!("Unterminated quote in argument: {}", arg));
+        add_option(&mut options, &mut problems, known, &arg);
+    }
+
+    (options, problems)
+}
+
+fn add_option(options: &mut HashMap<String, String>, problems: &mut Vec<String>, known: fn(&str) -> bool, arg: &str) {
+    let (key, value) = match arg.split_once('=') {
+        Some((key, value)) => (key.trim(), unquote(value.trim())),
+        None => (arg.trim(), String::new()),
+    };
+    if key.is_empty() {
+        problems.push(format!("Ignoring argument without a name: {}", arg));
+        return;
+    }
+    if !known(key) {
+        problems.push(format!("Unknown option: {}", key));
+    }
+
+    match options.get_mut(key) {
+        Some(existing) if LIST_OPTIONS.contains(&key) => {
+            if !existing.is_empty() {
+                existing.push(',');
+            }
+            existing.push_str(&value);
+        }
+        Some(existing) => {
+            problems.push(format!("Option {} is given more than once, using the last value", key));
+            *existing = value;
+        }
+        None => {
+            options.insert(key.to_string(), value);
+        }
+    }
+}
+
+// Adds the options of the `config` file for a PAM service. Lines before the first `[service]`
+// header apply to every service, a `[host_group:name]` section to hosts of that `host_group`, and
+// the section of the given service overrides both. The module arguments override everything.
+// An option replaces the one it overrides, list options included: `team` in a section is the
+// whole list for it, not added to the common one. Only repeating a list option within the same
+// section adds to it. Values may contain spaces without quoting
+pub fn with_config(options: HashMap<String, String>, service: &str) -> Result<(HashMap<String, String>, Vec<String>), String> {
+    let Some(path) = options.get("config") else {
+        return Ok((options, Vec::new()));
+    };
+    let metadata = fs::metadata(path).map_err(|e| format!("Failed to read config {}: {}", path, e))?;
+    // The file decides who can log in. Modules run as root, so it has to be root's
+    if metadata.uid() != unsafe { libc::geteuid() } || metadata.mode() & 0o022 != 0 {
+        return Err(format!("Config {} must be owned by root and not writable by group or others", path));
+    }
+    let content = fs::read_to_string(path).map_err(|e| format!("Failed to read config {}: {}", path, e))?;
+
+    let mut common = HashMap::new();
+    let mut own = HashMap::new();
+    let mut groups: HashMap<&str, HashMap<String, String>> = HashMap::new();
+    let mut section: Option<&str> = None;
+    let mut problems = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = Some(name.trim());
+            continue;
+        }
+        let target = match section {
+            None => &mut common,
+            Some(name) if name == service => &mut own,
+            Some(name) => match name.strip_prefix("host_group:") {
+                Some(group) => groups.entry(group.trim()).or_default(),
+                None => continue,
+            },
+        };
+        let before = problems.len();
+        add_option(target, &mut problems, is_known, line);
+        for problem in &mut problems[before..] {
+            *problem = format!("{}:{}: {}", path, i + 1, problem);
+        }
+    }
+    let host_group = [&options, &own, &common]
+        .into_iter()
+        .find_map(|o| o.get("host_group"))
+        .cloned();
+    let mut merged = common;
+    if let Some(group) = host_group.and_then(|g| groups.remove(g.as_str())) {
+        merged.extend(group);
+    }
+    merged.extend(own);
+    merged.extend(options);
+    Ok((merged, problems))
+}
+
+// Quotes that open or close a quoted value, leaving out escaped ones
+fn unescaped_quotes(arg: &str) -> usize {
+    let mut escaped = false;
+    arg.chars()
+        .filter(|c| {
+            let quote = *c == '"' && !escaped;
+            escaped = *c == '\\' && !escaped;
+            quote
+        })
+        .count()
+}
+
+fn unquote(value: &str) -> String {
+    let Some(inner) = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) else {
+        return value.to_string();
+    };
+    let mut unquoted = String::with_capacity(inner.len());
+    let mut chars = inner.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('\\', Some(&next @ ('"' | '\\'))) => {
+                unquoted.push(next);
+                chars.next();
+            }
+            _ => unquoted.push(c),
+        }
+    }
+    unquoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn options(args: &[&str]) -> HashMap<String, String> {
+        let (options, problems) = parse(args);
+        assert!(problems.is_empty(), "{:?}", problems);
+        options
+    }
+
+    #[test]
+    fn quoted_values_span_arguments() {
+        let options = options(&["team=\"Platform", "Engineering\"", "show_qr"]);
+        assert_eq!(options["team"], "Platform Engineering");
+        assert_eq!(options["show_qr"], "");
+    }
+
+    #[test]
+    fn escaped_quotes_stay_in_the_value() {
+        assert_eq!(unquote(r#""say \"hi\" \\ \n""#), r#"say "hi" \ \n"#);
+        assert_eq!(unquote(r#"plain\"value"#), r#"plain\"value"#);
+        // An escaped quote doesn't open or close the value
+        let options = options(&[r#"banner="a"#, r#"\"b"#, r#"c\"""#]);
+        assert_eq!(options["banner"], r#"a "b c""#);
+    }
+
+    #[test]
+    fn unterminated_quotes_are_reported() {
+        let (options, problems) = parse(["team=\"dev", "ops"]);
+        assert_eq!(options["team"], "\"dev ops");
+        assert_eq!(problems, ["Unterminated quote in argument: team=\"dev ops"]);
+    }
+
+    #[test]
+    fn list_options_accumulate() {
+        let options = options(&["org=", "org=acme", "org=globex,initech", "team=dev"]);
+        assert_eq!(options["org"], "acme,globex,initech");
+        assert_eq!(options["team"], "dev");
+
+        let (options, problems) = parse(["client_id=a", "client_id=b"]);
+        assert_eq!(options["client_id"], "b");
+        assert_eq!(problems, ["Option client_id is given more than once, using the last value"]);
+    }
+
+    #[test]
+    fn malformed_arguments_are_reported() {
+        let args: [&[u8]; 4] = [b"org=acme", b"team=\xffdev", b"=value", b"nonsense"];
+        let (options, problems) = parse(args);
+        assert_eq!(options.len(), 2);
+        assert_eq!(options["org"], "acme");
+        // Unknown options are kept for programs that read them themselves
+        assert_eq!(options["nonsense"], "");
+        assert_eq!(problems, [
+            "Ignoring argument that is not valid UTF-8: team=\u{fffd}dev",
+            "Ignoring argument without a name: =value",
+            "Unknown option: nonsense",
+        ]);
+
+        let (options, problems) = parse_known(["socket=/run/x.sock"], |key| key == "socket");
+        assert_eq!(options["socket"], "/run/x.sock");
+        assert!(problems.is_empty());
+    }
+
+    #[test]
+    fn config_sections_apply_in_order() {
+        let path = std::env::temp_dir().join(format!("args-config-{}", std::process::id()));
+        fs::write(
+            &path,
+            "# shared\norg = acme\nbanner = org/repo:motd with spaces\nbogus\n\
+             [host_group:db]\nteam = dba\n[sudo]\nteam = admins\n[sshd]\nteam = ops\n",
+        )
+        .unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        let config = path.to_string_lossy().into_owned();
+
+        let args = HashMap::from([("config".to_string(), config.clone()), ("host_group".to_string(), "db".to_string())]);
+        let (options, problems) = with_config(args, "sshd").unwrap();
+        assert_eq!(options["org"], "acme");
+        assert_eq!(options["banner"], "org/repo:motd with spaces");
+        assert_eq!(options["team"], "ops");
+        assert_eq!(problems, [format!("{}:4: Unknown option: bogus", config)]);
+
+        let args = HashMap::from([("config".to_string(), config.clone()), ("host_group".to_string(), "db".to_string())]);
+        assert_eq!(with_config(args, "login").unwrap().0["team"], "dba");
+        let args = HashMap::from([("config".to_string(), config), ("team".to_string(), "sre".to_string())]);
+        assert_eq!(with_config(args, "sudo").unwrap().0["team"], "sre");
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o664)).unwrap();
+        let args = HashMap::from([("config".to_string(), path.to_string_lossy().into_owned())]);
+        assert!(with_config(args, "sshd").is_err());
+        fs::remove_file(path).unwrap();
+
+        let args = HashMap::from([("org".to_string(), "acme".to_string())]);
+        assert_eq!(with_config(args.clone(), "sshd").unwrap(), (args, Vec::new()));
+    }
+
+    #[test]
+    fn config_sections_replace_lists_instead_of_extending_them() {
+        let path = std::env::temp_dir().join(format!("args-lists-{}", std::process::id()));
+        fs::write(
+            &path,
+            "team = developers\nteam = qa\nallow_cidr = 10.0.0.0/8\n\
+             [host_group:db]\nteam = dba\n[sshd]\nallow_cidr = 192.0.2.0/24\n",
+        )
+        .unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        let config = path.to_string_lossy().into_owned();
+
+        // Repeated within a section, a list grows
+        let args = HashMap::from([("config".to_string(), config.clone())]);
+        let (options, _) = with_config(args, "login").unwrap();
+        assert_eq!(options["team"], "developers,qa");
+        // A section, or the module arguments, replace it
+        let args = HashMap::from([("config".to_string(), config.clone()), ("host_group".to_string(), "db".to_string())]);
+        let (options, _) = with_config(args, "sshd").unwrap();
+        assert_eq!(options["team"], "dba");
+        assert_eq!(options["allow_cidr"], "192.0.2.0/24");
+        let args = HashMap::from([("config".to_string(), config), ("team".to_string(), "sre".to_string())]);
+        assert_eq!(with_config(args, "login").unwrap().0["team"], "sre");
+        fs::remove_file(path).unwrap();
+    }
+}
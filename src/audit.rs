@@ -0,0 +1,207 @@
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::logging;
+
+// One record per authentication attempt, kept apart from the operational log
+#[derive(Debug, Default, Serialize)]
+pub struct AuthEvent {
+    pub timestamp: String,
+    pub local_user: Option<String>,
+    pub github_login: Option<String>,
+    pub github_id: Option<u64>,
+    pub org: Option<String>,
+    pub teams: Vec<String>,
+    pub service: Option<String>,
+    pub rhost: Option<String>,
+    pub tty: Option<String>,
+    pub result: String,
+    pub latency_ms: u128,
+}
+
+impl AuthEvent {
+    pub fn finish(&mut self, result: &str, latency: Duration) {
+        self.timestamp = chrono::Utc::now().to_rfc3339();
+        self.result = result.to_string();
+        self.latency_ms = latency.as_millis();
+    }
+}
+
+// Appends the event as a JSON line to a file, or sends it to a socket given as `unix:/path`
+pub fn write(target: &str, event: &AuthEvent) {
+    let mut line = match serde_json::to_string(event) {
+        Ok(line) => line,
+        Err(err) => {
+            logging::log_to_file(&format!("Failed to serialize audit event: {}", err));
+            return;
+        }
+    };
+    line.push('\n');
+
+    let result = match target.strip_prefix("unix:") {
+        Some(path) => UnixStream::connect(path).and_then(|mut stream| stream.write_all(line.as_bytes())),
+        None => OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(target)
+            .and_then(|mut file| file.write_all(line.as_bytes())),
+    };
+    if let Err(err) = result {
+        logging::log_to_file(&format!("Failed to write audit event to {}: {}", target, err));
+    }
+}
+
+// Record types of the Linux audit subsystem, the ones pam_unix's decisions end up as
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KernelRecord {
+    // AUDIT_USER_AUTH
+    UserAuth,
+    // AUDIT_USER_ACCT
+    UserAcct,
+}
+
+impl KernelRecord {
+    fn code(&self) -> u16 {
+        match self {
+            KernelRecord::UserAuth => 1100,
+            KernelRecord::UserAcct => 1101,
+        }
+    }
+
+    fn op(&self) -> &'static str {
+        match self {
+            KernelRecord::UserAuth => "PAM:authentication",
+            KernelRecord::UserAcct => "PAM:accounting",
+        }
+    }
+}
+
+// Quoted like libaudit does, or hex encoded when the value could be mistaken for other fields
+fn encode_value(value: &str) -> String {
+    if value.is_empty() || value.bytes().any(|b| b == b'"' || !(0x21..=0x7e).contains(&b)) {
+        value.bytes().map(|b| format!("{:02X}", b)).collect()
+    } else {
+        format!("\"{}\"", value)
+    }
+}
+
+// The text of a record in the format of libaudit's audit_log_acct_message, which is what
+// ausearch and aureport parse
+pub fn kernel_message(
+    record: KernelRecord,
+    account: &str,
+    exe: &str,
+    rhost: Option<&str>,
+    tty: Option<&str>,
+    success: bool,
+) -> String {
+    let rhost = rhost.filter(|host| !host.is_empty() && !host.contains(char::is_whitespace));
+    let addr = rhost.and_then(|host| host.parse::<IpAddr>().ok());
+    format!(
+        "op={} grantors=pam_ssh_github_auth acct={} exe={} hostname={} addr={} terminal={} res={}",
+        record.op(),
+        encode_value(account),
+        encode_value(exe),
+        rhost.unwrap_or("?"),
+        addr.map(|addr| addr.to_string()).unwrap_or_else(|| "?".to_string()),
+        tty.filter(|tty| !tty.is_empty() && !tty.contains(char::is_whitespace)).unwrap_or("?"),
+        if success { "success" } else { "failed" },
+    )
+}
+
+// Sends a record to auditd through the kernel. Systems without audit support or with auditing
+// turned off are skipped quietly, like libaudit does
+pub fn write_kernel(record: KernelRecord, account: &str, rhost: Option<&str>, tty: Option<&str>, success: bool) {
+    let exe = std::env::current_exe()
+        .map(|exe| exe.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| "?".to_string());
+    let message = kernel_message(record, account, &exe, rhost, tty, success);
+    match send_netlink(record.code(), &message) {
+        Ok(()) => {}
+        Err(err)
+            if matches!(
+                err.raw_os_error(),
+                Some(libc::ECONNREFUSED) | Some(libc::EPROTONOSUPPORT) | Some(libc::EAFNOSUPPORT)
+            ) =>
+        {
+            logging::debug(&format!("Audit subsystem not available: {}", err))
+        }
+        Err(err) => logging::log_to_file(&format!("Failed to send audit record: {}", err)),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn send_netlink(kind: u16, message: &str) -> io::Result<()> {
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+    const HEADER_LEN: usize = 16;
+    const NLMSG_ERROR: u16 = 2;
+
+    let fd = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_RAW | libc::SOCK_CLOEXEC, libc::NETLINK_AUDIT) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    // struct nlmsghdr followed by the text and its NUL, padded to 4 bytes
+    let payload_len = message.len() + 1;
+    let len = HEADER_LEN + payload_len;
+    let mut buf = vec![0u8; (len + 3) & !3];
+    buf[0..4].copy_from_slice(&(len as u32).to_ne_bytes());
+    buf[4..6].copy_from_slice(&kind.to_ne_bytes());
+    buf[6..8].copy_from_slice(&((libc::NLM_F_REQUEST | libc::NLM_F_ACK) as u16).to_ne_bytes());
+    buf[8..12].copy_from_slice(&1u32.to_ne_bytes());
+    buf[HEADER_LEN..HEADER_LEN + message.len()].copy_from_slice(message.as_bytes());
+
+    let mut kernel: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+    kernel.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+    let sent = unsafe {
+        libc::sendto(
+            socket.as_raw_fd(),
+            buf.as_ptr() as *const libc::c_void,
+            buf.len(),
+            0,
+            &kernel as *const libc::sockaddr_nl as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+        )
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // The acknowledgement carries the error, e.g. EPERM without CAP_AUDIT_WRITE
+    let timeout = libc::timeval { tv_sec: 1, tv_usec: 0 };
+    unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_RCVTIMEO,
+            &timeout as *const libc::timeval as *const libc::c_void,
+            std::mem::size_of::<libc::timeval>() as libc::socklen_t,
+        )
+    };
+    let mut reply = [0u8; 64];
+    let received = unsafe { libc::recv(socket.as_raw_fd(), reply.as_mut_ptr() as *mut libc::c_void, reply.len(), 0) };
+    if received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if received as usize >= HEADER_LEN + 4 && u16::from_ne_bytes([reply[4], reply[5]]) == NLMSG_ERROR {
+        let errno = i32::from_ne_bytes([reply[16], reply[17], reply[18], reply[19]]);
+        if errno != 0 {
+            return Err(io::Error::from_raw_os_error(-errno));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_netlink(_kind: u16, _message: &str) -> io::Result<()> {
+    Err(io::Error::from_raw_os_error(libc::EAFNOSUPPORT))
+}
@@ -0,0 +1,233 @@
+use std::collections::HashMap;
+use std::{panic, thread};
+
+use crate::github::{
+    ClientConfig, DeviceCode, GithubApp, GithubClient, GithubError, GithubUser, OAuthApp, RepoPermission,
+    UserToken,
+};
+use crate::logging;
+
+// Proves who a user is on GitHub and that they belong to the configured organizations or
+// repository. Membership is looked up as the GitHub App when there is one, otherwise with the
+// user's own token
+pub struct GithubAuthenticator {
+    client: GithubClient,
+    app: Option<GithubApp>,
+    orgs: Vec<String>,
+    // Members of any organization of this enterprise are accepted too
+    enterprise: Option<String>,
+    repo: Option<(String, RepoPermission)>,
+    graphql: bool,
+    // GitHub logins accepted without membership, e.g. external contractors
+    extra_users: Vec<String>,
+}
+
+impl GithubAuthenticator {
+    pub fn new(client: GithubClient, app: Option<GithubApp>, orgs: Vec<String>) -> Self {
+        GithubAuthenticator {
+            client,
+            app,
+            orgs,
+            enterprise: None,
+            repo: None,
+            graphql: false,
+            extra_users: Vec::new(),
+        }
+    }
+
+    // The enterprise's organizations are listed with the user's token on each login
+    pub fn with_enterprise(mut self, enterprise: &str) -> Self {
+        self.enterprise = Some(enterprise.to_string());
+        self
+    }
+
+    // Collaborators of the repository are accepted instead of organization members
+    pub fn with_repo(mut self, repo: &str, permission: RepoPermission) -> Self {
+        self.repo = Some((repo.to_string(), permission));
+        self
+    }
+
+    // Only the owner of the token, or of the SSH key, is checked for these logins
+    pub fn with_extra_users(mut self, logins: &[&str]) -> Self {
+        self.extra_users = logins.iter().map(|login| login.to_ascii_lowercase()).collect();
+        self
+    }
+
+    // Checks identity, membership and teams with one GraphQL request, needs the user's token
+    pub fn with_graphql(mut self) -> Self {
+        self.graphql = true;
+        self
+    }
+
+    // From the same options as the PAM module: `org`, `enterprise`, `repo`, `min_permission`,
+    // `extra_users`, `app_id`, `app_private_key`, `use_graphql` and the HTTP client options
+    pub fn from_args(args: &HashMap<String, String>) -> Result<Self, String> {
+        let orgs: Vec<String> = args
+            .get("org")
+            .map(|org| org.split(',').map(|o| o.trim().to_string()).filter(|o| !o.is_empty()).collect())
+            .unwrap_or_default();
+        let repo = match args.get("repo") {
+            Some(repo) if repo.split('/').count() == 2 => {
+                let min_permission = args.get("min_permission").map(|p| p.as_str()).unwrap_or("write");
+                match RepoPermission::from_arg(min_permission) {
+                    Some(permission) => Some((repo.as_str(), permission)),
+                    None => return Err(format!("Invalid min_permission: {}", min_permission)),
+                }
+            }
+            Some(repo) => return Err(format!("Invalid repo, expected owner/name: {}", repo)),
+            None => None,
+        };
+        let enterprise = args.get("enterprise").filter(|e| !e.is_empty());
+        if orgs.is_empty() && repo.is_none() && enterprise.is_none() {
+            return Err("Missing organization name".to_string());
+        }
+        let app = match (args.get("app_id"), args.get("app_private_key")) {
+            (Some(app_id), Some(key_path)) => Some(
+                GithubApp::new(app_id, key_path)
+                    .map_err(|e| format!("Failed to load GitHub App credentials: {}", e))?,
+            ),
+            (None, None) => None,
+            _ => return Err("Both app_id and app_private_key are required for GitHub App mode".to_string()),
+        };
+        let graphql = args.contains_key("use_graphql");
+        if graphql && (app.is_some() || repo.is_some()) {
+            return Err(
+                "use_graphql checks membership with the user's token and can't be combined with app_id or repo"
+                    .to_string(),
+            );
+        }
+        if enterprise.is_some() && (app.is_some() || repo.is_some()) {
+            return Err(
+                "enterprise lists its organizations with the user's token and can't be combined with app_id or repo"
+                    .to_string(),
+            );
+        }
+        let config = ClientConfig::from_args(args)?;
+        let client = GithubClient::new(&config).map_err(|e| format!("Failed to create GitHub client: {}", e))?;
+
+        let mut authenticator = GithubAuthenticator::new(client, app, orgs);
+        if let Some((repo, permission)) = repo {
+            authenticator = authenticator.with_repo(repo, permission);
+        }
+        if graphql {
+            authenticator = authenticator.with_graphql();
+        }
+        if let Some(enterprise) = enterprise {
+            authenticator = authenticator.with_enterprise(enterprise);
+        }
+        if let Some(users) = args.get("extra_users") {
+            let users: Vec<&str> = users.split(',').map(|u| u.trim()).filter(|u| !u.is_empty()).collect();
+            authenticator = authenticator.with_extra_users(&users);
+        }
+        Ok(authenticator)
+    }
+
+    pub fn client(&self) -> &GithubClient {
+        &self.client
+    }
+
+    pub fn app(&self) -> Option<&GithubApp> {
+        self.app.as_ref()
+    }
+
+    // First step of the device flow, the user is shown the code and the verification URL
+    pub fn start_device_flow(&self, app: &OAuthApp) -> Result<DeviceCode, GithubError> {
+        self.client.get_auth_code(app)
+    }
+
+    // Last step of the device flow, once the user authorized the code as one of `logins`
+    pub fn finish_device_flow(
+        &self,
+        app: &OAuthApp,
+        device_code: &str,
+        logins: &[String],
+    ) -> Result<(UserToken, GithubUser), GithubError> {
+        let token = self.client.exchange_device_code(device_code, app)?;
+        let user = self.user_with_token(&token.token, logins)?;
+        Ok((token, user))
+    }
+
+    // The member behind a user token, which must belong to one of `logins`
+    pub fn user_with_token(&self, token: &str, logins: &[String]) -> Result<GithubUser, GithubError> {
+        if logins.iter().any(|login| self.is_extra_user(login)) {
+            let login = self.client.check_username(logins, token)?;
+            if self.is_extra_user(&login) {
+                return self.extra_user(&login, Some(token));
+            }
+            return self.find_user(&login, Some(token), &self.orgs_with_token(token)?);
+        }
+        if self.graphql {
+            let orgs = self.orgs_with_token(token)?;
+            let orgs: Vec<&str> = orgs.iter().map(|o| o.as_str()).collect();
+            return GithubUser::from_graphql(&self.client, token, logins, &orgs);
+        }
+        // With a single candidate login, membership is looked up while the token's owner is checked
+        if let [login] = logins {
+            let (owner, user) = thread::scope(|scope| {
+                let owner = scope.spawn(|| self.client.check_username(logins, token));
+                let user = self.orgs_with_token(token).and_then(|orgs| self.find_user(login, Some(token), &orgs));
+                (owner.join().unwrap_or_else(|p| panic::resume_unwind(p)), user)
+            });
+            owner?;
+            return user;
+        }
+        let login = self.client.check_username(logins, token)?;
+        self.find_user(&login, Some(token), &self.orgs_with_token(token)?)
+    }
+
+    // The member owning the SSH key sshd accepted, from its `SSH_AUTH_INFO_0`. Needs the app
+    pub fn user_with_key(&self, logins: &[String], auth_info: &str) -> Result<GithubUser, GithubError> {
+        for login in logins {
+            if self.client.verify_key_ownership(login, auth_info)? {
+                if self.is_extra_user(login) {
+                    return self.extra_user(login, None);
+                }
+                return self.find_user(login, None, &self.orgs);
+            }
+        }
+        Err(GithubError::InvalidUser(format!(
+            "SSH key used to connect is not listed on GitHub accounts {:?}",
+            logins
+        )))
+    }
+
+    // The configured organizations, followed by those of the enterprise
+    fn orgs_with_token(&self, token: &str) -> Result<Vec<String>, GithubError> {
+        let mut orgs = self.orgs.clone();
+        if let Some(enterprise) = &self.enterprise {
+            for org in self.client.enterprise_orgs(enterprise, token)? {
+                if !orgs.iter().any(|o| o.eq_ignore_ascii_case(&org)) {
+                    orgs.push(org);
+                }
+            }
+        }
+        Ok(orgs)
+    }
+
+    fn is_extra_user(&self, login: &str) -> bool {
+        self.extra_users.iter().any(|extra| extra.eq_ignore_ascii_case(login))
+    }
+
+    // Extra users are checked against the first organization, or the repository's owner
+    fn extra_user(&self, login: &str, token: Option<&str>) -> Result<GithubUser, GithubError> {
+        let org = match (self.orgs.first(), &self.enterprise, &self.repo) {
+            (Some(org), _, _) | (None, Some(org), _) => org.as_str(),
+            (None, None, Some((repo, _))) => repo.split('/').next().unwrap_or(repo),
+            (None, None, None) => "",
+        };
+        logging::log_to_file(&format!("Accepting {} from extra_users without checking membership", login));
+        GithubUser::without_membership(&self.client, login, token, org)
+    }
+
+    fn find_user(&self, login: &str, token: Option<&str>, orgs: &[String]) -> Result<GithubUser, GithubError> {
+        let client = &self.client;
+        let orgs: Vec<&str> = orgs.iter().map(|o| o.as_str()).collect();
+        match (&self.repo, &self.app, token) {
+            (Some((repo, permission)), Some(app), _) => GithubUser::from_repo_app(client, app, login, repo, *permission),
+            (Some((repo, permission)), None, Some(token)) => GithubUser::from_repo(client, token, login, repo, *permission),
+            (None, Some(app), _) => GithubUser::from_app(client, app, login, &orgs),
+            (None, None, Some(token)) => GithubUser::from_pat(client, token, login, &orgs),
+            (_, None, None) => Err(GithubError::Other("A user token or GitHub App is required".to_string())),
+        }
+    }
+}
@@ -0,0 +1,907 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::net::ToSocketAddrs;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::Path;
+use std::process;
+
+use ssh_github_auth::failure::FailurePolicy;
+use ssh_github_auth::lockout::Lockout;
+use ssh_github_auth::{access, args, github, helper, keys, logging, oidc, policy, state, tokens, totp, user, usermap, AuthMode, KeySync};
+
+const MODULE: &str = "pam_ssh_github_auth.so";
+
+type Options = HashMap<String, String>;
+
+fn usage() -> ! {
+    eprintln!("Usage: ghauth check [--pam-file PATH] [--service SERVICE] [key=value ...]");
+    eprintln!("       ghauth doctor [--pam-file PATH] [--service SERVICE] [key=value ...]");
+    eprintln!("       ghauth provision --team TEAM [--pam-file PATH] [key=value ...]");
+    eprintln!("       ghauth sync-keys [--pam-file PATH] [key=value ...]");
+    eprintln!("       ghauth principals --user USER [--pam-file PATH] [key=value ...]");
+    eprintln!("       ghauth users [--json]");
+    process::exit(2);
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(|a| a.as_str()) {
+        Some("check") => process::exit(check(&args[1..])),
+        Some("doctor") => process::exit(doctor(&args[1..])),
+        Some("provision") => process::exit(provision(&args[1..])),
+        Some("sync-keys") => process::exit(sync_keys(&args[1..])),
+        Some("principals") => process::exit(principals(&args[1..])),
+        Some("users") => process::exit(users(&args[1..])),
+        _ => usage(),
+    }
+}
+
+// Parses key=value options the same way as the PAM module
+fn parse_options<'a>(options: impl Iterator<Item = &'a str>) -> Options {
+    let (options, problems) = args::parse(options);
+    for problem in problems {
+        eprintln!("{}", problem);
+    }
+    options
+}
+
+// Splits a PAM file line into tokens, keeping `[...]` arguments with spaces together
+fn pam_tokens(line: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut bracket: Option<String> = None;
+    for token in line.split_whitespace() {
+        if let Some(mut open) = bracket.take() {
+            open.push(' ');
+            open.push_str(token);
+            match open.strip_suffix(']') {
+                Some(closed) => tokens.push(closed.to_string()),
+                None => bracket = Some(open),
+            }
+        } else if let Some(open) = token.strip_prefix('[') {
+            match open.strip_suffix(']') {
+                Some(closed) => tokens.push(closed.to_string()),
+                None => bracket = Some(open.to_string()),
+            }
+        } else {
+            tokens.push(token.to_string());
+        }
+    }
+    tokens.extend(bracket);
+    tokens
+}
+
+// Returns the options of every line of a PAM file that loads this module
+fn pam_file_options(path: &str) -> Result<Vec<(String, Options)>, String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    Ok(content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim_start().starts_with('#'))
+        .filter_map(|(i, line)| {
+            let tokens = pam_tokens(line);
+            let mut tokens = tokens.iter().map(|t| t.as_str());
+            tokens.by_ref().position(|t| t.ends_with(MODULE))?;
+            Some((format!("{}:{}", path, i + 1), parse_options(tokens)))
+        })
+        .collect())
+}
+
+// Collects the module options from --pam-file and key=value arguments, and the other --flag values.
+// The `config` file section is the one of --service, or else the PAM file's service
+fn load_options(args: &[String]) -> Result<(Vec<(String, Options)>, Options), String> {
+    let mut configs = Vec::new();
+    let mut options = Vec::new();
+    let mut flags = Options::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if let Some(flag) = arg.strip_prefix("--") {
+            let Some(value) = iter.next() else { usage() };
+            if flag != "pam-file" {
+                flags.insert(flag.to_string(), value.clone());
+                continue;
+            }
+            let found = pam_file_options(value)?;
+            if found.is_empty() {
+                return Err(format!("{} does not load {}", value, MODULE));
+            }
+            let service = Path::new(value).file_name().map(|name| name.to_string_lossy().into_owned());
+            configs.extend(found.into_iter().map(|(source, config)| (source, config, service.clone())));
+        } else {
+            options.push(arg.as_str());
+        }
+    }
+    // Options given on the command line override those from the PAM file
+    let options = parse_options(options.into_iter());
+    if configs.is_empty() {
+        configs.push(("command line".to_string(), options, None));
+    } else {
+        for (_, config, _) in configs.iter_mut() {
+            config.extend(options.clone());
+        }
+    }
+    let mut loaded = Vec::new();
+    for (source, config, service) in configs {
+        let service = flags.get("service").cloned().or(service).unwrap_or_else(|| "sshd".to_string());
+        let (config, problems) = args::with_config(config, &service)?;
+        for problem in problems {
+            eprintln!("{}", problem);
+        }
+        loaded.push((source, config));
+    }
+    Ok((loaded, flags))
+}
+
+fn parse_list(value: &str) -> Vec<&str> {
+    value.split(',').map(|v| v.trim()).filter(|v| !v.is_empty()).collect()
+}
+
+// ghauth connects itself, ghauthd only performs the module's operations
+fn build_client(options: &Options) -> Result<github::GithubClient, String> {
+    let config = github::ClientConfig {
+        helper_socket: None,
+        ..github::ClientConfig::from_args(options)?
+    };
+    github::GithubClient::new(&config).map_err(|err| err.to_string())
+}
+
+// Teams are only visible with a token, from the GitHub App or the environment
+fn org_token(app: Option<&github::GithubApp>, client: &github::GithubClient, org: &str) -> Result<Option<String>, github::GithubError> {
+    match app {
+        Some(app) => app.installation_token(client, org).map(Some),
+        None => Ok(std::env::var("GITHUB_TOKEN").ok()),
+    }
+}
+
+fn load_app(options: &Options) -> Result<Option<github::GithubApp>, String> {
+    match (options.get("app_id"), options.get("app_private_key")) {
+        (Some(app_id), Some(key)) => github::GithubApp::new(app_id, key).map(Some).map_err(|e| e.to_string()),
+        _ => Ok(None),
+    }
+}
+
+fn load_user_map(options: &Options) -> Result<usermap::UserMap, String> {
+    match options.get("user_map") {
+        Some(path) => usermap::UserMap::load(path),
+        None => Ok(usermap::UserMap::default()),
+    }
+}
+
+// Checks membership the way the module does, as the app or with the given token
+fn find_member(
+    client: &github::GithubClient,
+    app: Option<&github::GithubApp>,
+    token: &str,
+    login: &str,
+    orgs: &[&str],
+) -> Result<github::GithubUser, github::GithubError> {
+    match app {
+        Some(app) => github::GithubUser::from_app(client, app, login, orgs),
+        None => github::GithubUser::from_org_token(client, token, login, orgs),
+    }
+}
+
+fn check(args: &[String]) -> i32 {
+    let errors = match check_all(args, check_options) {
+        Ok(errors) => errors,
+        Err(err) => {
+            eprintln!("{}", err);
+            return 1;
+        }
+    };
+    if errors > 0 {
+        println!("{} problem(s) found", errors);
+        1
+    } else {
+        println!("No problems found");
+        0
+    }
+}
+
+// The checks of `check`, then whether GitHub can be reached from this host and the files the
+// module writes are safe. The last line is PASS or FAIL, for provisioning pipelines and monitoring
+fn doctor(args: &[String]) -> i32 {
+    let result = check_all(args, |options| check_options(options) + check_host(options));
+    match result {
+        Ok(0) => {
+            println!("PASS");
+            0
+        }
+        Ok(errors) => {
+            println!("FAIL: {} problem(s) found", errors);
+            1
+        }
+        Err(err) => {
+            eprintln!("{}", err);
+            println!("FAIL: {}", err);
+            1
+        }
+    }
+}
+
+// Runs the checks on every configuration and returns the number of problems
+fn check_all(args: &[String], checks: impl Fn(&Options) -> usize) -> Result<usize, String> {
+    let (configs, _) = load_options(args)?;
+    let mut errors = 0;
+    for (source, options) in &configs {
+        println!("Checking {}", source);
+        errors += checks(options);
+    }
+    Ok(errors)
+}
+
+fn check_host(options: &Options) -> usize {
+    let mut report = Report { errors: 0 };
+
+    // Problems building the client were reported by check_options
+    if let Ok(client) = build_client(options) {
+        // Names are resolved by the proxy when there is one
+        let proxied = options.contains_key("https_proxy")
+            || std::env::var_os("HTTPS_PROXY").is_some()
+            || std::env::var_os("https_proxy").is_some();
+        for endpoint in client.endpoints() {
+            if proxied {
+                println!("  skipped: DNS {}, requests go through a proxy", endpoint);
+            } else {
+                report.check(&format!("DNS {}", endpoint), resolve(endpoint));
+            }
+            report.check(&format!("TLS connection to {}", endpoint), client.check_reachable(endpoint));
+        }
+    }
+
+    let state_dir = Path::new(state::STATE_DIR);
+    report.check(&format!("state directory {}", state_dir.display()), owned_path(state_dir, 0o022));
+    report.check("token store key", owned_path(&state_dir.join("token.key"), 0o077));
+    report.check("token store", owned_path(&state_dir.join("tokens"), 0o077));
+    if options.get("log_backend").is_none_or(|backend| backend == "file") {
+        report.check(&format!("log file {}", logging::LOG_FILE), owned_path(Path::new(logging::LOG_FILE), 0o022));
+    }
+    if let Some(target) = options.get("audit_log") {
+        match target.strip_prefix("unix:") {
+            Some(socket) => {
+                report.check(&format!("audit socket {}", socket), socket_path(socket));
+            }
+            None => {
+                let parent = Path::new(target).parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+                report.check(&format!("audit log directory {}", parent.display()), directory(parent));
+                report.check(&format!("audit log {}", target), owned_path(Path::new(target), 0o077));
+            }
+        }
+    }
+    if let Some(dir) = options.get("metrics_dir") {
+        report.check(&format!("metrics directory {}", dir), directory(Path::new(dir)));
+    }
+    if options.contains_key("helper_socket") {
+        let socket = options
+            .get("helper_socket")
+            .filter(|socket| !socket.is_empty())
+            .map(|socket| socket.as_str())
+            .unwrap_or(helper::DEFAULT_SOCKET);
+        report.check(&format!("helper socket {}", socket), socket_path(socket));
+    }
+    report.errors
+}
+
+fn resolve(url: &str) -> Result<(), String> {
+    let (scheme, rest) = url.split_once("://").unwrap_or(("https", url));
+    let authority = rest.split('/').next().unwrap_or_default();
+    let authority = authority.rsplit('@').next().unwrap_or(authority);
+    // A port is only present after the closing bracket of an IPv6 address
+    let address = match authority.rfind(':') {
+        Some(colon) if !authority[colon..].contains(']') => authority.to_string(),
+        _ => format!("{}:{}", authority, if scheme == "http" { 80 } else { 443 }),
+    };
+    match address.to_socket_addrs().map(|mut addrs| addrs.next()) {
+        Ok(Some(_)) => Ok(()),
+        Ok(None) => Err(format!("{} has no addresses", authority)),
+        Err(err) => Err(format!("{}: {}", authority, err)),
+    }
+}
+
+// Owned by root, not a symlink and without the permission bits in `mask`. Missing paths are
+// fine, the module creates them on first use
+fn owned_path(path: &Path, mask: u32) -> Result<(), String> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.to_string()),
+    };
+    if metadata.file_type().is_symlink() {
+        return Err("is a symlink".to_string());
+    }
+    if metadata.uid() != unsafe { libc::geteuid() } {
+        return Err(format!("owned by UID {} instead of root", metadata.uid()));
+    }
+    if metadata.mode() & mask != 0 {
+        return Err(format!("mode {:o} is too open", metadata.mode() & 0o7777));
+    }
+    Ok(())
+}
+
+fn directory(path: &Path) -> Result<(), String> {
+    match fs::metadata(path) {
+        Ok(metadata) if metadata.is_dir() => Ok(()),
+        Ok(_) => Err("not a directory".to_string()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+fn socket_path(path: &str) -> Result<(), String> {
+    match fs::metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => Ok(()),
+        Ok(_) => Err("not a socket".to_string()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+struct Report {
+    errors: usize,
+}
+
+impl Report {
+    fn ok(&self, message: &str) {
+        println!("  ok: {}", message);
+    }
+
+    fn error(&mut self, message: &str) {
+        println!("  error: {}", message);
+        self.errors += 1;
+    }
+
+    fn check<T, E: std::fmt::Display>(&mut self, what: &str, result: Result<T, E>) -> Option<T> {
+        match result {
+            Ok(value) => {
+                self.ok(what);
+                Some(value)
+            }
+            Err(err) => {
+                self.error(&format!("{}: {}", what, err));
+                None
+            }
+        }
+    }
+}
+
+fn check_options(options: &Options) -> usize {
+    let mut report = Report { errors: 0 };
+
+    let mut unknown: Vec<&String> = options.keys().filter(|key| !args::is_known(key)).collect();
+    unknown.sort();
+    for key in unknown {
+        report.error(&format!("unknown option: {}", key));
+    }
+
+    let auth_mode = AuthMode::from_arg(options.get("auth_mode").map(|m| m.as_str()));
+    let orgs = options.get("org").map(|org| parse_list(org)).unwrap_or_default();
+    if auth_mode == Some(AuthMode::ActionsOidc) {
+        report.check("actions oidc", oidc::OidcPolicy::from_args(options));
+    } else if orgs.is_empty() && !options.contains_key("repo") && !options.contains_key("enterprise") {
+        report.error("org, enterprise or repo is required");
+    }
+    if options.contains_key("enterprise") && (options.contains_key("app_id") || options.contains_key("repo")) {
+        report.error("enterprise can't be combined with app_id or repo");
+    }
+    if let Some(permission) = options.get("min_permission")
+        && github::RepoPermission::from_arg(permission).is_none()
+    {
+        report.error(&format!("invalid min_permission: {}", permission));
+    }
+    match &auth_mode {
+        Some(AuthMode::Device) if !options.contains_key("client_id") => {
+            report.error("client_id is required with auth_mode=device")
+        }
+        Some(mode) => report.ok(&format!("auth_mode {:?}", mode)),
+        None => report.error(&format!("invalid auth_mode: {:?}", options.get("auth_mode"))),
+    }
+    if let Some(policy) = options.get("team_policy")
+        && github::TeamPolicy::from_arg(Some(policy)).is_none()
+    {
+        report.error(&format!("invalid team_policy: {}", policy));
+    }
+    if let Some(role) = options.get("require_role")
+        && github::GithubRole::from_arg(role).is_none()
+    {
+        report.error(&format!("invalid require_role: {}", role));
+    }
+    if let Some(mode) = options.get("deprovision")
+        && user::DeprovisionMode::from_arg(mode).is_none()
+    {
+        report.error(&format!("invalid deprovision: {}", mode));
+    }
+    if let Some(mode) = options.get("ephemeral_user")
+        && user::EphemeralMode::from_arg(mode).is_none()
+    {
+        report.error(&format!("invalid ephemeral_user: {}", mode));
+    }
+    report.check("features", args::check_features(options));
+    report.check("helper", args::check_helper(options));
+    report.check("failure policy", FailurePolicy::from_args(options));
+    report.check("lockout", Lockout::from_args(options, "check", None));
+    report.check("log backend", logging::set_backend(options.get("log_backend").map(|b| b.as_str())));
+    report.check("sudo policy", user::SudoPolicy::from_args(options));
+    report.check("priv_helper", user::PrivHelper::from_arg(options.get("priv_helper").map(|h| h.as_str())));
+    report.check("role groups", user::RoleGroups::parse(options.get("role_group_map").map(|m| m.as_str())));
+    report.check("key policy", github::KeyPolicy::from_args(options));
+    if let Some(pattern) = options.get("authorized_keys_path") {
+        report.check("authorized_keys_path", user::expand_keys_path(pattern, "user", "/home/user", 1000));
+    }
+    if let Some(Some(hours)) = report.check("access hours", access::AccessHours::from_args(options))
+        && !hours.teams().is_empty()
+        && !(options.contains_key("app_id") && options.contains_key("org"))
+    {
+        report.error("access hours for teams require org, app_id and app_private_key");
+    }
+    report.check("allow_cidr", access::SourcePolicy::from_args(options));
+    report.check("deny list", access::DenyList::from_args(options));
+    report.check("access policy", policy::Policy::from_args(options));
+    if let Some(banner) = options.get("banner") {
+        report.check("banner", github::RepoFile::parse(banner));
+    }
+    report.check("uid_offset", user::parse_uid_offset(options.get("uid_offset").map(|o| o.as_str())));
+    report.check("account_expiry_days", user::parse_expiry_days(options.get("account_expiry_days").map(|d| d.as_str())));
+    if let Some(path) = options.get("user_map") {
+        report.check(&format!("user map {}", path), usermap::UserMap::load(path));
+    }
+    if let Some(path) = options.get("totp_secret_file") {
+        report.check(&format!("TOTP secrets {}", path), totp::TotpSecrets::load(path));
+    }
+    let app = match (options.get("app_id"), options.get("app_private_key")) {
+        (Some(app_id), Some(key)) => report.check("GitHub App key", github::GithubApp::new(app_id, key)),
+        (None, None) => None,
+        _ => {
+            report.error("app_id and app_private_key must be given together");
+            None
+        }
+    };
+    let owner_token = report.check("owner token", github::owner_token_from_args(options)).flatten();
+    if options.contains_key("require_2fa") && app.is_none() && owner_token.is_none() {
+        report.error("require_2fa needs app_id and app_private_key, or owner_token_file");
+    }
+    if options.contains_key("store_token") && options.contains_key("revoke_token_after_auth") {
+        report.error("store_token can't be combined with revoke_token_after_auth");
+    }
+
+    let Some(client) = report.check("GitHub client", build_client(options)) else {
+        return report.errors;
+    };
+    if auth_mode == Some(AuthMode::ActionsOidc)
+        && let Ok(policy) = oidc::OidcPolicy::from_args(options)
+    {
+        let issuer = policy.issuer(&client);
+        report.check(&format!("ID token keys of {}", issuer), client.actions_jwks(&issuer));
+    }
+
+    // Requesting a device code is harmless, it simply expires unused
+    let oauth_app = report.check("OAuth app", github::OAuthApp::from_args(options)).flatten();
+    if auth_mode == Some(AuthMode::Device)
+        && let Some(app) = &oauth_app
+    {
+        report.check("device code request for client_id", client.get_auth_code(app));
+    }
+
+    let teams = options.get("team").map(|team| parse_list(team)).unwrap_or_default();
+    for org in &orgs {
+        let token = report
+            .check(&format!("token for {}", org), org_token(app.as_ref(), &client, org))
+            .flatten();
+        report.check(&format!("organization {}", org), client.check_org(org, token.as_deref()));
+        let Some(token) = &token else {
+            for team in &teams {
+                println!("  skipped: team {}/{}, set GITHUB_TOKEN to check teams", org, team);
+            }
+            continue;
+        };
+        // Names are looked up like the module does, in the organization's teams
+        let org_teams = match teams.iter().all(|team| github::is_team_slug(team)) {
+            true => None,
+            false => report.check(&format!("teams of {}", org), client.org_teams(org, token)),
+        };
+        for team in &teams {
+            let what = format!("team {}/{}", org, team);
+            match &org_teams {
+                Some(org_teams) => match github::team_slug(team, org_teams) {
+                    Some(slug) => report.ok(&format!("{} ({})", what, slug)),
+                    None => report.error(&format!("{}: no team with this name or slug", what)),
+                },
+                None => {
+                    report.check(&what, client.check_team(org, team, token));
+                }
+            }
+        }
+    }
+    if let Some(enterprise) = options.get("enterprise") {
+        match std::env::var("GITHUB_TOKEN") {
+            Ok(token) => {
+                let what = format!("enterprise {}", enterprise);
+                if let Some(orgs) = report.check(&what, client.enterprise_orgs(enterprise, &token)) {
+                    println!("  organizations of {}: {}", enterprise, orgs.join(", "));
+                }
+            }
+            Err(_) => println!("  skipped: enterprise {}, set GITHUB_TOKEN to check it", enterprise),
+        }
+    }
+    report.errors
+}
+
+// Creates the accounts of a team's members ahead of their first login and imports their keys
+fn provision(args: &[String]) -> i32 {
+    let (configs, flags) = match load_options(args) {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            eprintln!("{}", err);
+            return 1;
+        }
+    };
+    let Some(team) = flags.get("team") else { usage() };
+    let options = &configs[0].1;
+    user::set_authorized_keys_path(options.get("authorized_keys_path").map(|p| p.as_str()));
+    user::set_dry_run(options.contains_key("dry_run"));
+    if let Err(err) = user::set_priv_helper(options.get("priv_helper").map(|h| h.as_str())) {
+        eprintln!("{}", err);
+        return 1;
+    }
+    match provision_team(options, team) {
+        Ok(0) => 0,
+        Ok(failed) => {
+            eprintln!("Failed to provision {} user(s)", failed);
+            1
+        }
+        Err(err) => {
+            eprintln!("{}", err);
+            1
+        }
+    }
+}
+
+// How provisioned accounts are set up, shared by every member of the team
+struct AccountSettings {
+    sudo_policy: Option<user::SudoPolicy>,
+    uid_offset: Option<u32>,
+    account_expiry_days: Option<u32>,
+    bind_github_id: bool,
+    key_policy: github::KeyPolicy,
+    groups: Vec<String>,
+    include_child_teams: bool,
+}
+
+impl AccountSettings {
+    fn from_options(options: &Options) -> Result<Self, String> {
+        Ok(AccountSettings {
+            sudo_policy: user::SudoPolicy::from_args(options)?,
+            uid_offset: user::parse_uid_offset(options.get("uid_offset").map(|o| o.as_str()))?,
+            account_expiry_days: user::parse_expiry_days(options.get("account_expiry_days").map(|d| d.as_str()))?,
+            bind_github_id: options.contains_key("bind_github_id") || options.contains_key("deprovision"),
+            key_policy: github::KeyPolicy::from_args(options)?,
+            groups: options
+                .get("groups")
+                .map(|g| parse_list(g).into_iter().map(String::from).collect())
+                .unwrap_or_default(),
+            include_child_teams: options.contains_key("include_child_teams"),
+        })
+    }
+}
+
+fn provision_team(options: &Options, team: &str) -> Result<usize, String> {
+    let orgs = options.get("org").map(|org| parse_list(org)).unwrap_or_default();
+    if orgs.is_empty() {
+        return Err("org is required".to_string());
+    }
+    let client = build_client(options)?;
+    let app = load_app(options)?;
+    let user_map = load_user_map(options)?;
+    let settings = AccountSettings::from_options(options)?;
+
+    let mut failed = 0;
+    for org in orgs {
+        let token = org_token(app.as_ref(), &client, org)
+            .map_err(|e| e.to_string())?
+            .ok_or("Listing team members needs app_id and app_private_key, or GITHUB_TOKEN")?;
+        let members = match client.team_members(org, team, &token) {
+            Ok(members) => members,
+            Err(github::GithubError::NotFound(_)) => {
+                println!("Team {} not found in {}", team, org);
+                continue;
+            }
+            Err(err) => return Err(format!("Failed to list members of {}/{}: {}", org, team, err)),
+        };
+
+        for login in members {
+            let local_user = user_map.local_user(&login);
+            match provision_user(&client, app.as_ref(), &token, org, &login, &local_user, &settings) {
+                Ok(message) => println!("{} ({}): {}", local_user, login, message),
+                Err(err) => {
+                    println!("{} ({}): {}", local_user, login, err);
+                    failed += 1;
+                }
+            }
+        }
+    }
+    Ok(failed)
+}
+
+fn provision_user(
+    client: &github::GithubClient,
+    app: Option<&github::GithubApp>,
+    token: &str,
+    org: &str,
+    login: &str,
+    local_user: &str,
+    settings: &AccountSettings,
+) -> Result<String, String> {
+    let github_user = find_member(client, app, token, login, &[org]).map_err(|e| e.to_string())?;
+
+    if settings.bind_github_id
+        && let state::IdentityCheck::Mismatch(expected) = state::bind_identity(local_user, github_user.id(), login)?
+    {
+        return Err(format!("bound to a different GitHub ID {}", expected));
+    }
+    let sudo_policy = settings.sudo_policy.as_ref();
+    let privileged = match sudo_policy.and_then(|policy| policy.teams.as_ref()) {
+        Some(teams) => {
+            let teams: Vec<&str> = teams.iter().map(|t| t.as_str()).collect();
+            let found = match settings.include_child_teams {
+                true => github_user.check_team_trees(&teams, github::TeamPolicy::Any),
+                false => github_user.check_teams(&teams, github::TeamPolicy::Any),
+            };
+            found.map_err(|e| e.to_string())?
+        }
+        None => true,
+    };
+    let sudo_grant = sudo_policy.filter(|_| privileged).map(|policy| &policy.grant);
+    let uid = settings.uid_offset.map(|offset| user::uid_for(offset, github_user.id())).transpose()?;
+    let existed = user::ensure_user_exists(local_user, sudo_grant, uid, &settings.groups)?;
+    if existed
+        && let Some(policy) = sudo_policy.filter(|policy| policy.teams.is_some())
+    {
+        user::set_sudo(local_user, &policy.grant, privileged)?;
+    }
+    if let Some(days) = settings.account_expiry_days {
+        user::set_account_expiry(local_user, days)?;
+    }
+    let keys = match KeySync::new(local_user).with_policy(settings.key_policy.clone()).from_user(&github_user)? {
+        keys::KeyChange::Updated => "keys imported",
+        keys::KeyChange::Unchanged => "keys unchanged",
+    };
+    Ok(format!("{}, {}", if existed { "already exists" } else { "created" }, keys))
+}
+
+// Refreshes the keys of every GitHub-backed user, meant to run from a timer so revoked keys
+// stop working without waiting for the next interactive login
+fn sync_keys(args: &[String]) -> i32 {
+    let configs = match load_options(args) {
+        Ok((configs, _)) => configs,
+        Err(err) => {
+            eprintln!("{}", err);
+            return 1;
+        }
+    };
+    user::set_authorized_keys_path(configs[0].1.get("authorized_keys_path").map(|p| p.as_str()));
+    user::set_dry_run(configs[0].1.contains_key("dry_run"));
+    if let Err(err) = user::set_priv_helper(configs[0].1.get("priv_helper").map(|h| h.as_str())) {
+        eprintln!("{}", err);
+        return 1;
+    }
+    match sync_all_keys(&configs[0].1) {
+        Ok(0) => 0,
+        Ok(failed) => {
+            eprintln!("Failed to sync keys of {} user(s)", failed);
+            1
+        }
+        Err(err) => {
+            eprintln!("{}", err);
+            1
+        }
+    }
+}
+
+fn sync_all_keys(options: &Options) -> Result<usize, String> {
+    let orgs = options.get("org").map(|org| parse_list(org)).unwrap_or_default();
+    if orgs.is_empty() {
+        return Err("org is required".to_string());
+    }
+    let deprovision = match options.get("deprovision") {
+        Some(mode) => Some(user::DeprovisionMode::from_arg(mode).ok_or(format!("Invalid deprovision: {}", mode))?),
+        None => None,
+    };
+    let sudo = user::SudoPolicy::from_args(options)?.map(|policy| policy.grant);
+    let key_policy = github::KeyPolicy::from_args(options)?;
+    let client = build_client(options)?;
+    let app = load_app(options)?;
+    let user_map = load_user_map(options)?;
+    let oauth_app = github::OAuthApp::from_args(options)?;
+    let token = org_token(app.as_ref(), &client, orgs[0])
+        .map_err(|e| e.to_string())?
+        .ok_or("Checking membership needs app_id and app_private_key, or GITHUB_TOKEN")?;
+
+    let identities = state::identities()?;
+    let mut users: BTreeSet<String> = identities.keys().cloned().collect();
+    users.extend(state::key_etag_users()?);
+
+    let mut failed = 0;
+    for local_user in users {
+        // Bound users are looked up by ID, so renamed logins are followed
+        let login = match identities.get(&local_user) {
+            Some(identity) => client.login_for_id(identity.github_id, &token),
+            None => Ok(user_map.github_logins(&local_user).remove(0)),
+        };
+        // The user's own stored token also sees the key details needed by key restrictions
+        let member = login.and_then(|login| match tokens::access_token(&client, oauth_app.as_ref(), &local_user) {
+            Ok(Some(user_token)) => match github::GithubUser::from_pat(&client, &user_token, &login, &orgs) {
+                Err(github::GithubError::Unauthorized(_)) => {
+                    let _ = tokens::remove_rejected(&local_user, &user_token);
+                    find_member(&client, app.as_ref(), &token, &login, &orgs)
+                }
+                member => member,
+            },
+            _ => find_member(&client, app.as_ref(), &token, &login, &orgs),
+        });
+        let result = match member {
+            Ok(github_user) => KeySync::new(&local_user).with_policy(key_policy.clone()).from_user(&github_user).map(|sync| match sync {
+                keys::KeyChange::Updated => "keys updated".to_string(),
+                keys::KeyChange::Unchanged => "keys unchanged".to_string(),
+            }),
+            Err(github::GithubError::NotFound(_)) => remove_access(&local_user, deprovision, sudo.as_ref()),
+            Err(err) => Err(err.to_string()),
+        };
+        match result {
+            Ok(message) => println!("{}: {}", local_user, message),
+            Err(err) => {
+                println!("{}: {}", local_user, err);
+                failed += 1;
+            }
+        }
+    }
+    Ok(failed)
+}
+
+// Prints the certificate principals of a local user, one per line, for sshd's
+// AuthorizedPrincipalsCommand. Nothing is printed for users outside the organizations
+fn principals(args: &[String]) -> i32 {
+    let (configs, flags) = match load_options(args) {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            eprintln!("{}", err);
+            return 1;
+        }
+    };
+    let Some(local_user) = flags.get("user") else { usage() };
+    match user_principals(&configs[0].1, &local_user.to_ascii_lowercase()) {
+        Ok(principals) => {
+            for principal in principals {
+                println!("{}", principal);
+            }
+            0
+        }
+        Err(err) => {
+            eprintln!("{}", err);
+            1
+        }
+    }
+}
+
+// Lists what is recorded about each GitHub-backed user, as a table or as the stored JSON
+fn users(args: &[String]) -> i32 {
+    let json = match args {
+        [] => false,
+        [flag] if flag == "--json" => true,
+        _ => usage(),
+    };
+    let users = match state::users() {
+        Ok(users) => users,
+        Err(err) => {
+            eprintln!("{}", err);
+            return 1;
+        }
+    };
+    if json {
+        match serde_json::to_string_pretty(&users) {
+            Ok(users) => println!("{}", users),
+            Err(err) => {
+                eprintln!("Failed to serialize users: {}", err);
+                return 1;
+            }
+        }
+        return 0;
+    }
+
+    let time = |at: Option<i64>| match at.and_then(|at| chrono::DateTime::from_timestamp(at, 0)) {
+        Some(at) => at.format("%Y-%m-%d %H:%M").to_string(),
+        None => "-".to_string(),
+    };
+    println!("USER\tGITHUB\tID\tBOUND\tLAST LOGIN\tTOKEN EXPIRES\tKEYS");
+    for (local_user, record) in users {
+        let token = match &record.token {
+            Some(token) if token.expires_at.is_none() => "never".to_string(),
+            Some(token) => time(token.expires_at),
+            None => "-".to_string(),
+        };
+        println!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            local_user,
+            record.github_login.as_deref().unwrap_or("-"),
+            record.github_id.map(|id| id.to_string()).unwrap_or_else(|| "-".to_string()),
+            if record.bound { "yes" } else { "no" },
+            time(record.last_login),
+            token,
+            record.keys.len()
+        );
+    }
+    0
+}
+
+// `team` or `team:principal` entries, a team's members get its slug unless a principal is given
+fn parse_principal_teams(value: &str) -> Vec<(&str, &str)> {
+    parse_list(value)
+        .into_iter()
+        .map(|entry| match entry.split_once(':') {
+            Some((team, principal)) => (team.trim(), principal.trim()),
+            None => (entry, entry),
+        })
+        .collect()
+}
+
+fn user_principals(options: &Options, local_user: &str) -> Result<Vec<String>, String> {
+    let teams = options.get("principal_teams").map(|teams| parse_principal_teams(teams)).unwrap_or_default();
+    if teams.is_empty() {
+        return Err("principal_teams is required".to_string());
+    }
+    let orgs = options.get("org").map(|org| parse_list(org)).unwrap_or_default();
+    if orgs.is_empty() {
+        return Err("org is required".to_string());
+    }
+    let cache_ttl = match options.get("principals_cache_ttl") {
+        Some(ttl) => ttl.parse::<i64>().map_err(|_| format!("Invalid principals_cache_ttl: {}", ttl))?,
+        None => 300,
+    };
+    if cache_ttl > 0
+        && let Some(principals) = state::cached_principals(local_user, cache_ttl)?
+    {
+        return Ok(principals);
+    }
+
+    let client = build_client(options)?;
+    let app = load_app(options)?;
+    let token = org_token(app.as_ref(), &client, orgs[0])
+        .map_err(|e| e.to_string())?
+        .ok_or("Checking teams needs app_id and app_private_key, or GITHUB_TOKEN")?;
+    // Bound users are looked up by ID, so renamed logins are followed
+    let login = match state::lookup_identity(local_user)? {
+        Some(identity) => client.login_for_id(identity.github_id, &token).map_err(|e| e.to_string())?,
+        None => load_user_map(options)?.github_logins(local_user).remove(0),
+    };
+    let include_child_teams = options.contains_key("include_child_teams");
+    let mut principals = Vec::new();
+    match find_member(&client, app.as_ref(), &token, &login, &orgs) {
+        Ok(github_user) => {
+            for (team, principal) in teams {
+                let in_team = match include_child_teams {
+                    true => github_user.check_team_trees(&[team], github::TeamPolicy::Any),
+                    false => github_user.check_teams(&[team], github::TeamPolicy::Any),
+                };
+                let in_team = in_team
+                    .map_err(|e| format!("Failed to check team {}: {}", team, e))?;
+                if in_team && !principals.iter().any(|p| p == principal) {
+                    principals.push(principal.to_string());
+                }
+            }
+        }
+        Err(github::GithubError::NotFound(_)) => {}
+        Err(err) => return Err(err.to_string()),
+    }
+    if cache_ttl > 0 {
+        state::cache_principals(local_user, &principals)?;
+    }
+    Ok(principals)
+}
+
+fn remove_access(
+    local_user: &str,
+    deprovision: Option<user::DeprovisionMode>,
+    sudo: Option<&user::SudoGrant>,
+) -> Result<String, String> {
+    match deprovision {
+        Some(mode) => user::deprovision_user(local_user, mode, sudo)?,
+        None => user::set_managed_keys(local_user, "")?,
+    }
+    state::set_key_etag(local_user, None)?;
+    Ok("no longer a member, keys removed".to_string())
+}
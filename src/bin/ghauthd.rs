@@ -0,0 +1,117 @@
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::process;
+use std::thread;
+
+use ssh_github_auth::{args, github, helper, logging, nss};
+
+// Options of the daemon itself, next to the module's
+const DAEMON_OPTIONS: &[&str] = &["nss_gid", "nss_home", "nss_refresh", "nss_shell", "nss_socket", "socket"];
+
+// Binds the socket, replacing one a previous instance may have left behind
+fn bind(socket: &str, mode: u32) -> UnixListener {
+    if let Some(dir) = Path::new(socket).parent()
+        && let Err(err) = fs::create_dir_all(dir)
+    {
+        eprintln!("Failed to create {}: {}", dir.display(), err);
+        process::exit(1);
+    }
+    let _ = fs::remove_file(socket);
+    let listener = match UnixListener::bind(socket) {
+        Ok(listener) => listener,
+        Err(err) => {
+            eprintln!("Failed to bind {}: {}", socket, err);
+            process::exit(1);
+        }
+    };
+    if let Err(err) = fs::set_permissions(socket, fs::Permissions::from_mode(mode)) {
+        eprintln!("Failed to set permissions on {}: {}", socket, err);
+        process::exit(1);
+    }
+    listener
+}
+
+fn main() {
+    // Takes the same key=value options as the PAM module, parsed the same way
+    let (args, problems) =
+        args::parse_known(std::env::args().skip(1), |key| args::is_known(key) || DAEMON_OPTIONS.contains(&key));
+    for problem in problems {
+        eprintln!("{}", problem);
+    }
+    logging::set_debug(args.contains_key("debug"));
+    if let Err(err) = logging::set_backend(args.get("log_backend").map(|b| b.as_str())) {
+        eprintln!("{}", err);
+        process::exit(2);
+    }
+
+    // ghauthd connects itself even when the options are shared with a module using it
+    let config = match github::ClientConfig::from_args(&args) {
+        Ok(config) => github::ClientConfig { helper_socket: None, ..config },
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(2);
+        }
+    };
+    let client = match github::GithubClient::new(&config) {
+        Ok(client) => client,
+        Err(err) => {
+            eprintln!("Failed to create GitHub client: {}", err);
+            process::exit(1);
+        }
+    };
+
+    let directory = match nss::Directory::from_args(&args) {
+        Ok(directory) => directory,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(2);
+        }
+    };
+    if let Some(directory) = directory {
+        // Members are listed as the GitHub App, or with a token from the environment
+        let app = match (args.get("app_id"), args.get("app_private_key")) {
+            (Some(app_id), Some(key)) => match github::GithubApp::new(app_id, key) {
+                Ok(app) => Some(app),
+                Err(err) => {
+                    eprintln!("Failed to load GitHub App credentials: {}", err);
+                    process::exit(2);
+                }
+            },
+            _ => None,
+        };
+        let token = std::env::var("GITHUB_TOKEN").ok();
+        if app.is_none() && token.is_none() {
+            eprintln!("nss_socket requires app_id and app_private_key, or GITHUB_TOKEN");
+            process::exit(2);
+        }
+        let nss_socket = match args.get("nss_socket").map(|s| s.as_str()) {
+            Some("") | None => nss::DEFAULT_SOCKET,
+            Some(socket) => socket,
+        };
+        // Every process resolving users connects, and only gets passwd entries
+        let listener = bind(nss_socket, 0o666);
+        let entries = nss::load_entries();
+        let client = client.clone();
+        let fresh = entries.clone();
+        thread::spawn(move || {
+            nss::keep_fresh(&directory, &client, &fresh, |org| match (&app, &token) {
+                (Some(app), _) => app.installation_token(&client, org),
+                (None, token) => Ok(token.clone().unwrap_or_default()),
+            })
+        });
+        thread::spawn(move || nss::serve(listener, entries));
+        logging::log_to_file(&format!("ghauthd serving organization members on {}", nss_socket));
+    }
+
+    let socket = args
+        .get("socket")
+        .map(|s| s.as_str())
+        .unwrap_or(helper::DEFAULT_SOCKET);
+    // Only root (sshd) may use the helper
+    let listener = bind(socket, 0o600);
+
+    logging::log_to_file(&format!("ghauthd listening on {}", socket));
+    helper::serve(listener, client);
+}
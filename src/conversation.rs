@@ -0,0 +1,129 @@
+use std::ffi::{CStr, CString};
+use std::ptr;
+
+use pam_sys::{wrapped::get_item, PamHandle, PamItemType, PamMessageStyle, PamReturnCode};
+
+use crate::pam::Pam;
+
+fn conversation(pamh: *mut PamHandle) -> Option<&'static pam_sys::PamConversation> {
+    let mut conv_ptr: *const libc::c_void = ptr::null();
+    let ret = unsafe { get_item(&*pamh, PamItemType::CONV, &mut conv_ptr) };
+    let conv_ptr = conv_ptr as *const pam_sys::PamConversation;
+    if ret != PamReturnCode::SUCCESS || conv_ptr.is_null() {
+        return None;
+    }
+    let conv = unsafe { &*conv_ptr };
+    conv.conv.map(|_| conv)
+}
+
+// Whether the application can talk to the user at all, cron and some SFTP-only clients can't
+pub fn available(pamh: *mut PamHandle) -> bool {
+    conversation(pamh).is_some()
+}
+
+// Sends all messages in one conversation call and returns one entry per message,
+// the text typed by the user for prompts and None for informational messages
+pub fn converse(
+    pamh: *mut PamHandle,
+    messages: &[(PamMessageStyle, &str)],
+) -> Result<Vec<Option<String>>, PamReturnCode> {
+    if messages.is_empty() {
+        return Ok(Vec::new());
+    }
+    let texts = messages
+        .iter()
+        .map(|(_, text)| CString::new(*text).map_err(|_| PamReturnCode::BUF_ERR))
+        .collect::<Result<Vec<_>, _>>()?;
+    let msgs: Vec<pam_sys::PamMessage> = messages
+        .iter()
+        .zip(&texts)
+        .map(|((style, _), text)| pam_sys::PamMessage {
+            msg_style: *style as i32,
+            msg: text.as_ptr(),
+        })
+        .collect();
+    // An array of pointers into a contiguous array works with both the Linux-PAM
+    // and the Solaris reading of the message argument
+    let pmsgs: Vec<*const pam_sys::PamMessage> = msgs.iter().map(|m| m as *const _).collect();
+
+    let conv = conversation(pamh).ok_or(PamReturnCode::CONV_ERR)?;
+    let conv_fn = conv.conv.ok_or(PamReturnCode::CONV_ERR)?;
+    let mut responses = Responses {
+        ptr: ptr::null_mut(),
+        len: messages.len(),
+    };
+    let ret = PamReturnCode::from(conv_fn(
+        messages.len() as libc::c_int,
+        pmsgs.as_ptr() as *mut *mut pam_sys::PamMessage,
+        &mut responses.ptr as *mut *mut pam_sys::PamResponse,
+        conv.data_ptr,
+    ));
+    if ret != PamReturnCode::SUCCESS {
+        return Err(ret);
+    }
+    if responses.ptr.is_null() {
+        return Err(PamReturnCode::CONV_ERR);
+    }
+
+    messages
+        .iter()
+        .enumerate()
+        .map(|(i, (style, _))| match style {
+            // A prompt without an answer means the application is broken, not that the user typed nothing
+            PamMessageStyle::PROMPT_ECHO_OFF | PamMessageStyle::PROMPT_ECHO_ON => {
+                responses.take(i).map(Some).ok_or(PamReturnCode::CONV_ERR)
+            }
+            _ => Ok(None),
+        })
+        .collect()
+}
+
+// The response array returned by the conversation function. Dropping it frees every
+// response string, answered or not, and the array itself on every path
+struct Responses {
+    ptr: *mut pam_sys::PamResponse,
+    len: usize,
+}
+
+impl Responses {
+    fn take(&mut self, i: usize) -> Option<String> {
+        let resp = unsafe { &mut (*self.ptr.add(i)).resp };
+        if resp.is_null() {
+            return None;
+        }
+        let text = unsafe { CStr::from_ptr(*resp) }.to_string_lossy().into_owned();
+        free_response(*resp);
+        *resp = ptr::null_mut();
+        Some(text)
+    }
+}
+
+impl Drop for Responses {
+    fn drop(&mut self) {
+        if self.ptr.is_null() {
+            return;
+        }
+        for i in 0..self.len {
+            let resp = unsafe { (*self.ptr.add(i)).resp };
+            if !resp.is_null() {
+                free_response(resp);
+            }
+        }
+        unsafe { libc::free(self.ptr as *mut libc::c_void) };
+    }
+}
+
+// Responses may hold tokens, so they are wiped before being freed
+fn free_response(resp: *mut libc::c_char) {
+    unsafe {
+        let len = libc::strlen(resp);
+        ptr::write_bytes(resp, 0, len);
+        libc::free(resp as *mut libc::c_void);
+    }
+}
+
+// Sends a single message, returning the response for prompts and an empty string otherwise
+pub fn prompt(pam: &dyn Pam, text: &str, style: PamMessageStyle) -> Result<String, PamReturnCode> {
+    let mut responses = pam.converse(&[(style, text)])?;
+    Ok(responses.pop().flatten().unwrap_or_default())
+}
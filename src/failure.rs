@@ -0,0 +1,301 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use pam_sys::{PamMessageStyle, PamReturnCode};
+
+use crate::github::{GithubError, GithubUser};
+use crate::pam::Pam;
+use crate::{conversation, logging, state};
+
+// Reasons authentication can fail, so admins can choose how each one stacks with other modules
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Failure {
+    NotInOrg,
+    PendingMembership,
+    DeniedUser,
+    InvalidUser,
+    Unauthorized,
+    NetworkError,
+    MissingRole,
+    NotInTeam,
+    IdentityMismatch,
+    No2fa,
+    EmailDomain,
+    SsoRequired,
+    MissingPermission,
+    Totp,
+    NotInPolicy,
+    ServiceError,
+}
+
+impl Failure {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "not_in_org" => Some(Failure::NotInOrg),
+            "pending_membership" => Some(Failure::PendingMembership),
+            "denied_user" => Some(Failure::DeniedUser),
+            "invalid_user" => Some(Failure::InvalidUser),
+            "unauthorized" => Some(Failure::Unauthorized),
+            "network_error" => Some(Failure::NetworkError),
+            "missing_role" => Some(Failure::MissingRole),
+            "not_in_team" => Some(Failure::NotInTeam),
+            "identity_mismatch" => Some(Failure::IdentityMismatch),
+            "no_2fa" => Some(Failure::No2fa),
+            "email_domain" => Some(Failure::EmailDomain),
+            "sso_required" => Some(Failure::SsoRequired),
+            "missing_permission" => Some(Failure::MissingPermission),
+            "totp" => Some(Failure::Totp),
+            "not_in_policy" => Some(Failure::NotInPolicy),
+            "service_error" => Some(Failure::ServiceError),
+            _ => None,
+        }
+    }
+
+    fn default_code(&self) -> PamReturnCode {
+        match self {
+            Failure::NetworkError | Failure::ServiceError => PamReturnCode::SERVICE_ERR,
+            _ => PamReturnCode::USER_UNKNOWN,
+        }
+    }
+
+    // What the user is told, never more than the reason
+    pub fn message(&self) -> &'static str {
+        match self {
+            Failure::NotInOrg => "User not found in organization",
+            Failure::PendingMembership => "Your invitation to the organization has not been accepted yet",
+            Failure::DeniedUser => "This GitHub account is not allowed to log in",
+            Failure::InvalidUser => "This GitHub account can't be used to log in as this user",
+            Failure::Unauthorized => "Unauthorized access",
+            Failure::NetworkError => "GitHub can't be reached right now, please try again later",
+            Failure::MissingRole => "User does not have the required organization role",
+            Failure::NotInTeam => "User is not a member of the required team",
+            Failure::IdentityMismatch => "This account is bound to a different GitHub user",
+            Failure::No2fa => "Two-factor authentication must be enabled on your GitHub account to log in",
+            Failure::EmailDomain => "A verified email address in an allowed domain is required on your GitHub account",
+            Failure::SsoRequired => "Your organization requires SAML single sign-on",
+            Failure::MissingPermission => "User does not have the required permission on the repository",
+            Failure::Totp => "Invalid one-time password",
+            Failure::NotInPolicy => "You are not allowed to log in to this host",
+            Failure::ServiceError => "Authentication is not available right now, please contact your administrator",
+        }
+    }
+
+    pub fn from_error(err: &GithubError) -> Self {
+        match err {
+            GithubError::NotFound(_) => Failure::NotInOrg,
+            GithubError::InvalidUser(_) => Failure::InvalidUser,
+            GithubError::Unauthorized(_) | GithubError::DeviceFlow { .. } => Failure::Unauthorized,
+            GithubError::SsoRequired(_) => Failure::SsoRequired,
+            GithubError::PermissionDenied(_) => Failure::MissingPermission,
+            GithubError::Request { .. } | GithubError::Helper { .. } => Failure::NetworkError,
+            // Server errors mean GitHub is having trouble, not that the request was wrong
+            GithubError::Api(api) if api.status >= 500 => Failure::NetworkError,
+            _ => Failure::ServiceError,
+        }
+    }
+}
+
+fn parse_code(name: &str) -> Option<PamReturnCode> {
+    match name {
+        "auth_err" => Some(PamReturnCode::AUTH_ERR),
+        "authinfo_unavail" => Some(PamReturnCode::AUTHINFO_UNAVAIL),
+        "ignore" => Some(PamReturnCode::IGNORE),
+        "perm_denied" => Some(PamReturnCode::PERM_DENIED),
+        "service_err" => Some(PamReturnCode::SERVICE_ERR),
+        "user_unknown" => Some(PamReturnCode::USER_UNKNOWN),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ReturnCodes {
+    overrides: HashMap<Failure, PamReturnCode>,
+}
+
+impl ReturnCodes {
+    // Parses `reason:code` pairs split with `,`, e.g. `network_error:ignore,not_in_org:auth_err`
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let mut overrides = HashMap::new();
+        for pair in value.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+            let (reason, code) = pair
+                .split_once(':')
+                .ok_or_else(|| format!("Invalid return code mapping: {}", pair))?;
+            let failure = Failure::from_name(reason.trim())
+                .ok_or_else(|| format!("Unknown failure reason: {}", reason))?;
+            let code = parse_code(code.trim())
+                .ok_or_else(|| format!("Unknown PAM return code: {}", code))?;
+            overrides.insert(failure, code);
+        }
+        Ok(ReturnCodes { overrides })
+    }
+
+    pub fn code(&self, failure: Failure) -> PamReturnCode {
+        self.overrides
+            .get(&failure)
+            .copied()
+            .unwrap_or_else(|| failure.default_code())
+    }
+}
+
+// What to do when GitHub cannot be contacted
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Unreachable {
+    Deny,
+    Ignore,
+    // Accept users whose last successful authentication is at most this many seconds old
+    AllowCached(i64),
+}
+
+#[derive(Debug)]
+pub struct FailurePolicy {
+    return_codes: ReturnCodes,
+    on_unreachable: Unreachable,
+}
+
+impl FailurePolicy {
+    pub fn from_args(args: &HashMap<String, String>) -> Result<Self, String> {
+        let return_codes = match args.get("return_codes") {
+            Some(codes) => ReturnCodes::parse(codes)?,
+            None => ReturnCodes::default(),
+        };
+        let on_unreachable = match args.get("on_unreachable").map(|p| p.as_str()) {
+            Some("deny") | None => Unreachable::Deny,
+            Some("ignore") => Unreachable::Ignore,
+            Some("allow_cached") => {
+                let ttl = match args.get("cache_ttl") {
+                    Some(ttl) => match ttl.parse::<i64>() {
+                        Ok(ttl) if ttl > 0 => ttl,
+                        _ => return Err(format!("Invalid cache_ttl: {}", ttl)),
+                    },
+                    None => 86400,
+                };
+                Unreachable::AllowCached(ttl)
+            }
+            Some(other) => return Err(format!("Invalid on_unreachable policy: {}", other)),
+        };
+        Ok(FailurePolicy { return_codes, on_unreachable })
+    }
+
+    pub fn code(&self, failure: Failure) -> PamReturnCode {
+        self.return_codes.code(failure)
+    }
+
+    pub fn error_code(&self, err: &GithubError, local_user: &str) -> PamReturnCode {
+        let failure = Failure::from_error(err);
+        if failure != Failure::NetworkError {
+            return self.code(failure);
+        }
+        match self.on_unreachable {
+            Unreachable::Deny => self.code(failure),
+            Unreachable::Ignore => PamReturnCode::IGNORE,
+            // A recent login proves nothing about who is asking now, so on its own it only
+            // leaves the decision to the other modules of the stack
+            Unreachable::AllowCached(_) if self.cached(err, local_user) => PamReturnCode::IGNORE,
+            Unreachable::AllowCached(_) => self.code(failure),
+        }
+    }
+
+    // Whether GitHub is unreachable and on_unreachable=allow_cached remembers a recent login
+    pub fn cached(&self, err: &GithubError, local_user: &str) -> bool {
+        let Unreachable::AllowCached(ttl) = self.on_unreachable else {
+            return false;
+        };
+        if Failure::from_error(err) != Failure::NetworkError {
+            return false;
+        }
+        match state::last_success(local_user) {
+            Ok(Some(at)) => chrono::Utc::now().timestamp() - at <= ttl,
+            Ok(None) => false,
+            Err(err) => {
+                logging::log_to_file(&format!("Failed to look up cached authentication: {}", err));
+                false
+            }
+        }
+    }
+
+    // Remembered for on_unreachable=allow_cached, and for sudo_grace which may be set
+    // for another service than the one logging in
+    pub fn record_success(&self, local_user: &str, github_user: &GithubUser) {
+        if let Err(err) = state::record_success(local_user, github_user.id(), &github_user.username) {
+            logging::log_to_file(&format!("Failed to record successful authentication: {}", err));
+        }
+    }
+}
+
+// Failures reach the user as one short message once authentication is over, while the details
+// only go to the log. With `verbose_errors` the details are shown too, e.g. while setting up
+pub struct ErrorReport<'a> {
+    pam: &'a dyn Pam,
+    verbose: bool,
+    // Message and details of the failure that ended the attempt
+    failure: RefCell<Option<(String, String)>>,
+}
+
+impl<'a> ErrorReport<'a> {
+    pub fn new(pam: &'a dyn Pam, args: &HashMap<String, String>) -> Self {
+        ErrorReport {
+            pam,
+            verbose: args.contains_key("verbose_errors"),
+            failure: RefCell::new(None),
+        }
+    }
+
+    pub fn fail(&self, failure: Failure, detail: &str) {
+        self.fail_with(failure.message(), detail);
+    }
+
+    // For failures that need more than the reason, e.g. where to go next
+    pub fn fail_with(&self, message: &str, detail: &str) {
+        logging::log_to_file(detail);
+        self.failure.replace(Some((message.to_string(), detail.to_string())));
+    }
+
+    // Nothing is shown when the attempt succeeded or was left to the other modules, and a
+    // failure nothing was reported for still gets a message
+    pub fn finish(&self, code: PamReturnCode) {
+        if matches!(code, PamReturnCode::SUCCESS | PamReturnCode::IGNORE) || !self.pam.has_conversation() {
+            return;
+        }
+        let message = match self.failure.take() {
+            Some((message, detail)) if self.verbose => format!("{}\n{}", message, detail),
+            Some((message, _)) => message,
+            None if code == PamReturnCode::SERVICE_ERR => Failure::ServiceError.message().to_string(),
+            None => "Authentication failed".to_string(),
+        };
+        let _ = conversation::prompt(self.pam, &message, PamMessageStyle::ERROR_MSG);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn return_codes_override_the_defaults() {
+        let codes = ReturnCodes::parse(" network_error : ignore , not_in_org:auth_err,").unwrap();
+        assert_eq!(codes.code(Failure::NetworkError), PamReturnCode::IGNORE);
+        assert_eq!(codes.code(Failure::NotInOrg), PamReturnCode::AUTH_ERR);
+        assert_eq!(codes.code(Failure::ServiceError), PamReturnCode::SERVICE_ERR);
+        assert_eq!(codes.code(Failure::NotInTeam), PamReturnCode::USER_UNKNOWN);
+
+        // The last mapping of a reason wins
+        let codes = ReturnCodes::parse("totp:perm_denied,totp:authinfo_unavail").unwrap();
+        assert_eq!(codes.code(Failure::Totp), PamReturnCode::AUTHINFO_UNAVAIL);
+    }
+
+    #[test]
+    fn return_codes_default_without_a_mapping() {
+        let codes = ReturnCodes::parse("").unwrap();
+        assert_eq!(codes.code(Failure::NetworkError), PamReturnCode::SERVICE_ERR);
+        assert_eq!(codes.code(Failure::ServiceError), PamReturnCode::SERVICE_ERR);
+        assert_eq!(codes.code(Failure::DeniedUser), PamReturnCode::USER_UNKNOWN);
+    }
+
+    #[test]
+    fn invalid_return_codes_are_rejected() {
+        let error = |value: &str| ReturnCodes::parse(value).unwrap_err();
+        assert_eq!(error("network_error"), "Invalid return code mapping: network_error");
+        assert_eq!(error("offline:ignore"), "Unknown failure reason: offline");
+        assert_eq!(error("not_in_org:success"), "Unknown PAM return code: success");
+    }
+}
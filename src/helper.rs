@@ -0,0 +1,260 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::github::{
+    ApiError, CollaboratorPermission, DeviceCode, GithubClient, GithubError, InstallationTarget, Keys, Membership, OAuthApp,
+    OrgTeam, UserToken,
+};
+use crate::logging;
+
+pub const DEFAULT_SOCKET: &str = "/run/ssh_github_auth/ghauthd.sock";
+
+// What ghauthd does on GitHub for the module. Nothing else can be asked of it, so the module
+// never needs an HTTP client of its own
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Operation {
+    // First step of the device flow
+    DeviceCode { app: OAuthApp },
+    // Polls for the token once the user authorized the code
+    AccessToken { app: OAuthApp, device_code: String },
+    // Swaps the refresh token of a stored token for a new token
+    RefreshToken { app: OAuthApp, refresh_token: String },
+    // The login of the token's owner, which must be one of `logins`
+    Login { token: String, logins: Vec<String> },
+    // The current login of a bound account, looked up by its ID with `token`
+    LoginForId { token: String, id: u64 },
+    // The user's membership in the organization, looked up with `token`
+    Membership { token: String, org: String, username: String },
+    // Public keys of an account, None while they still match `etag`
+    Keys { username: String, etag: Option<String> },
+    // Slugs of the token owner's teams in the organization
+    Teams { token: String, org: String },
+    // Whether the user is in a team, looked up with `token`
+    TeamMembership { token: String, org: String, team: String, username: String },
+    // Names and slugs of the organization's teams
+    OrgTeams { token: String, org: String },
+    // A team and the teams nested below it
+    TeamTree { token: String, org: String, team: String },
+    // The user's permission on a repository, looked up with `token`
+    RepoPermission { token: String, repo: String, username: String },
+    // Swaps a JWT the module signed with the app's key for an installation token
+    InstallationToken { jwt: String, target: InstallationTarget },
+}
+
+// One JSON object per line in each direction
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HelperRequest {
+    pub operation: Operation,
+    // pin_sha256 of the module, ghauthd enforces them on its connection
+    #[serde(default)]
+    pub pins: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum HelperReply {
+    DeviceCode(DeviceCode),
+    AccessToken(UserToken),
+    Login(String),
+    Membership(Membership),
+    Keys(Option<Keys>),
+    Teams(Vec<String>),
+    TeamMembership(bool),
+    OrgTeams(Vec<OrgTeam>),
+    TeamTree(Vec<String>),
+    RepoPermission(CollaboratorPermission),
+    InstallationToken(String),
+    Err(HelperError),
+}
+
+// A GithubError on its way back to the module, which tells the user why by its kind
+#[derive(Debug, Serialize, Deserialize)]
+pub enum HelperError {
+    NotFound(ApiError),
+    Unauthorized(ApiError),
+    Forbidden(ApiError),
+    Api(ApiError),
+    InvalidUser(String),
+    DeviceFlow {
+        error: String,
+        description: Option<String>,
+        #[serde(default)]
+        interval: Option<u64>,
+    },
+    SsoRequired(String),
+    PinMismatch { url: String, pin: String },
+    AppNotInstalled(String),
+    // GitHub could not be reached from ghauthd
+    Unreachable(String),
+    Other(String),
+}
+
+impl From<GithubError> for HelperError {
+    fn from(err: GithubError) -> Self {
+        match err {
+            GithubError::NotFound(api) => HelperError::NotFound(api),
+            GithubError::Unauthorized(api) => HelperError::Unauthorized(api),
+            GithubError::Forbidden(api) => HelperError::Forbidden(api),
+            GithubError::Api(api) => HelperError::Api(api),
+            GithubError::InvalidUser(info) => HelperError::InvalidUser(info),
+            GithubError::DeviceFlow { error, description, interval } => {
+                HelperError::DeviceFlow { error, description, interval }
+            }
+            GithubError::SsoRequired(url) => HelperError::SsoRequired(url),
+            GithubError::PinMismatch { url, pin } => HelperError::PinMismatch { url, pin },
+            GithubError::AppNotInstalled(target) => HelperError::AppNotInstalled(target),
+            err @ (GithubError::Request { .. } | GithubError::Helper { .. }) => HelperError::Unreachable(err.to_string()),
+            err => HelperError::Other(err.to_string()),
+        }
+    }
+}
+
+impl HelperError {
+    pub fn into_error(self, endpoint: &str) -> GithubError {
+        match self {
+            HelperError::NotFound(api) => GithubError::NotFound(api),
+            HelperError::Unauthorized(api) => GithubError::Unauthorized(api),
+            HelperError::Forbidden(api) => GithubError::Forbidden(api),
+            HelperError::Api(api) => GithubError::Api(api),
+            HelperError::InvalidUser(info) => GithubError::InvalidUser(info),
+            HelperError::DeviceFlow { error, description, interval } => {
+                GithubError::DeviceFlow { error, description, interval }
+            }
+            HelperError::SsoRequired(url) => GithubError::SsoRequired(url),
+            HelperError::PinMismatch { url, pin } => GithubError::PinMismatch { url, pin },
+            HelperError::AppNotInstalled(target) => GithubError::AppNotInstalled(target),
+            HelperError::Unreachable(message) => GithubError::Helper {
+                endpoint: endpoint.to_string(),
+                message,
+            },
+            HelperError::Other(message) => GithubError::Other(message),
+        }
+    }
+}
+
+// Has ghauthd perform an operation instead of connecting to GitHub from this process
+pub fn call(socket: &str, operation: Operation, pins: &[String], timeout: Duration) -> Result<HelperReply, String> {
+    let request = HelperRequest {
+        operation,
+        pins: pins.to_vec(),
+    };
+
+    let mut stream = UnixStream::connect(socket)
+        .map_err(|e| format!("Failed to connect to {}: {}", socket, e))?;
+    stream
+        .set_read_timeout(Some(timeout))
+        .map_err(|e| format!("Failed to set helper timeout: {}", e))?;
+
+    let mut line = serde_json::to_string(&request)
+        .map_err(|e| format!("Failed to serialize helper request: {}", e))?;
+    line.push('\n');
+    stream
+        .write_all(line.as_bytes())
+        .map_err(|e| format!("Failed to write to helper: {}", e))?;
+
+    let mut reply = String::new();
+    BufReader::new(stream)
+        .read_line(&mut reply)
+        .map_err(|e| format!("Failed to read from helper: {}", e))?;
+    serde_json::from_str(&reply).map_err(|e| format!("Invalid helper reply: {}", e))
+}
+
+// Clients for the pins requests came with, built once per set
+type PinnedClients = Arc<Mutex<HashMap<Vec<String>, GithubClient>>>;
+
+pub fn serve(listener: UnixListener, client: GithubClient) {
+    let pinned: PinnedClients = Arc::default();
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let client = client.clone();
+                let pinned = pinned.clone();
+                thread::spawn(move || handle_connection(stream, &client, &pinned));
+            }
+            Err(err) => logging::log_to_file(&format!("Failed to accept helper connection: {}", err)),
+        }
+    }
+}
+
+fn pinned_client(client: &GithubClient, pinned: &PinnedClients, pins: &[String]) -> Result<GithubClient, GithubError> {
+    if pins.is_empty() {
+        return Ok(client.clone());
+    }
+    let mut pinned = pinned.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Some(client) = pinned.get(pins) {
+        return Ok(client.clone());
+    }
+    let built = client.with_pins(pins)?;
+    pinned.insert(pins.to_vec(), built.clone());
+    Ok(built)
+}
+
+fn perform(client: &GithubClient, operation: Operation) -> Result<HelperReply, GithubError> {
+    Ok(match operation {
+        Operation::DeviceCode { app } => HelperReply::DeviceCode(client.get_auth_code(&app)?),
+        Operation::AccessToken { app, device_code } => {
+            HelperReply::AccessToken(client.exchange_device_code(&device_code, &app)?)
+        }
+        Operation::RefreshToken { app, refresh_token } => {
+            HelperReply::AccessToken(client.refresh_user_token(&refresh_token, &app)?)
+        }
+        Operation::Login { token, logins } => HelperReply::Login(client.check_username(&logins, &token)?),
+        Operation::LoginForId { token, id } => HelperReply::Login(client.login_for_id(id, &token)?),
+        Operation::Membership { token, org, username } => {
+            HelperReply::Membership(client.membership(&token, &org, &username)?)
+        }
+        Operation::Keys { username, etag } => HelperReply::Keys(client.fetch_keys_since(&username, etag.as_deref())?),
+        Operation::Teams { token, org } => HelperReply::Teams(client.user_teams(&token, &org)?),
+        Operation::TeamMembership { token, org, team, username } => {
+            HelperReply::TeamMembership(client.team_membership(&token, &org, &team, &username)?)
+        }
+        Operation::OrgTeams { token, org } => HelperReply::OrgTeams(client.org_teams(&org, &token)?),
+        Operation::TeamTree { token, org, team } => HelperReply::TeamTree(client.team_tree(&org, &team, &token)?),
+        Operation::RepoPermission { token, repo, username } => {
+            HelperReply::RepoPermission(client.collaborator_permission(&token, &repo, &username)?)
+        }
+        Operation::InstallationToken { jwt, target } => {
+            HelperReply::InstallationToken(client.app_installation_token(&jwt, &target)?)
+        }
+    })
+}
+
+fn handle_connection(stream: UnixStream, client: &GithubClient, pinned: &PinnedClients) {
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(err) => {
+            logging::log_to_file(&format!("Failed to clone helper connection: {}", err));
+            return;
+        }
+    };
+
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+        let reply = match serde_json::from_str::<HelperRequest>(&line) {
+            Ok(request) => pinned_client(client, pinned, &request.pins)
+                .and_then(|client| perform(&client, request.operation))
+                .unwrap_or_else(|err| HelperReply::Err(err.into())),
+            Err(err) => HelperReply::Err(HelperError::Other(format!("Invalid helper request: {}", err))),
+        };
+
+        let mut reply = match serde_json::to_string(&reply) {
+            Ok(reply) => reply,
+            Err(err) => {
+                logging::log_to_file(&format!("Failed to serialize helper reply: {}", err));
+                return;
+            }
+        };
+        reply.push('\n');
+        if writer.write_all(reply.as_bytes()).is_err() {
+            return;
+        }
+    }
+}
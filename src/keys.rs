@@ -0,0 +1,86 @@
+use crate::github::{self, GithubClient, GithubError, GithubUser, KeyPolicy, Keys};
+use crate::{state, user};
+
+#[derive(Debug, PartialEq)]
+pub enum KeyChange {
+    Updated,
+    Unchanged,
+}
+
+// Keeps the managed block of a local user's authorized_keys in sync with their GitHub keys
+#[derive(Debug)]
+pub struct KeySync<'a> {
+    local_user: &'a str,
+    policy: KeyPolicy,
+}
+
+impl<'a> KeySync<'a> {
+    pub fn new(local_user: &'a str) -> Self {
+        KeySync {
+            local_user,
+            policy: KeyPolicy::default(),
+        }
+    }
+
+    // Keys the policy rejects are left out, e.g. old ones
+    pub fn with_policy(mut self, policy: KeyPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    // Refreshes the block, skipping the rewrite when GitHub reports no change
+    pub fn from_user(&self, github_user: &GithubUser) -> Result<KeyChange, String> {
+        self.sync_with(|etag| github_user.get_keys_since(etag, &self.policy))
+    }
+
+    // Same for a GitHub login remembered from an earlier step, keys are public
+    pub fn from_login(&self, client: &GithubClient, github_login: &str) -> Result<KeyChange, String> {
+        self.sync_with(|etag| client.fetch_keys_since(github_login, etag))
+    }
+
+    // Whether sshd accepted one of the imported keys for this connection
+    pub fn was_used(&self, auth_info: &str) -> Result<bool, String> {
+        let used = github::auth_info_keys(auth_info);
+        if used.is_empty() {
+            return Ok(false);
+        }
+        Ok(user::managed_keys(self.local_user)?
+            .iter()
+            .any(|line| github::key_parts(line).is_some_and(|key| used.contains(&key))))
+    }
+
+    fn sync_with<F>(&self, fetch: F) -> Result<KeyChange, String>
+    where
+        F: FnOnce(Option<&str>) -> Result<Option<Keys>, GithubError>,
+    {
+        let local_user = self.local_user;
+        // Without a managed block the cached ETag can't be trusted, e.g. for a recreated account.
+        // Expiring keys are written again on every sync to push their expiry back
+        let etag = if user::has_managed_keys(local_user)? && self.policy.ttl.is_none() {
+            state::key_etag(local_user)?
+        } else {
+            None
+        };
+
+        match fetch(etag.as_deref()) {
+            // Stale copies may still be left from before pruning was enabled
+            Ok(None) if self.policy.prune_stale => match user::set_managed_keys_pruned(local_user, None)? {
+                0 => Ok(KeyChange::Unchanged),
+                _ => Ok(KeyChange::Updated),
+            },
+            Ok(None) => Ok(KeyChange::Unchanged),
+            Ok(Some(keys)) => {
+                user::check_strict_modes(local_user, self.policy.fix_permissions)?;
+                let lines = self.policy.restrict(&keys.keys, chrono::Local::now());
+                if self.policy.prune_stale {
+                    user::set_managed_keys_pruned(local_user, Some(&lines))?;
+                } else {
+                    user::set_managed_keys(local_user, &lines)?;
+                }
+                state::set_key_etag(local_user, keys.etag.as_deref())?;
+                Ok(KeyChange::Updated)
+            }
+            Err(err) => Err(format!("Failed to fetch keys: {}", err)),
+        }
+    }
+}
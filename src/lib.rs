@@ -1,416 +1,57 @@
-use pam_sys::{wrapped::{get_item, get_user}, PamFlag, PamHandle, PamItemType, PamMessageStyle, PamReturnCode};
-use user::ensure_user_exists;
-use std::ffi::{CStr, CString};
-use std::ptr;
-use std::collections::HashMap;
-use libc;
-
+//! GitHub authentication for SSH hosts.
+//!
+//! The library proves who a user is on GitHub and whether they may log in, and keeps their keys
+//! and accounts on the host in sync. The PAM module (the `pam` feature, on by default) is a thin
+//! layer over it, so the CLI, the helper daemon and other tools can use the same logic without
+//! linking PAM:
+//!
+//! - [`GithubAuthenticator`] runs the device flow and checks organization, repository and team
+//!   membership, as a GitHub App or with the user's token
+//! - [`KeySync`] keeps the managed block of a user's `authorized_keys` in sync with GitHub
+//! - [`Policy`] decides who may log in to a host group from a policy file in a repository
+//!
+//! Most functions take the module's `key=value` options, see the README for the list. Account
+//! management (`accounts`), key import (`keys`), cached logins and tokens (`cache`) and metrics
+//! (`metrics`) are default features that minimal builds can leave out.
+
+pub mod access;
+pub mod args;
+pub mod audit;
+pub mod auth;
+#[cfg(feature = "pam")]
+pub mod conversation;
+#[cfg(feature = "pam")]
+pub mod failure;
 pub mod github;
+pub mod helper;
+#[cfg(feature = "keys")]
+pub mod keys;
+#[cfg(feature = "pam")]
+pub mod lockout;
 pub mod user;
 pub mod logging;
-
-
-fn parse_args(argc: libc::c_int, argv: *const *const libc::c_char) -> HashMap<String, String> {
-    let mut args_map = HashMap::new();
-    let mut flags = Vec::new();
-    
-    for i in 0..argc {
-        unsafe {
-            let arg_ptr = *argv.offset(i as isize);
-            let arg = CStr::from_ptr(arg_ptr).to_string_lossy().into_owned();
-            
-            // Check if the argument is a key=value pair
-            if let Some(pos) = arg.find('=') {
-                let (key, value) = arg.split_at(pos);
-                // Skip the '=' character
-                args_map.insert(key.to_string(), value[1..].to_string());
-            } else {
-                // It's a flag
-                flags.push(arg);
-            }
-        }
-    }
-    
-    // Add flags as keys with empty values
-    for flag in flags {
-        args_map.insert(flag, String::new());
-    }
-    
-    args_map
-}
-
-
-
-fn prompt_user(pamh: *mut PamHandle, prompt: &str, style: PamMessageStyle) -> Result<String, PamReturnCode> {
-    let c_prompt = CString::new(prompt).unwrap();
-    
-
-    // Create message structure
-    let msg = pam_sys::PamMessage {
-        msg_style: style as i32,
-        msg: c_prompt.as_ptr(),
-    };
-    
-    // Create pointer to message
-    let pmsg = [&msg as *const pam_sys::PamMessage];
-    
-    // Prepare response pointer
-    let mut response_ptr: *mut pam_sys::PamResponse = ptr::null_mut();
-    
-    // Get conversation function
-    let mut conv_ptr: *const libc::c_void = ptr::null();
-    let ret = unsafe {
-        get_item(
-            &*pamh, 
-            PamItemType::CONV, 
-            &mut conv_ptr
-        )
-    };
-
-    // After get_item completes, cast to the right type
-    let conv_ptr = conv_ptr as *const pam_sys::PamConversation;
-    
-    if ret != PamReturnCode::SUCCESS || conv_ptr.is_null() {
-        return Err(PamReturnCode::CONV_ERR);
-    }
-    
-    // Call conversation function
-    let conv = unsafe { &*conv_ptr };
-    let ret = 
-        if let Some(conv_fn) = conv.conv {
-            PamReturnCode::from(conv_fn(
-                1,
-                pmsg.as_ptr() as *mut *mut pam_sys::PamMessage,
-                &mut response_ptr as *mut *mut pam_sys::PamResponse,
-                conv.data_ptr
-            ))
-        } else {
-            PamReturnCode::CONV_ERR
-        };
-
-
-
-    if ret != PamReturnCode::SUCCESS {
-        return Err(ret);
-    }
-    
-    if response_ptr.is_null() {
-        return Err(PamReturnCode::CONV_ERR);
-    }
-
-    
-    let response = if style == PamMessageStyle::PROMPT_ECHO_OFF || style == PamMessageStyle::PROMPT_ECHO_ON {
-        let resp_ptr = unsafe { (*response_ptr).resp };
-        let r = unsafe { CStr::from_ptr(resp_ptr) }
-            .to_string_lossy()
-            .into_owned();
-        unsafe { libc::free(resp_ptr as *mut libc::c_void) };
-        r
-    } else {
-        "".to_string()
-    };
-    unsafe { libc::free(response_ptr as *mut libc::c_void) };
-    
-    Ok(response)
-}
-
-
-
-#[unsafe(no_mangle)]
-#[allow(improper_ctypes_definitions)]
-pub extern "C" fn pam_sm_authenticate(
-    pamh: *mut PamHandle,
-    _flags: PamFlag,
-    argc: libc::c_int,
-    argv: *const *const libc::c_char,
-) -> PamReturnCode {
-
-    let args = parse_args(argc, argv);
-    
-    // Check if the required arguments are present
-    let org = match args.get("org") {
-        Some(org) => org,
-        None => {
-            logging::log_to_file("Missing organization name");
-            return PamReturnCode::SERVICE_ERR;
-        }
-    };
-    let client_id = match args.get("client_id") {
-        Some(client_id) => client_id,
-        None => {
-            logging::log_to_file("Missing client ID");
-            return PamReturnCode::SERVICE_ERR;
-        }
-    };
-    let auto_create_user = args.contains_key("auto_create_user");
-    let auto_create_user_sudoer = if auto_create_user {
-        match args.get("auto_create_user") {
-            Some(sudoer) => match sudoer.as_str() {
-                "sudoer" => true,
-                _ => false
-
-            },
-            None => false
-        }
-    } else {
-        false
-    };
-    let allow_import_keys = args.contains_key("allow_import_keys");
-    let always_import_keys = if allow_import_keys {
-        match args.get("allow_import_keys") {
-            Some(allow) => match allow.as_str() {
-                "always" => true,
-                _ => false
-            },
-            None => false
-        }
-    } else {
-        false
-    };
-
-    // Get username
-    let mut user = ptr::null();
-    let username = match unsafe { get_user(&*pamh, &mut user, ptr::null()) } {
-        PamReturnCode::SUCCESS => {
-            let username_cstr = unsafe { CStr::from_ptr(user) };
-            username_cstr.to_string_lossy().into_owned()
-        },
-        code => {
-            logging::log_to_file(&format!("Failed to get username: {:?}", code));
-            return code;
-        }
-    }.to_ascii_lowercase();
-
-    logging::log_to_file(&format!("Authentication request for username: {}", username));
-
-    // Prompt for device auth
-    let (device_code, user_code) = match github::get_auth_code(&client_id) {
-        Ok(code) => code,
-        Err(err) => {
-            logging::log_to_file(&format!("Failed to get device code: {:?}", err));
-            return PamReturnCode::SERVICE_ERR;
-        }
-    };
-
-    // Prompt user for device code
-    let prompt = format!(
-        "Please visit https://github.com/login/device and enter the following code: {}\n\
-        You have 10 minutes to complete this step.
-        \nAfter a successful login, press Enter to continue...",
-        user_code
-    );
-
-
-    let _ = match prompt_user(pamh, &prompt, PamMessageStyle::PROMPT_ECHO_OFF) {
-        Ok(resp) => resp,
-        Err(err) => {
-            logging::log_to_file(&format!("Failed to prompt user: {:?}", err));
-            return PamReturnCode::SERVICE_ERR;
-        }
-    };
-
-
-
-    let device_code = device_code.trim().to_string();
-
-    // Retrieve user info
-    let github_user = match github::GithubUser::from_device_code(&device_code, client_id, &username, org) {
-        Ok(user) => user,
-        Err(err) => {
-            match err {
-                github::GithubError::NotFound => {
-                    logging::log_to_file("User not found in organization");
-                    let _ = prompt_user(pamh, "User not found in organization", PamMessageStyle::TEXT_INFO);
-                    return PamReturnCode::USER_UNKNOWN;
-                }
-                github::GithubError::InvalidUser(info) => {
-                    logging::log_to_file(&format!("Invalid user: {:?}", info));
-                    return PamReturnCode::USER_UNKNOWN;
-                }
-                github::GithubError::Unauthorized => {
-                    logging::log_to_file("Unauthorized access");
-                    let _ = prompt_user(pamh, "Unauthorized access", PamMessageStyle::TEXT_INFO);
-                    return PamReturnCode::USER_UNKNOWN;
-                }
-                _ => {
-                    logging::log_to_file(&format!("Unexpected error: {:?}", err));
-                    return PamReturnCode::SERVICE_ERR;
-                }
-            }
-        }
-    };
-
-
-    if let Some(team) = args.get("team") {
-        let it = team.split(',');
-        let mut team_found = false;
-        for t in it {
-            let team = t.trim();
-            if team.is_empty() {
-                continue;
-            }
-            logging::log_to_file(&format!("Checking team membership for team: {}", team));
-            match github_user.is_in_team(team) {
-                Ok(in_team) => if in_team {
-                    team_found = true;
-                    break;
-                }
-                Err(err) => {
-                    logging::log_to_file(&format!("Failed to check team membership: {:?}", err));
-                    return PamReturnCode::SERVICE_ERR;
-                }
-            };
-        }
-        if !team_found {
-            logging::log_to_file("User is not a member of the required team");
-            let _ = prompt_user(pamh, "User is not a member of the required team", PamMessageStyle::TEXT_INFO);
-            return PamReturnCode::USER_UNKNOWN;
-        }
-    }
-
-    let _ = match prompt_user(pamh, "Authentication successful", PamMessageStyle::TEXT_INFO) {
-        Ok(_) => {},
-        Err(err) => {
-            logging::log_to_file(&format!("Failed to prompt user: {:?}", err));
-            return PamReturnCode::SERVICE_ERR;
-        }
-    };
-    logging::log_to_file(&format!("Authentication successful for user {}", username));
-
-
-    if auto_create_user {
-        match ensure_user_exists(&username, auto_create_user_sudoer) {
-            Ok(existed) => {
-                if existed {
-                    logging::log_to_file(&format!("User {} already exists", username));
-                } else {
-                    logging::log_to_file(&format!("Created user {}", username));
-                    
-                    // Prompt user about account creation
-                    let message = "Your account has been created successfully!\n\
-                                  After this session, you'll need to disconnect and log in again for your new account to be fully recognized.";
-                    let _ = prompt_user(pamh, message, PamMessageStyle::TEXT_INFO);
-                    
-                    if always_import_keys {
-                        match github_user.get_keys() {
-                            Ok(_) => {
-                                let keys = github_user.get_keys().unwrap();
-                                if let Err(e) = user::add_authorized_key(&username, &keys) {
-                                    logging::log_to_file(&format!("Failed to import keys: {}", e));
-                                    return PamReturnCode::SERVICE_ERR;
-                                }
-                                let message = "Your SSH keys have been imported successfully!";
-                                let _ = prompt_user(pamh, message, PamMessageStyle::TEXT_INFO);
-                                logging::log_to_file(&format!("Imported keys for user {}", username));
-                            },
-                            Err(err) => {
-                                logging::log_to_file(&format!("Failed to import keys: {:?}", err));
-                                return PamReturnCode::SERVICE_ERR;
-                            }
-                        }
-                    }
-
-                    return PamReturnCode::SUCCESS;
-                }
-            },
-            Err(err) => {
-                logging::log_to_file(&format!("Failed to create user: {}", err));
-                return PamReturnCode::SERVICE_ERR;
-            }
-        }
-    }
-
-    if allow_import_keys {
-        let ans = prompt_user(
-            pamh,
-            "Do you want to import your SSH keys from GitHub? (y/n) ",
-            PamMessageStyle::PROMPT_ECHO_ON,
-        );
-        if let Err(err) = ans {
-            logging::log_to_file(&format!("Failed to prompt user: {:?}", err));
-            return PamReturnCode::SERVICE_ERR;
-        }
-        let ans = ans.unwrap();
-        let ans = ans.trim().to_lowercase();
-        if ans != "y" {
-            logging::log_to_file("User declined to import keys");
-            return PamReturnCode::SUCCESS;
-        }
-        logging::log_to_file("User accepted to import keys");
-        match github_user.get_keys() {
-            Ok(_) => {
-                let keys = github_user.get_keys().unwrap();
-                if let Err(e) = user::add_authorized_key(&username, &keys) {
-                    logging::log_to_file(&format!("Failed to import keys: {}", e));
-                    return PamReturnCode::SERVICE_ERR;
-                }
-                let message = "Your SSH keys have been imported successfully!";
-                let _ = prompt_user(pamh, message, PamMessageStyle::TEXT_INFO);
-                logging::log_to_file(&format!("Imported keys for user {}", username));
-            },
-            Err(err) => {
-                logging::log_to_file(&format!("Failed to import keys: {:?}", err));
-                return PamReturnCode::SERVICE_ERR;
-            }
-        }
-    }
-
-    PamReturnCode::SUCCESS
-}
-
-
-
-#[allow(improper_ctypes_definitions)]
-#[unsafe(no_mangle)]
-pub extern "C" fn pam_sm_setcred(
-    _pamh: *mut PamHandle,
-    _flags: PamFlag,
-    _argc: libc::c_int,
-    _argv: *const *const libc::c_char,
-) -> PamReturnCode {
-    PamReturnCode::SUCCESS
-}
-
-#[unsafe(no_mangle)]
-#[allow(improper_ctypes_definitions)]
-pub extern "C" fn pam_sm_acct_mgmt(
-    _pamh: *mut PamHandle,
-    _flags: PamFlag,
-    _argc: libc::c_int,
-    _argv: *const *const libc::c_char,
-) -> PamReturnCode {
-    PamReturnCode::SUCCESS
-}
-
-#[unsafe(no_mangle)]
-#[allow(improper_ctypes_definitions)]
-pub extern "C" fn pam_sm_open_session(
-    _pamh: *mut PamHandle,
-    _flags: PamFlag,
-    _argc: libc::c_int,
-    _argv: *const *const libc::c_char,
-) -> PamReturnCode {
-    PamReturnCode::SUCCESS
-}
-
-#[unsafe(no_mangle)]
-#[allow(improper_ctypes_definitions)]
-pub extern "C" fn pam_sm_close_session(
-    _pamh: *mut PamHandle,
-    _flags: PamFlag,
-    _argc: libc::c_int,
-    _argv: *const *const libc::c_char,
-) -> PamReturnCode {
-    PamReturnCode::SUCCESS
-}
-
-#[unsafe(no_mangle)]
-#[allow(improper_ctypes_definitions)]
-pub extern "C" fn pam_sm_chauthtok(
-    _pamh: *mut PamHandle,
-    _flags: PamFlag,
-    _argc: libc::c_int,
-    _argv: *const *const libc::c_char,
-) -> PamReturnCode {
-    PamReturnCode::SUCCESS
-}
+pub mod metrics;
+pub mod nss;
+pub mod oidc;
+#[cfg(feature = "pam")]
+pub mod pam;
+pub mod policy;
+pub mod state;
+#[cfg(feature = "cache")]
+pub mod tokens;
+pub mod totp;
+pub mod usermap;
+
+#[cfg(feature = "pam")]
+mod module;
+
+#[cfg(all(feature = "direct", not(any(feature = "native-tls", feature = "rustls"))))]
+compile_error!("either the `native-tls` or the `rustls` feature is needed to reach GitHub directly");
+
+pub use auth::GithubAuthenticator;
+#[cfg(feature = "keys")]
+pub use keys::KeySync;
+pub use policy::Policy;
+
+#[cfg(feature = "pam")]
+pub use module::{acct_mgmt_with, authenticate_with, service_args, AuthMode};
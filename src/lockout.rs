@@ -0,0 +1,65 @@
+use std::collections::HashMap;
+
+use crate::failure::Failure;
+use crate::{logging, state};
+
+// Locks out a user and remote host after repeated failures, to slow down probing
+#[derive(Debug)]
+pub struct Lockout {
+    key: String,
+    // Maximum failures and the window in seconds they are counted in
+    limit: Option<(usize, i64)>,
+}
+
+impl Lockout {
+    pub fn from_args(args: &HashMap<String, String>, local_user: &str, rhost: Option<&str>) -> Result<Self, String> {
+        let key = format!("{}@{}", local_user, rhost.unwrap_or("-"));
+        let attempts = match args.get("lockout_attempts") {
+            Some(value) => match value.parse::<usize>() {
+                Ok(attempts) if attempts > 0 => attempts,
+                _ => return Err(format!("Invalid lockout_attempts: {}", value)),
+            },
+            None => return Ok(Lockout { key, limit: None }),
+        };
+        let window = match args.get("lockout_window") {
+            Some(value) => match value.parse::<i64>() {
+                Ok(window) if window > 0 => window,
+                _ => return Err(format!("Invalid lockout_window: {}", value)),
+            },
+            None => 900,
+        };
+        Ok(Lockout { key, limit: Some((attempts, window)) })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        let Some((attempts, window)) = self.limit else {
+            return false;
+        };
+        match state::recent_failures(&self.key, window) {
+            Ok(failures) => failures >= attempts,
+            Err(err) => {
+                logging::log_to_file(&format!("Failed to read failed attempts: {}", err));
+                false
+            }
+        }
+    }
+
+    // Only failures the user is responsible for count, not GitHub or configuration errors
+    pub fn record(&self, failure: Failure) {
+        let Some((_, window)) = self.limit else {
+            return;
+        };
+        if failure == Failure::NetworkError || failure == Failure::ServiceError {
+            return;
+        }
+        if let Err(err) = state::record_failure(&self.key, window) {
+            logging::log_to_file(&format!("Failed to record failed attempt: {}", err));
+        }
+    }
+
+    pub fn clear(&self) {
+        if self.limit.is_some() && let Err(err) = state::clear_failures(&self.key) {
+            logging::log_to_file(&format!("Failed to clear failed attempts: {}", err));
+        }
+    }
+}
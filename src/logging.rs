@@ -1,12 +1,133 @@
 use std::fs::OpenOptions;
 use std::io::Write;
+use std::os::unix::net::UnixDatagram;
 use std::process::Command;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Log file of the `file` backend
+pub const LOG_FILE: &str = "/tmp/github_ssh.log";
+
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+static DEBUG: AtomicBool = AtomicBool::new(false);
+static JOURNALD: AtomicBool = AtomicBool::new(false);
+// Messages go through the tracing subscriber, so they carry the spans they were logged in
+#[cfg(feature = "trace")]
+static TRACING: AtomicBool = AtomicBool::new(false);
+// Structured fields attached to every journal entry of the current attempt
+static FIELDS: Mutex<Vec<(&'static str, String)>> = Mutex::new(Vec::new());
+// The PAM service, remote host and terminal of the current attempt, e.g. `service=sshd rhost=192.0.2.1 tty=ssh`
+static CONNECTION: Mutex<String> = Mutex::new(String::new());
+
+pub fn set_debug(enabled: bool) {
+    DEBUG.store(enabled, Ordering::Relaxed);
+}
+
+// Selects the log backend from the `log_backend` argument, `file` (default) or `journald`
+pub fn set_backend(backend: Option<&str>) -> Result<(), String> {
+    let journald = match backend {
+        Some("file") | None => false,
+        Some("journald") => true,
+        Some(other) => return Err(format!("Invalid log backend: {}", other)),
+    };
+    JOURNALD.store(journald, Ordering::Relaxed);
+    Ok(())
+}
+
+// Installs a subscriber writing to the log backend, unless the application already has one
+#[cfg(feature = "trace")]
+pub fn init_tracing() {
+    static INIT: std::sync::Once = std::sync::Once::new();
+    INIT.call_once(|| {
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(|| Backend)
+            .with_ansi(false)
+            .without_time()
+            .with_level(false)
+            .with_target(false)
+            .with_max_level(tracing::Level::TRACE)
+            .finish();
+        TRACING.store(tracing::subscriber::set_global_default(subscriber).is_ok(), Ordering::Relaxed);
+    });
+}
+
+// Writes each formatted event as one message to the configured backend
+#[cfg(feature = "trace")]
+struct Backend;
+
+#[cfg(feature = "trace")]
+impl Write for Backend {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        write_entry(String::from_utf8_lossy(buf).trim_end());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+pub fn set_field(name: &'static str, value: &str) {
+    if let Ok(mut fields) = FIELDS.lock() {
+        fields.retain(|(n, _)| *n != name);
+        fields.push((name, value.to_string()));
+    }
+}
+
+pub fn clear_fields() {
+    if let Ok(mut fields) = FIELDS.lock() {
+        fields.clear();
+    }
+    if let Ok(mut connection) = CONNECTION.lock() {
+        connection.clear();
+    }
+}
+
+// Attached to every message of the attempt, as fields in the journal and as a prefix elsewhere
+pub fn set_connection(service: Option<&str>, rhost: Option<&str>, tty: Option<&str>) {
+    let mut parts = Vec::new();
+    for (name, label, value) in [("PAM_SERVICE", "service", service), ("RHOST", "rhost", rhost), ("PAM_TTY", "tty", tty)] {
+        if let Some(value) = value.filter(|v| !v.is_empty()) {
+            set_field(name, value);
+            parts.push(format!("{}={}", label, value));
+        }
+    }
+    if let Ok(mut connection) = CONNECTION.lock() {
+        *connection = parts.join(" ");
+    }
+}
+
+// Detailed tracing of each step, only logged with the `debug` argument
+pub fn debug(message: &str) {
+    if DEBUG.load(Ordering::Relaxed) {
+        log_to_file(&format!("DEBUG {}", message));
+    }
+}
 
 pub fn log_to_file(message: &str) {
+    #[cfg(feature = "trace")]
+    if TRACING.load(Ordering::Relaxed) {
+        tracing::info!("{}", message);
+        return;
+    }
+    write_entry(message);
+}
+
+fn write_entry(message: &str) {
+    if JOURNALD.load(Ordering::Relaxed) && log_to_journal(message).is_ok() {
+        return;
+    }
+    let message = match CONNECTION.lock() {
+        Ok(connection) if !connection.is_empty() => format!("[{}] {}", connection, message),
+        _ => message.to_string(),
+    };
+    let message = message.as_str();
+
     if let Ok(mut file) = OpenOptions::new()
         .create(true)
         .append(true)
-        .open("/tmp/github_ssh.log") 
+        .open(LOG_FILE) 
     {
         let timestamp = chrono::Local::now().format("%Y-%m-%d %H:%M:%S");
         let _ = writeln!(file, "[{}] {}", timestamp, message);
@@ -16,4 +137,34 @@ pub fn log_to_file(message: &str) {
     let _ = Command::new("logger")
         .args(["-t", "github_ssh_auth", message])
         .status();
-}
\ No newline at end of file
+}
+
+// Sends an entry using journald's native protocol, so fields stay searchable
+fn log_to_journal(message: &str) -> std::io::Result<()> {
+    let mut entry = Vec::new();
+    append_field(&mut entry, "MESSAGE", message);
+    append_field(&mut entry, "PRIORITY", "6");
+    append_field(&mut entry, "SYSLOG_IDENTIFIER", "github_ssh_auth");
+    if let Ok(fields) = FIELDS.lock() {
+        for (name, value) in fields.iter() {
+            append_field(&mut entry, name, value);
+        }
+    }
+
+    let socket = UnixDatagram::unbound()?;
+    socket.send_to(&entry, JOURNAL_SOCKET)?;
+    Ok(())
+}
+
+fn append_field(entry: &mut Vec<u8>, name: &str, value: &str) {
+    entry.extend_from_slice(name.as_bytes());
+    // Values with newlines are sent length-prefixed instead of as NAME=value lines
+    if value.contains('\n') {
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value.as_bytes());
+    entry.push(b'\n');
+}
@@ -0,0 +1,171 @@
+// Timings of the current attempt are always kept for the log, the Prometheus export needs the
+// `metrics` feature
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+#[cfg(feature = "metrics")]
+use std::{collections::BTreeMap, fmt::Write, fs, path::Path};
+
+#[cfg(feature = "metrics")]
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "metrics")]
+use crate::state;
+
+#[cfg(feature = "metrics")]
+const PROM_FILE: &str = "ssh_github_auth.prom";
+#[cfg(feature = "metrics")]
+const DEVICE_FLOW_BUCKETS: [f64; 8] = [5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 900.0];
+
+// Observations made during the current attempt, until they are flushed
+struct Pending {
+    device_flow: Vec<f64>,
+    github_errors: Vec<String>,
+    steps: Vec<(&'static str, Duration)>,
+}
+
+static PENDING: Mutex<Pending> = Mutex::new(Pending {
+    device_flow: Vec::new(),
+    github_errors: Vec::new(),
+    steps: Vec::new(),
+});
+
+// Time spent in one step of the login over all attempts
+#[cfg(feature = "metrics")]
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StepTotal {
+    count: u64,
+    sum: f64,
+}
+
+// Counters are kept in the state directory since every login runs in a new process
+#[cfg(feature = "metrics")]
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Metrics {
+    attempts: BTreeMap<String, u64>,
+    // Cumulative counts for each of DEVICE_FLOW_BUCKETS
+    device_flow_buckets: Vec<u64>,
+    device_flow_count: u64,
+    device_flow_sum: f64,
+    github_errors: BTreeMap<String, u64>,
+    #[serde(default)]
+    steps: BTreeMap<String, StepTotal>,
+}
+
+pub fn observe_device_flow(duration: Duration) {
+    if let Ok(mut pending) = PENDING.lock() {
+        pending.device_flow.push(duration.as_secs_f64());
+    }
+}
+
+// Runs one step of the login, e.g. `membership` or `user_creation`, and records how long it took
+pub fn timed<T>(step: &'static str, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let result = f();
+    if let Ok(mut pending) = PENDING.lock() {
+        pending.steps.push((step, start.elapsed()));
+    }
+    result
+}
+
+// Total time of each step of the current attempt in the order they first ran, e.g.
+// `device_code=180ms conversation=21.2s`
+pub fn step_summary() -> String {
+    let Ok(pending) = PENDING.lock() else {
+        return String::new();
+    };
+    let mut totals: Vec<(&str, Duration)> = Vec::new();
+    for (step, duration) in &pending.steps {
+        match totals.iter_mut().find(|(name, _)| name == step) {
+            Some((_, total)) => *total += *duration,
+            None => totals.push((step, *duration)),
+        }
+    }
+    totals
+        .iter()
+        .map(|(step, total)| format!("{}={:.1?}", step, total))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Labelled with the HTTP status, or the kind of failure when there was no response
+pub fn github_error(label: &str) {
+    if let Ok(mut pending) = PENDING.lock() {
+        pending.github_errors.push(label.to_string());
+    }
+}
+
+// Adds the attempt to the stored counters and rewrites the node_exporter textfile in `dir`
+#[cfg(feature = "metrics")]
+pub fn flush(dir: &str, result: &str) -> Result<(), String> {
+    let (device_flow, github_errors, steps) = match PENDING.lock() {
+        Ok(mut pending) => (
+            std::mem::take(&mut pending.device_flow),
+            std::mem::take(&mut pending.github_errors),
+            std::mem::take(&mut pending.steps),
+        ),
+        Err(_) => return Err("Metrics lock is poisoned".to_string()),
+    };
+
+    let mut metrics: Metrics = state::load("metrics.json")?;
+    *metrics.attempts.entry(result.to_ascii_lowercase()).or_default() += 1;
+    metrics.device_flow_buckets.resize(DEVICE_FLOW_BUCKETS.len(), 0);
+    for secs in device_flow {
+        for (bucket, le) in metrics.device_flow_buckets.iter_mut().zip(DEVICE_FLOW_BUCKETS) {
+            if secs <= le {
+                *bucket += 1;
+            }
+        }
+        metrics.device_flow_count += 1;
+        metrics.device_flow_sum += secs;
+    }
+    for label in github_errors {
+        *metrics.github_errors.entry(label).or_default() += 1;
+    }
+    for (step, duration) in steps {
+        let total = metrics.steps.entry(step.to_string()).or_default();
+        total.count += 1;
+        total.sum += duration.as_secs_f64();
+    }
+    state::save("metrics.json", &metrics)?;
+
+    let path = Path::new(dir).join(PROM_FILE);
+    // node_exporter may read the file at any time, so replace it atomically
+    let tmp_path = path.with_extension("prom.tmp");
+    fs::write(&tmp_path, render(&metrics))
+        .map_err(|e| format!("Failed to write {}: {}", tmp_path.display(), e))?;
+    fs::rename(&tmp_path, &path)
+        .map_err(|e| format!("Failed to replace {}: {}", path.display(), e))
+}
+
+#[cfg(feature = "metrics")]
+fn render(metrics: &Metrics) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "# HELP ssh_github_auth_attempts_total Authentication attempts by PAM result.");
+    let _ = writeln!(out, "# TYPE ssh_github_auth_attempts_total counter");
+    for (result, count) in &metrics.attempts {
+        let _ = writeln!(out, "ssh_github_auth_attempts_total{{result=\"{}\"}} {}", result, count);
+    }
+
+    let _ = writeln!(out, "# HELP ssh_github_auth_device_flow_seconds Time users took to complete the device flow.");
+    let _ = writeln!(out, "# TYPE ssh_github_auth_device_flow_seconds histogram");
+    for (le, count) in DEVICE_FLOW_BUCKETS.iter().zip(&metrics.device_flow_buckets) {
+        let _ = writeln!(out, "ssh_github_auth_device_flow_seconds_bucket{{le=\"{}\"}} {}", le, count);
+    }
+    let _ = writeln!(out, "ssh_github_auth_device_flow_seconds_bucket{{le=\"+Inf\"}} {}", metrics.device_flow_count);
+    let _ = writeln!(out, "ssh_github_auth_device_flow_seconds_sum {}", metrics.device_flow_sum);
+    let _ = writeln!(out, "ssh_github_auth_device_flow_seconds_count {}", metrics.device_flow_count);
+
+    let _ = writeln!(out, "# HELP ssh_github_auth_github_errors_total Failed GitHub requests by status or error.");
+    let _ = writeln!(out, "# TYPE ssh_github_auth_github_errors_total counter");
+    for (label, count) in &metrics.github_errors {
+        let _ = writeln!(out, "ssh_github_auth_github_errors_total{{error=\"{}\"}} {}", label, count);
+    }
+
+    let _ = writeln!(out, "# HELP ssh_github_auth_step_seconds Time spent in each step of the login.");
+    let _ = writeln!(out, "# TYPE ssh_github_auth_step_seconds summary");
+    for (step, total) in &metrics.steps {
+        let _ = writeln!(out, "ssh_github_auth_step_seconds_sum{{step=\"{}\"}} {}", step, total.sum);
+        let _ = writeln!(out, "ssh_github_auth_step_seconds_count{{step=\"{}\"}} {}", step, total.count);
+    }
+    out
+}
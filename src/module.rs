@@ -0,0 +1,2262 @@
+// The PAM module: the pam_sm_* entry points and the conversations they run, on top of the library
+use qrcode::{render::unicode, QrCode};
+use pam_sys::{PamFlag, PamHandle, PamItemType, PamMessageStyle, PamReturnCode};
+use std::ffi::CStr;
+use std::collections::HashMap;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::failure::{ErrorReport, Failure, FailurePolicy};
+use crate::pam::Pam;
+use crate::{access, args, audit, auth, conversation, github, lockout, logging, metrics, oidc, pam, policy, state, totp, user, usermap};
+#[cfg(feature = "keys")]
+use crate::keys;
+#[cfg(feature = "cache")]
+use crate::tokens;
+
+fn parse_args(argc: libc::c_int, argv: *const *const libc::c_char) -> HashMap<String, String> {
+    let raw: Vec<&[u8]> = if argv.is_null() {
+        Vec::new()
+    } else {
+        (0..argc.max(0) as usize)
+            .map(|i| unsafe { *argv.add(i) })
+            .filter(|arg| !arg.is_null())
+            .map(|arg| unsafe { CStr::from_ptr(arg) }.to_bytes())
+            .collect()
+    };
+
+    let (args_map, problems) = args::parse(raw);
+    for problem in problems {
+        logging::log_to_file(&problem);
+    }
+    args_map
+}
+
+
+// Completes the module arguments with the options of the calling PAM service from the `config` file
+pub fn service_args(pam: &dyn Pam, args: HashMap<String, String>) -> Result<HashMap<String, String>, PamReturnCode> {
+    let service = pam.item(PamItemType::SERVICE).unwrap_or_default();
+    match args::with_config(args, &service).and_then(|(args, problems)| {
+        args::check_features(&args)?;
+        args::check_helper(&args)?;
+        Ok((args, problems))
+    }) {
+        Ok((args, problems)) => {
+            for problem in problems {
+                logging::log_to_file(&problem);
+            }
+            Ok(args)
+        }
+        Err(err) => {
+            logging::log_to_file(&err);
+            Err(PamReturnCode::SERVICE_ERR)
+        }
+    }
+}
+
+// Secrets must not end up in the debug log
+fn describe_args(args: &HashMap<String, String>) -> String {
+    let mut args: Vec<String> = args
+        .iter()
+        .map(|(key, value)| {
+            if key.contains("secret") {
+                format!("{}=<redacted>", key)
+            } else if value.is_empty() {
+                key.clone()
+            } else {
+                format!("{}={}", key, value)
+            }
+        })
+        .collect();
+    args.sort();
+    args.join(" ")
+}
+
+// Logs the arguments and outcome of a PAM entry point when `debug` is set
+fn traced<F: FnOnce() -> PamReturnCode>(name: &str, pam: &dyn Pam, args: &HashMap<String, String>, f: F) -> PamReturnCode {
+    logging::set_debug(args.contains_key("debug"));
+    logging::clear_fields();
+    // Ties every line to the connection without going through sshd's log
+    logging::set_connection(
+        pam.item(PamItemType::SERVICE).as_deref(),
+        pam.item(PamItemType::RHOST).as_deref(),
+        pam.item(PamItemType::TTY).as_deref(),
+    );
+    if let Err(err) = logging::set_backend(args.get("log_backend").map(|b| b.as_str())) {
+        logging::log_to_file(&err);
+        return PamReturnCode::SERVICE_ERR;
+    }
+    #[cfg(any(feature = "accounts", feature = "keys"))]
+    user::set_dry_run(args.contains_key("dry_run"));
+    #[cfg(feature = "accounts")]
+    if let Err(err) = user::set_priv_helper(args.get("priv_helper").map(|h| h.as_str())) {
+        logging::log_to_file(&err);
+        return PamReturnCode::SERVICE_ERR;
+    }
+    #[cfg(feature = "keys")]
+    user::set_authorized_keys_path(args.get("authorized_keys_path").map(|p| p.as_str()));
+    #[cfg(feature = "trace")]
+    let _span = {
+        logging::init_tracing();
+        tracing::info_span!("pam", entry = name).entered()
+    };
+    logging::debug(&format!("{} called with: {}", name, describe_args(args)));
+    let start = Instant::now();
+    let code = f();
+    logging::debug(&format!("{} returned {:?} after {:?}", name, code, start.elapsed()));
+    code
+}
+
+fn render_qr(data: &str) -> Option<String> {
+    let code = QrCode::new(data.as_bytes()).ok()?;
+    // Inverted colors render correctly on the usual dark terminal background
+    Some(
+        code.render::<unicode::Dense1x2>()
+            .dark_color(unicode::Dense1x2::Light)
+            .light_color(unicode::Dense1x2::Dark)
+            .build(),
+    )
+}
+
+
+
+fn parse_list(value: &str) -> Vec<&str> {
+    value.split(',').map(|v| v.trim()).filter(|v| !v.is_empty()).collect()
+}
+
+// Seconds to wait after slow_down when GitHub doesn't say, the device flow's default interval
+const DEVICE_FLOW_INTERVAL: u64 = 5;
+
+// Team names are looked up among the organization's teams, which are listed at most once an hour
+const TEAM_CACHE_TTL: i64 = 3600;
+
+// Slugs for `team` and `sudoer_teams` entries, which may also be names like `Platform Engineering`.
+// Teams that don't exist are kept as given, so they never match
+fn team_slugs(github_user: &github::GithubUser, teams: &[&str]) -> Result<Vec<String>, github::GithubError> {
+    if teams.iter().all(|team| github::is_team_slug(team)) {
+        return Ok(teams.iter().map(|team| team.to_string()).collect());
+    }
+    let org = github_user.org();
+    let cached = state::cached_teams(org, TEAM_CACHE_TTL).unwrap_or_else(|err| {
+        logging::log_to_file(&err);
+        None
+    });
+    // A team created since the listing is found by listing again
+    let stale = cached
+        .as_ref()
+        .is_some_and(|cached| teams.iter().any(|team| github::team_slug(team, cached).is_none()));
+    let org_teams = match cached {
+        Some(cached) if !stale => cached,
+        _ => {
+            let fresh = github_user.org_teams()?;
+            if let Err(err) = state::cache_teams(org, &fresh) {
+                logging::log_to_file(&err);
+            }
+            fresh
+        }
+    };
+    Ok(teams
+        .iter()
+        .map(|team| match github::team_slug(team, &org_teams) {
+            Some(slug) => slug.to_string(),
+            None => {
+                logging::log_to_file(&format!(
+                    "Team \"{}\" does not exist in organization {}, use the name or slug of one of its teams: {}",
+                    team,
+                    org,
+                    org_teams.iter().map(|t| t.slug.as_str()).collect::<Vec<_>>().join(", ")
+                ));
+                team.to_string()
+            }
+        })
+        .collect())
+}
+
+// Which of `teams` the user is in, resolved like the `team` check: by name or slug, and with
+// include_child_teams also through the teams nested below them. Teams that don't exist never match
+fn member_of_teams(
+    github_user: &github::GithubUser,
+    teams: &[&str],
+    include_child_teams: bool,
+) -> Result<Vec<String>, github::GithubError> {
+    let slugs = team_slugs(github_user, teams)?;
+    let mut member_of = Vec::new();
+    for (team, slug) in teams.iter().zip(&slugs) {
+        let found = match include_child_teams {
+            true => github_user.check_team_trees(&[slug], github::TeamPolicy::Any),
+            false => github_user.check_teams(&[slug], github::TeamPolicy::Any),
+        };
+        match found {
+            Ok(true) => member_of.push(team.to_string()),
+            Ok(false) | Err(github::GithubError::NotFound(_)) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(member_of)
+}
+
+// GitHub logins a local user goes by before GitHub is asked: `logins` from the user map, and the
+// one recorded at their last login, bound or not
+fn known_logins(username: &str, mut logins: Vec<String>) -> Result<Vec<String>, String> {
+    if let Some(login) = state::user_record(username)?.and_then(|record| record.github_login)
+        && !logins.iter().any(|l| l.eq_ignore_ascii_case(&login))
+    {
+        logins.push(login);
+    }
+    Ok(logins)
+}
+
+fn build_client(args: &HashMap<String, String>) -> Result<github::GithubClient, PamReturnCode> {
+    let config = match github::ClientConfig::from_args(args) {
+        Ok(config) => config,
+        Err(err) => {
+            logging::log_to_file(&err);
+            return Err(PamReturnCode::SERVICE_ERR);
+        }
+    };
+    github::GithubClient::new(&config).map_err(|err| {
+        logging::log_to_file(&format!("Failed to create GitHub client: {}", err));
+        PamReturnCode::SERVICE_ERR
+    })
+}
+
+fn load_github_app(args: &HashMap<String, String>) -> Result<Option<github::GithubApp>, PamReturnCode> {
+    match (args.get("app_id"), args.get("app_private_key")) {
+        (Some(app_id), Some(key_path)) => match github::GithubApp::new(app_id, key_path) {
+            Ok(app) => Ok(Some(app)),
+            Err(err) => {
+                logging::log_to_file(&format!("Failed to load GitHub App credentials: {}", err));
+                Err(PamReturnCode::SERVICE_ERR)
+            }
+        },
+        (None, None) => Ok(None),
+        _ => {
+            logging::log_to_file("Both app_id and app_private_key are required for GitHub App mode");
+            Err(PamReturnCode::SERVICE_ERR)
+        }
+    }
+}
+
+fn parse_deprovision(args: &HashMap<String, String>) -> Result<Option<user::DeprovisionMode>, PamReturnCode> {
+    match args.get("deprovision") {
+        Some(mode) => match user::DeprovisionMode::from_arg(mode) {
+            Some(mode) => Ok(Some(mode)),
+            None => {
+                logging::log_to_file(&format!("Invalid deprovision mode: {}", mode));
+                Err(PamReturnCode::SERVICE_ERR)
+            }
+        },
+        None => Ok(None),
+    }
+}
+
+fn parse_ephemeral(args: &HashMap<String, String>) -> Result<Option<user::EphemeralMode>, PamReturnCode> {
+    match args.get("ephemeral_user") {
+        Some(mode) => match user::EphemeralMode::from_arg(mode) {
+            Some(mode) => Ok(Some(mode)),
+            None => {
+                logging::log_to_file(&format!("Invalid ephemeral_user mode: {}", mode));
+                Err(PamReturnCode::SERVICE_ERR)
+            }
+        },
+        None => Ok(None),
+    }
+}
+
+// Read by the sudo service, and by the session stack of sshd to record login sessions
+fn parse_sudo_grace(args: &HashMap<String, String>) -> Result<Option<i64>, PamReturnCode> {
+    match args.get("sudo_grace").map(|g| g.parse::<i64>()) {
+        Some(Ok(grace)) if grace > 0 => Ok(Some(grace)),
+        Some(_) => {
+            logging::log_to_file(&format!("Invalid sudo_grace: {:?}", args.get("sudo_grace")));
+            Err(PamReturnCode::SERVICE_ERR)
+        }
+        None => Ok(None),
+    }
+}
+
+// The tty and audit session of a login, which sudo run in it shares. None without a tty, or
+// outside an audit session, which pam_loginuid starts
+fn login_session(pam: &dyn Pam) -> Option<String> {
+    let tty = pam.item(PamItemType::TTY)?;
+    let tty = tty.strip_prefix("/dev/").unwrap_or(&tty);
+    let session_id = std::fs::read_to_string("/proc/self/sessionid").ok()?;
+    let session_id = session_id.trim();
+    // (unsigned int)-1 is no audit session
+    if tty.is_empty() || session_id.is_empty() || session_id == "4294967295" {
+        return None;
+    }
+    Some(format!("{}:{}", tty, session_id))
+}
+
+fn get_username(pam: &dyn Pam) -> Result<String, PamReturnCode> {
+    pam.user().inspect_err(|code| {
+        logging::log_to_file(&format!("Failed to get username: {:?}", code));
+    })
+}
+
+#[cfg(feature = "keys")]
+const LOGIN_DATA: &CStr = c"ssh_github_auth_login";
+
+#[cfg(feature = "keys")]
+#[cfg_attr(feature = "trace", tracing::instrument(skip_all, fields(user = username)))]
+fn import_keys(
+    pam: &dyn Pam,
+    github_user: &github::GithubUser,
+    username: &str,
+    policy: &github::KeyPolicy,
+) -> Result<(), PamReturnCode> {
+    match metrics::timed("key_import", || keys::KeySync::new(username).with_policy(policy.clone()).from_user(github_user)) {
+        Ok(keys::KeyChange::Updated) => {
+            let message = "Your SSH keys have been imported successfully!";
+            let _ = conversation::prompt(pam, message, PamMessageStyle::TEXT_INFO);
+            logging::log_to_file(&format!("Imported keys for user {}", username));
+            Ok(())
+        }
+        Ok(keys::KeyChange::Unchanged) => {
+            let _ = conversation::prompt(pam, "Your SSH keys are already up to date", PamMessageStyle::TEXT_INFO);
+            logging::log_to_file(&format!("Keys for user {} are unchanged", username));
+            Ok(())
+        }
+        Err(err) => {
+            logging::log_to_file(&format!("Failed to import keys: {}", err));
+            Err(PamReturnCode::SERVICE_ERR)
+        }
+    }
+}
+
+// Kept for deprovision and `ghauth sync-keys`, which check the user again later on
+#[cfg(feature = "cache")]
+fn store_user_token(username: &str, token: &github::UserToken) {
+    if let Err(err) = tokens::store(username, &tokens::StoredToken::issued(token, chrono::Utc::now().timestamp())) {
+        logging::log_to_file(&format!("Failed to store token: {}", err));
+    }
+}
+
+// store_token needs the `cache` feature, the option is rejected without it
+#[cfg(not(feature = "cache"))]
+fn store_user_token(_username: &str, _token: &github::UserToken) {}
+
+// Running the device flow for every sudo is impractical, so a user who authenticated with
+// GitHub within the grace period only confirms, in the login session that followed that
+// authentication. None when the full flow is needed
+fn confirm_sudo(pam: &dyn Pam, username: &str, grace: i64) -> Option<PamReturnCode> {
+    let Some(session) = login_session(pam) else {
+        logging::debug("No tty or audit session, sudo_grace does not apply");
+        return None;
+    };
+    let elapsed = match state::session_login(username, &session) {
+        Ok(Some(at)) => chrono::Utc::now().timestamp() - at,
+        Ok(None) => return None,
+        Err(err) => {
+            logging::log_to_file(&format!("Failed to look up cached authentication: {}", err));
+            return None;
+        }
+    };
+    if !(0..=grace).contains(&elapsed) {
+        return None;
+    }
+    let message = format!(
+        "You authenticated with GitHub {} minute(s) ago. Continue as {}? (y/n) ",
+        elapsed / 60,
+        username
+    );
+    match conversation::prompt(pam, &message, PamMessageStyle::PROMPT_ECHO_ON) {
+        Ok(answer) if answer.trim().eq_ignore_ascii_case("y") => {
+            logging::log_to_file(&format!("Accepted sudo for user {} authenticated {}s ago", username, elapsed));
+            Some(PamReturnCode::SUCCESS)
+        }
+        Ok(_) => {
+            logging::log_to_file(&format!("User {} declined sudo", username));
+            Some(PamReturnCode::AUTH_ERR)
+        }
+        Err(err) => {
+            logging::log_to_file(&format!("Failed to prompt user: {:?}", err));
+            Some(PamReturnCode::SERVICE_ERR)
+        }
+    }
+}
+
+// Login notices are best effort, a missing banner never fails the login
+fn show_banner(
+    pam: &dyn Pam,
+    client: &github::GithubClient,
+    app: Option<&github::GithubApp>,
+    user_token: Option<&str>,
+    banner: &github::RepoFile,
+) {
+    let content = match app {
+        Some(app) => app
+            .repo_installation_token(client, &banner.repo)
+            .and_then(|token| client.file_contents(banner, Some(&token))),
+        None => client.file_contents(banner, user_token),
+    };
+    match content {
+        Ok(content) if !content.trim().is_empty() => {
+            let _ = conversation::prompt(pam, content.trim_end(), PamMessageStyle::TEXT_INFO);
+        }
+        Ok(_) => {}
+        Err(err) => logging::log_to_file(&format!("Failed to fetch banner {}:{}: {}", banner.repo, banner.path, err)),
+    }
+}
+
+// Scopes an OAuth app's token needs for the configured checks
+fn required_scopes(args: &HashMap<String, String>, github_app: bool) -> Vec<&'static str> {
+    let mut scopes = Vec::new();
+    // Memberships and teams are checked with the user's token unless there is a GitHub App
+    if !github_app && (args.contains_key("org") || args.contains_key("team") || args.contains_key("sudoer_teams")) {
+        scopes.push("read:org");
+    }
+    if args.contains_key("enterprise") {
+        scopes.extend(["read:org", "read:enterprise"]);
+    }
+    if args.contains_key("require_email_domain") {
+        scopes.push("user:email");
+    }
+    // Without it keys still come from the public list, which can't be restricted
+    if args.contains_key("key_max_age_days") || args.contains_key("key_title_pattern") {
+        scopes.push("read:public_key");
+    }
+    scopes
+}
+
+// Revokes the user's OAuth token once authentication is over, whatever the outcome
+struct TokenRevoker<'a> {
+    client: &'a github::GithubClient,
+    client_id: &'a str,
+    client_secret: &'a str,
+    token: Option<String>,
+}
+
+impl Drop for TokenRevoker<'_> {
+    fn drop(&mut self) {
+        if let Some(token) = self.token.take() {
+            match self.client.revoke_token(self.client_id, self.client_secret, &token) {
+                Ok(()) => logging::log_to_file("Revoked OAuth access token"),
+                Err(err) => logging::log_to_file(&format!("Failed to revoke OAuth access token: {}", err)),
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AuthMode {
+    Device,
+    Pat,
+    SshKey,
+    ActionsOidc,
+}
+
+impl AuthMode {
+    pub fn from_arg(mode: Option<&str>) -> Option<Self> {
+        match mode {
+            Some("device") | None => Some(AuthMode::Device),
+            Some("pat") => Some(AuthMode::Pat),
+            Some("ssh_key") => Some(AuthMode::SshKey),
+            Some("actions_oidc") => Some(AuthMode::ActionsOidc),
+            Some(_) => None,
+        }
+    }
+}
+
+// How allow_users log in: with a personal access token, or left to another module stacked with
+// this one, e.g. pam_unix or a key module
+#[derive(Debug, PartialEq)]
+enum AllowUsersMode {
+    Pat,
+    Ignore,
+}
+
+impl AllowUsersMode {
+    fn from_arg(mode: Option<&str>) -> Option<Self> {
+        match mode {
+            Some("pat") | None => Some(AllowUsersMode::Pat),
+            Some("ignore") => Some(AllowUsersMode::Ignore),
+            Some(_) => None,
+        }
+    }
+}
+
+// Local usernames or GitHub logins of machine accounts in allow_users
+fn is_allowed_user(args: &HashMap<String, String>, username: &str, github_logins: &[String]) -> bool {
+    let allow_users = args.get("allow_users").map(|users| parse_list(users)).unwrap_or_default();
+    allow_users
+        .iter()
+        .any(|bot| bot.eq_ignore_ascii_case(username) || github_logins.iter().any(|login| bot.eq_ignore_ascii_case(login)))
+}
+
+// Local logins, e.g. a display manager or a text console, have a tty and no remote host. None for
+// anything else, otherwise the X display of the session if there is one
+fn local_console(pam: &dyn Pam) -> Option<Option<String>> {
+    if pam.item(PamItemType::RHOST).is_some_and(|rhost| !rhost.is_empty()) {
+        return None;
+    }
+    let tty = pam.item(PamItemType::TTY)?;
+    if tty.starts_with(':') {
+        return Some(Some(tty));
+    }
+    let device = tty.strip_prefix("/dev/").unwrap_or(&tty);
+    let console = device.strip_prefix("tty").is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()));
+    console.then_some(None)
+}
+
+// Best effort, the URL is shown in the prompt anyway
+fn open_browser(pam: &dyn Pam, username: &str, url: &str) {
+    let Some(display) = local_console(pam) else {
+        logging::debug("Not a local console login, not opening the verification URL");
+        return;
+    };
+    match user::open_url(username, url, display.as_deref()) {
+        Ok(()) => logging::debug(&format!("Opened the verification URL for user {}", username)),
+        Err(err) => logging::log_to_file(&err),
+    }
+}
+
+// Runs the device flow conversation and returns the device code once the user is done
+#[cfg_attr(feature = "trace", tracing::instrument(skip_all, fields(user = username)))]
+fn device_flow(
+    pam: &dyn Pam,
+    authenticator: &auth::GithubAuthenticator,
+    app: &github::OAuthApp,
+    args: &HashMap<String, String>,
+    failure_policy: &FailurePolicy,
+    errors: &ErrorReport,
+    username: &str,
+) -> Result<String, PamReturnCode> {
+    let device = match metrics::timed("device_code", || authenticator.start_device_flow(app)) {
+        Ok(code) => code,
+        Err(err) => {
+            errors.fail(Failure::from_error(&err), &format!("Failed to get device code: {}", err));
+            return Err(unreachable_code(pam, args, failure_policy, errors, &err, username));
+        }
+    };
+    logging::debug(&format!("Got device code {}, expires in {}s", device.user_code, device.expires_in));
+
+    // Prompt user for device code
+    let visit = match &device.verification_uri_complete {
+        Some(uri) => format!(
+            "Please visit {} to authorize this login,\n\
+            or visit {} and enter the following code: {}",
+            uri, device.verification_uri, device.user_code
+        ),
+        None => format!(
+            "Please visit {} and enter the following code: {}",
+            device.verification_uri, device.user_code
+        ),
+    };
+    if args.contains_key("open_browser") {
+        open_browser(pam, username, device.verification_uri_complete.as_deref().unwrap_or(&device.verification_uri));
+    }
+    let visit = if args.contains_key("show_qr") {
+        let qr_data = device
+            .verification_uri_complete
+            .as_deref()
+            .unwrap_or(&device.verification_uri);
+        match render_qr(qr_data) {
+            Some(qr) => format!("{}\n{}", qr, visit),
+            None => {
+                logging::log_to_file("Failed to render QR code");
+                visit
+            }
+        }
+    } else {
+        visit
+    };
+    let info = format!(
+        "{}\n\
+        You have {} minutes to complete this step.\n",
+        visit,
+        device.expires_in / 60
+    );
+
+    // The instructions and the prompt go out in a single round trip
+    let start = Instant::now();
+    let _ = match metrics::timed("conversation", || {
+        pam.converse(&[
+            (PamMessageStyle::TEXT_INFO, &info),
+            (PamMessageStyle::PROMPT_ECHO_OFF, "After a successful login, press Enter to continue..."),
+        ])
+    }) {
+        Ok(resp) => resp,
+        Err(err) => {
+            logging::log_to_file(&format!("Failed to prompt user: {:?}", err));
+            return Err(PamReturnCode::SERVICE_ERR);
+        }
+    };
+    logging::debug(&format!("User confirmed the device flow after {:?}", start.elapsed()));
+    metrics::observe_device_flow(start.elapsed());
+
+    Ok(device.device_code.trim().to_string())
+}
+
+
+
+#[unsafe(no_mangle)]
+#[allow(improper_ctypes_definitions)]
+pub extern "C" fn pam_sm_authenticate(
+    pamh: *mut PamHandle,
+    _flags: PamFlag,
+    argc: libc::c_int,
+    argv: *const *const libc::c_char,
+) -> PamReturnCode {
+    let pam = pam::Handle(pamh);
+    let args = parse_args(argc, argv);
+    // The PAM line decides when the config file itself is broken
+    let soft_fail = args.contains_key("soft_fail");
+    let args = match service_args(&pam, args) {
+        Ok(args) => args,
+        Err(_) if soft_fail => return PamReturnCode::IGNORE,
+        Err(code) => return code,
+    };
+    authenticate_with(&pam, &args)
+}
+
+// With `soft_fail`, failures that say nothing about the user (GitHub unreachable, a client that
+// can't be prompted, a broken configuration) leave the decision to the next module of the stack
+fn soft_fail(args: &HashMap<String, String>, code: PamReturnCode) -> PamReturnCode {
+    if !args.contains_key("soft_fail") {
+        return code;
+    }
+    match code {
+        PamReturnCode::SERVICE_ERR
+        | PamReturnCode::AUTHINFO_UNAVAIL
+        | PamReturnCode::SYSTEM_ERR
+        | PamReturnCode::CONV_ERR
+        | PamReturnCode::BUF_ERR => {
+            logging::log_to_file(&format!("Returning PAM_IGNORE instead of {:?} for soft_fail", code));
+            PamReturnCode::IGNORE
+        }
+        code => code,
+    }
+}
+
+// The return code for a failed request to GitHub. With on_unreachable=allow_cached a recent
+// login only stands in for GitHub together with the user's one-time password, and without
+// totp_secret_file the other modules of the stack decide
+fn unreachable_code(
+    pam: &dyn Pam,
+    args: &HashMap<String, String>,
+    failure_policy: &FailurePolicy,
+    errors: &ErrorReport,
+    err: &github::GithubError,
+    username: &str,
+) -> PamReturnCode {
+    if !failure_policy.cached(err, username) {
+        return failure_policy.error_code(err, username);
+    }
+    let Some(path) = args.get("totp_secret_file") else {
+        logging::log_to_file(&format!(
+            "GitHub is unreachable, leaving user {} who logged in recently to the other modules",
+            username
+        ));
+        return PamReturnCode::IGNORE;
+    };
+    let secrets = match totp::TotpSecrets::load(path) {
+        Ok(secrets) => secrets,
+        Err(err) => {
+            logging::log_to_file(&err);
+            return PamReturnCode::SERVICE_ERR;
+        }
+    };
+    match check_totp(pam, errors, &secrets, username) {
+        Ok(()) => {
+            logging::log_to_file(&format!(
+                "GitHub is unreachable, accepting user {} who logged in recently with a one-time password",
+                username
+            ));
+            PamReturnCode::SUCCESS
+        }
+        Err(failure) => failure_policy.code(failure),
+    }
+}
+
+// Asks for a code of the user's local TOTP secret once GitHub accepted them
+#[cfg_attr(feature = "trace", tracing::instrument(skip_all, fields(user = username)))]
+fn check_totp(
+    pam: &dyn Pam,
+    errors: &ErrorReport,
+    secrets: &totp::TotpSecrets,
+    username: &str,
+) -> Result<(), Failure> {
+    if !secrets.has_secret(username) {
+        errors.fail_with(
+            "No one-time password is set up for this account",
+            &format!("No TOTP secret configured for user {}", username),
+        );
+        return Err(Failure::Totp);
+    }
+    let code = match conversation::prompt(pam, "One-time password: ", PamMessageStyle::PROMPT_ECHO_OFF) {
+        Ok(code) => code,
+        Err(err) => {
+            logging::log_to_file(&format!("Failed to prompt user: {:?}", err));
+            return Err(Failure::ServiceError);
+        }
+    };
+    let now = chrono::Utc::now().timestamp().max(0) as u64;
+    let step = match secrets.verify(username, &code, now) {
+        Some(step) => step,
+        None => {
+            errors.fail(Failure::Totp, &format!("Invalid one-time password for user {}", username));
+            return Err(Failure::Totp);
+        }
+    };
+    match state::use_totp_step(username, step) {
+        Ok(true) => Ok(()),
+        Ok(false) => {
+            errors.fail_with(
+                "This one-time password was already used",
+                &format!("One-time password of user {} was already used", username),
+            );
+            Err(Failure::Totp)
+        }
+        Err(err) => {
+            logging::log_to_file(&format!("Failed to record one-time password use: {}", err));
+            Err(Failure::ServiceError)
+        }
+    }
+}
+
+// Everything pam_sm_authenticate does once the arguments are parsed
+pub fn authenticate_with(pam: &dyn Pam, args: &HashMap<String, String>) -> PamReturnCode {
+    let start = Instant::now();
+    let mut event = audit::AuthEvent {
+        service: pam.item(PamItemType::SERVICE),
+        rhost: pam.item(PamItemType::RHOST),
+        tty: pam.item(PamItemType::TTY),
+        ..Default::default()
+    };
+    let errors = ErrorReport::new(pam, args);
+    let code = traced("pam_sm_authenticate", pam, args, || authenticate(pam, args, &mut event, &errors));
+    let code = soft_fail(args, code);
+    errors.finish(code);
+    let result = format!("{:?}", code);
+    logging::set_field("RESULT", &result);
+    logging::log_to_file(&format!("Authentication finished with {:?}", code));
+    let steps = metrics::step_summary();
+    if !steps.is_empty() {
+        logging::log_to_file(&format!("Time spent per step: {}", steps));
+    }
+    if let Some(target) = args.get("audit_log") {
+        event.finish(&result, start.elapsed());
+        audit::write(target, &event);
+    }
+    write_kernel_audit(pam, args, audit::KernelRecord::UserAuth, code);
+    #[cfg(feature = "metrics")]
+    if let Some(dir) = args.get("metrics_dir")
+        && let Err(err) = metrics::flush(dir, &result)
+    {
+        logging::log_to_file(&format!("Failed to write metrics: {}", err));
+    }
+    code
+}
+
+// How auto_create_user sets up the accounts it creates
+#[cfg(feature = "accounts")]
+struct AccountSetup {
+    sudo_policy: Option<user::SudoPolicy>,
+    uid_offset: Option<u32>,
+    expiry_days: Option<u32>,
+    ephemeral: bool,
+    // allow_import_keys=always imports keys into new accounts without asking
+    import_keys: bool,
+    role_groups: user::RoleGroups,
+    // Supplementary groups every account is kept in
+    groups: Vec<String>,
+}
+
+#[cfg(feature = "accounts")]
+impl AccountSetup {
+    // None without auto_create_user, the other options are still checked
+    fn from_args(args: &HashMap<String, String>) -> Result<Option<Self>, String> {
+        let uid_offset = user::parse_uid_offset(args.get("uid_offset").map(|o| o.as_str()))?;
+        let expiry_days = user::parse_expiry_days(args.get("account_expiry_days").map(|d| d.as_str()))?;
+        let role_groups = user::RoleGroups::parse(args.get("role_group_map").map(|m| m.as_str()))?;
+        let ephemeral = match args.get("ephemeral_user") {
+            Some(mode) => match user::EphemeralMode::from_arg(mode) {
+                Some(_) => true,
+                None => return Err(format!("Invalid ephemeral_user mode: {}", mode)),
+            },
+            None => false,
+        };
+        if !args.contains_key("auto_create_user") {
+            return Ok(None);
+        }
+        Ok(Some(AccountSetup {
+            sudo_policy: user::SudoPolicy::from_args(args)?,
+            uid_offset,
+            expiry_days,
+            ephemeral,
+            import_keys: !args.contains_key("sync_keys_on_session")
+                && args.get("allow_import_keys").is_some_and(|allow| allow == "always"),
+            role_groups,
+            groups: parse_list(args.get("groups").map(|g| g.as_str()).unwrap_or_default())
+                .into_iter()
+                .map(|g| g.to_string())
+                .collect(),
+        }))
+    }
+}
+
+// Checked for every auth_mode before the user is asked for anything: the client must connect from
+// within allow_cidr, and have a conversation function when the flow needs one
+fn check_client(
+    pam: &dyn Pam,
+    args: &HashMap<String, String>,
+    username: &str,
+    needs_conversation: bool,
+) -> Result<(), PamReturnCode> {
+    let source_policy = access::SourcePolicy::from_args(args).map_err(|err| {
+        logging::log_to_file(&err);
+        PamReturnCode::SERVICE_ERR
+    })?;
+    // Non-interactive clients can't run any of the flows
+    let no_conversation = match args.get("no_conversation").map(|c| c.as_str()) {
+        None if args.contains_key("soft_fail") => PamReturnCode::IGNORE,
+        Some("auth_err") | None => PamReturnCode::AUTH_ERR,
+        Some("ignore") => PamReturnCode::IGNORE,
+        Some(other) => {
+            logging::log_to_file(&format!("Invalid no_conversation code: {}", other));
+            return Err(PamReturnCode::SERVICE_ERR);
+        }
+    };
+
+    let rhost = pam.item(PamItemType::RHOST);
+    if let Some(policy) = &source_policy
+        && !policy.allows(rhost.as_deref())
+    {
+        logging::log_to_file(&format!("User {} connected from {:?} outside allow_cidr", username, rhost));
+        return Err(match policy.outside {
+            access::Outside::Ignore => PamReturnCode::IGNORE,
+            access::Outside::Deny => PamReturnCode::AUTH_ERR,
+        });
+    }
+    if needs_conversation && !pam.has_conversation() {
+        logging::log_to_file(&format!(
+            "No PAM conversation function for user {}, the client is not interactive",
+            username
+        ));
+        return Err(no_conversation);
+    }
+    Ok(())
+}
+
+// auth_mode=actions_oidc: the job passes its ID token as the password, or the token is prompted for
+#[cfg_attr(feature = "trace", tracing::instrument(skip_all))]
+fn authenticate_oidc(
+    pam: &dyn Pam,
+    args: &HashMap<String, String>,
+    event: &mut audit::AuthEvent,
+    errors: &ErrorReport,
+) -> PamReturnCode {
+    let oidc_policy = match oidc::OidcPolicy::from_args(args) {
+        Ok(policy) => policy,
+        Err(err) => {
+            logging::log_to_file(&err);
+            return PamReturnCode::SERVICE_ERR;
+        }
+    };
+    let failure_policy = match FailurePolicy::from_args(args) {
+        Ok(policy) => policy,
+        Err(err) => {
+            logging::log_to_file(&err);
+            return PamReturnCode::SERVICE_ERR;
+        }
+    };
+    let deny_list = match access::DenyList::from_args(args) {
+        Ok(list) => list,
+        Err(err) => {
+            logging::log_to_file(&err);
+            return PamReturnCode::SERVICE_ERR;
+        }
+    };
+    let client = match build_client(args) {
+        Ok(client) => client,
+        Err(code) => return code,
+    };
+    let username = match get_username(pam) {
+        Ok(username) => username,
+        Err(code) => return code,
+    };
+    logging::log_to_file(&format!("Actions OIDC authentication request for username: {}", username));
+    let rhost = pam.item(PamItemType::RHOST);
+    event.local_user = Some(username.clone());
+    // Only prompted for when the job didn't pass it as the password
+    let token = pam.item(PamItemType::AUTHTOK).filter(|token| !token.is_empty());
+    if let Err(code) = check_client(pam, args, &username, token.is_none()) {
+        return code;
+    }
+    let lockout = match lockout::Lockout::from_args(args, &username, rhost.as_deref()) {
+        Ok(lockout) => lockout,
+        Err(err) => {
+            logging::log_to_file(&err);
+            return PamReturnCode::SERVICE_ERR;
+        }
+    };
+    if lockout.is_locked() {
+        errors.fail_with(
+            "Too many failed attempts, this account is temporarily locked",
+            &format!("User {} from {:?} is temporarily locked out", username, rhost),
+        );
+        return PamReturnCode::AUTH_ERR;
+    }
+
+    let token = match token {
+        Some(token) => token,
+        None => match conversation::prompt(pam, "GitHub Actions ID token: ", PamMessageStyle::PROMPT_ECHO_OFF) {
+            Ok(token) => token,
+            Err(err) => {
+                logging::log_to_file(&format!("Failed to prompt user: {:?}", err));
+                return PamReturnCode::SERVICE_ERR;
+            }
+        },
+    };
+    let issuer = oidc_policy.issuer(&client);
+    let keys = match metrics::timed("oidc_keys", || client.actions_jwks(&issuer)) {
+        Ok(keys) => keys,
+        Err(err) => {
+            errors.fail(Failure::from_error(&err), &format!("Failed to get the keys of {}: {}", issuer, err));
+            return unreachable_code(pam, args, &failure_policy, errors, &err, &username);
+        }
+    };
+    let claims = match oidc_policy.verify(token.trim(), &issuer, &keys, &username) {
+        Ok(claims) => claims,
+        Err(err) => {
+            errors.fail(Failure::Unauthorized, &format!("Actions ID token rejected for user {}: {}", username, err));
+            lockout.record(Failure::Unauthorized);
+            return failure_policy.code(Failure::Unauthorized);
+        }
+    };
+    if deny_list.denies(&claims.actor) {
+        errors.fail(Failure::DeniedUser, &format!("Run of {} was triggered by {}, who is in the deny list", claims.repository, claims.actor));
+        return failure_policy.code(Failure::DeniedUser);
+    }
+    logging::log_to_file(&format!(
+        "User {} authenticated as workflow {} of {} on {}, run {} by {}",
+        username,
+        claims.workflow.as_deref().unwrap_or("-"),
+        claims.repository,
+        claims.git_ref,
+        claims.run_id.as_deref().unwrap_or("-"),
+        claims.actor
+    ));
+    event.github_login = Some(claims.actor.clone());
+    logging::set_field("GITHUB_LOGIN", &claims.actor);
+    event.org = Some(claims.repository_owner);
+    lockout.clear();
+    PamReturnCode::SUCCESS
+}
+
+#[cfg_attr(feature = "trace", tracing::instrument(skip_all))]
+fn authenticate(
+    pam: &dyn Pam,
+    args: &HashMap<String, String>,
+    event: &mut audit::AuthEvent,
+    errors: &ErrorReport,
+) -> PamReturnCode {
+    // CI jobs prove where they run with an ID token, not with a GitHub account
+    if args.get("auth_mode").is_some_and(|mode| mode == "actions_oidc") {
+        return authenticate_oidc(pam, args, event, errors);
+    }
+    // Organizations or repository, the GitHub App and the HTTP client
+    let authenticator = match auth::GithubAuthenticator::from_args(args) {
+        Ok(authenticator) => authenticator,
+        Err(err) => {
+            logging::log_to_file(&err);
+            return PamReturnCode::SERVICE_ERR;
+        }
+    };
+    let client = authenticator.client();
+    let github_app = authenticator.app();
+    let auth_mode = match AuthMode::from_arg(args.get("auth_mode").map(|m| m.as_str())) {
+        Some(mode) => mode,
+        None => {
+            logging::log_to_file(&format!("Invalid auth mode: {:?}", args.get("auth_mode")));
+            return PamReturnCode::SERVICE_ERR;
+        }
+    };
+    let allow_users_mode = match AllowUsersMode::from_arg(args.get("allow_users_mode").map(|m| m.as_str())) {
+        Some(mode) => mode,
+        None => {
+            logging::log_to_file(&format!("Invalid allow_users_mode: {:?}", args.get("allow_users_mode")));
+            return PamReturnCode::SERVICE_ERR;
+        }
+    };
+    let oauth_app = match github::OAuthApp::from_args(args) {
+        Ok(app) => app,
+        Err(err) => {
+            logging::log_to_file(&err);
+            return PamReturnCode::SERVICE_ERR;
+        }
+    };
+    if auth_mode == AuthMode::Device && oauth_app.is_none() {
+        logging::log_to_file("Missing client ID");
+        return PamReturnCode::SERVICE_ERR;
+    }
+    #[cfg(feature = "accounts")]
+    let account_setup = match AccountSetup::from_args(args) {
+        Ok(setup) => setup,
+        Err(err) => {
+            logging::log_to_file(&err);
+            return PamReturnCode::SERVICE_ERR;
+        }
+    };
+    // Keys are then synced when the session opens instead of asking
+    #[cfg(feature = "keys")]
+    let sync_keys_on_session = args.contains_key("sync_keys_on_session");
+    #[cfg(feature = "keys")]
+    let allow_import_keys = args.contains_key("allow_import_keys") && !sync_keys_on_session;
+
+    let max_retries = match args.get("max_retries").map(|r| r.parse::<u32>()) {
+        Some(Ok(retries)) => retries,
+        Some(Err(_)) => {
+            logging::log_to_file(&format!("Invalid max_retries: {:?}", args.get("max_retries")));
+            return PamReturnCode::SERVICE_ERR;
+        }
+        None => 2,
+    };
+    #[cfg(feature = "keys")]
+    let key_policy = match github::KeyPolicy::from_args(args) {
+        Ok(policy) => policy,
+        Err(err) => {
+            logging::log_to_file(&err);
+            return PamReturnCode::SERVICE_ERR;
+        }
+    };
+    let include_child_teams = args.contains_key("include_child_teams");
+    let team_policy = match github::TeamPolicy::from_arg(args.get("team_policy").map(|p| p.as_str())) {
+        Some(policy) => policy,
+        None => {
+            logging::log_to_file(&format!("Invalid team policy: {:?}", args.get("team_policy")));
+            return PamReturnCode::SERVICE_ERR;
+        }
+    };
+    let allow_pending = args.contains_key("allow_pending");
+    let require_role = match args.get("require_role") {
+        Some(role) => match github::GithubRole::from_arg(role) {
+            Some(role) => Some(role),
+            None => {
+                logging::log_to_file(&format!("Invalid required role: {}", role));
+                return PamReturnCode::SERVICE_ERR;
+            }
+        },
+        None => None,
+    };
+
+    let failure_policy = match FailurePolicy::from_args(args) {
+        Ok(policy) => policy,
+        Err(err) => {
+            logging::log_to_file(&err);
+            return PamReturnCode::SERVICE_ERR;
+        }
+    };
+
+    if let Err(code) = parse_deprovision(args) {
+        return code;
+    }
+    // Deprovisioning relies on the recorded identities to know which users are GitHub-backed
+    let bind_github_id = args.contains_key("bind_github_id") || args.contains_key("deprovision");
+    let store_token = args.contains_key("store_token");
+    if store_token && args.contains_key("revoke_token_after_auth") {
+        logging::log_to_file("store_token can't be combined with revoke_token_after_auth");
+        return PamReturnCode::SERVICE_ERR;
+    }
+    let mut token_revoker = if args.contains_key("revoke_token_after_auth") {
+        match oauth_app.as_ref() {
+            Some(github::OAuthApp { client_id, client_secret: Some(client_secret), .. }) => Some(TokenRevoker {
+                client,
+                client_id,
+                client_secret,
+                token: None,
+            }),
+            _ => {
+                logging::log_to_file("revoke_token_after_auth requires client_id and client_secret");
+                return PamReturnCode::SERVICE_ERR;
+            }
+        }
+    } else {
+        None
+    };
+    let totp_secrets = match args.get("totp_secret_file") {
+        Some(path) => match totp::TotpSecrets::load(path) {
+            Ok(secrets) => Some(secrets),
+            Err(err) => {
+                logging::log_to_file(&err);
+                return PamReturnCode::SERVICE_ERR;
+            }
+        },
+        None => None,
+    };
+    let user_map = match args.get("user_map") {
+        Some(path) => match usermap::UserMap::load(path) {
+            Ok(map) => map,
+            Err(err) => {
+                logging::log_to_file(&err);
+                return PamReturnCode::SERVICE_ERR;
+            }
+        },
+        None => usermap::UserMap::default(),
+    };
+    let owner_token = match github::owner_token_from_args(args) {
+        Ok(token) => token,
+        Err(err) => {
+            logging::log_to_file(&err);
+            return PamReturnCode::SERVICE_ERR;
+        }
+    };
+    // Only owners see who has 2FA disabled, which the user logging in usually isn't
+    let require_2fa = args.contains_key("require_2fa");
+    if require_2fa && github_app.is_none() && owner_token.is_none() {
+        logging::log_to_file("require_2fa needs app_id and app_private_key, or owner_token_file");
+        return PamReturnCode::SERVICE_ERR;
+    }
+    // Without a user token, membership can only be checked as the app
+    if auth_mode == AuthMode::SshKey && github_app.is_none() {
+        logging::log_to_file("auth_mode=ssh_key requires app_id and app_private_key");
+        return PamReturnCode::SERVICE_ERR;
+    }
+    let sudo_grace = match parse_sudo_grace(args) {
+        Ok(grace) => grace,
+        Err(code) => return code,
+    };
+    let banner = match args.get("banner").map(|b| github::RepoFile::parse(b)) {
+        Some(Ok(banner)) => Some(banner),
+        Some(Err(err)) => {
+            logging::log_to_file(&err);
+            return PamReturnCode::SERVICE_ERR;
+        }
+        None => None,
+    };
+    let policy_source = match policy::Policy::from_args(args) {
+        Ok(source) => source,
+        Err(err) => {
+            logging::log_to_file(&err);
+            return PamReturnCode::SERVICE_ERR;
+        }
+    };
+    let deny_list = match access::DenyList::from_args(args) {
+        Ok(list) => list,
+        Err(err) => {
+            logging::log_to_file(&err);
+            return PamReturnCode::SERVICE_ERR;
+        }
+    };
+    let email_domains = args.get("require_email_domain").map(|domains| parse_list(domains));
+    if auth_mode == AuthMode::SshKey && email_domains.is_some() {
+        logging::log_to_file("require_email_domain needs a user token and can't be used with auth_mode=ssh_key");
+        return PamReturnCode::SERVICE_ERR;
+    }
+
+    // Get username
+    let username = match get_username(pam) {
+        Ok(username) => username,
+        Err(code) => return code,
+    };
+
+    logging::log_to_file(&format!("Authentication request for username: {}", username));
+    let rhost = pam.item(PamItemType::RHOST);
+    event.local_user = Some(username.clone());
+    let service = pam.item(PamItemType::SERVICE);
+    let github_logins = user_map.github_logins(&username);
+    // Machine accounts can't complete a browser flow. They log in with a personal access token, or
+    // are left to another module. Their accounts are then kept up to date by acct_mgmt
+    let auth_mode = match auth_mode == AuthMode::Device && is_allowed_user(args, &username, &github_logins) {
+        true if allow_users_mode == AllowUsersMode::Ignore => {
+            logging::log_to_file(&format!("User {} is in allow_users, leaving authentication to other modules", username));
+            return PamReturnCode::IGNORE;
+        }
+        true => {
+            logging::log_to_file(&format!("User {} is in allow_users, asking for a personal access token", username));
+            AuthMode::Pat
+        }
+        false => auth_mode,
+    };
+    // A personal access token may already be the password, from a module stacked before this one
+    // or a client that can't be prompted. Only without it is there anything to ask
+    let authtok = match auth_mode {
+        AuthMode::Pat => pam.item(PamItemType::AUTHTOK).filter(|token| !token.is_empty()),
+        _ => None,
+    };
+    if let Err(code) = check_client(pam, args, &username, authtok.is_none()) {
+        return code;
+    }
+    let lockout = match lockout::Lockout::from_args(args, &username, rhost.as_deref()) {
+        Ok(lockout) => lockout,
+        Err(err) => {
+            logging::log_to_file(&err);
+            return PamReturnCode::SERVICE_ERR;
+        }
+    };
+    if lockout.is_locked() {
+        errors.fail_with(
+            "Too many failed attempts, this account is temporarily locked",
+            &format!("User {} from {:?} is temporarily locked out", username, rhost),
+        );
+        return PamReturnCode::AUTH_ERR;
+    }
+    // Blocked accounts are refused before GitHub is asked, and before anything could let them in,
+    // like a key imported for them with skip_if_publickey
+    if !deny_list.is_empty() {
+        let logins = match known_logins(&username, user_map.github_logins(&username)) {
+            Ok(logins) => logins,
+            Err(err) => {
+                logging::log_to_file(&format!("Failed to look up the GitHub login of {}: {}", username, err));
+                return PamReturnCode::SERVICE_ERR;
+            }
+        };
+        if let Some(login) = logins.iter().find(|login| deny_list.denies(login)) {
+            errors.fail(Failure::DeniedUser, &format!("GitHub user {} of user {} is in the deny list", login, username));
+            return failure_policy.code(Failure::DeniedUser);
+        }
+    }
+    if let Some(grace) = sudo_grace
+        && matches!(service.as_deref(), Some("sudo") | Some("sudo-i"))
+        && let Some(code) = confirm_sudo(pam, &username, grace)
+    {
+        return code;
+    }
+    #[cfg(feature = "keys")]
+    if args.contains_key("skip_if_publickey")
+        && let Some(auth_info) = pam.env("SSH_AUTH_INFO_0")
+    {
+        match keys::KeySync::new(&username).was_used(&auth_info) {
+            Ok(true) => {
+                logging::log_to_file(&format!("User {} already authenticated with a key imported from GitHub", username));
+                lockout.clear();
+                return PamReturnCode::SUCCESS;
+            }
+            Ok(false) => {}
+            Err(err) => logging::log_to_file(&format!("Failed to read managed keys: {}", err)),
+        }
+    }
+
+    let from_token = |token: String| metrics::timed("membership", || authenticator.user_with_token(&token, &github_logins));
+
+    // Prove the user's identity, either with a user token or with the SSH key used
+    // for the first factor, then retrieve user info
+    let mut user_token = None;
+    // With the expiry and refresh token of apps issuing expiring tokens, for store_token
+    let mut issued_token = None;
+    let github_user = match auth_mode {
+        AuthMode::Device => {
+            let app = oauth_app.as_ref().unwrap();
+            let mut device_code = match device_flow(pam, &authenticator, app, args, &failure_policy, errors, &username) {
+                Ok(code) => code,
+                Err(code) => return code,
+            };
+            // A code that is not authorized yet is polled again, one that expired or was denied can
+            // be replaced without reconnecting. Only new codes count against max_retries
+            let mut retries = 0;
+            let token = loop {
+                let token = metrics::timed("token_exchange", || client.exchange_device_code(&device_code, app));
+                let (error, interval) = match &token {
+                    Err(github::GithubError::DeviceFlow { error, interval, .. }) => (error.as_str(), *interval),
+                    _ => break token,
+                };
+                match error {
+                    "authorization_pending" => {
+                        logging::log_to_file("Device flow authorization is still pending");
+                        let message = "This login has not been authorized yet.\n\
+                                      After authorizing it, press Enter to continue...";
+                        if conversation::prompt(pam, message, PamMessageStyle::PROMPT_ECHO_OFF).is_err() {
+                            break token;
+                        }
+                        continue;
+                    }
+                    // Polled too soon, GitHub says how long to wait. The user already confirmed
+                    "slow_down" => {
+                        let wait = interval.unwrap_or(DEVICE_FLOW_INTERVAL);
+                        logging::log_to_file(&format!("Device flow asked to slow down, polling again in {}s", wait));
+                        thread::sleep(Duration::from_secs(wait));
+                        continue;
+                    }
+                    "expired_token" | "access_denied" if retries < max_retries => {}
+                    // e.g. incorrect_client_credentials or device_flow_disabled, which a new code doesn't fix
+                    _ => break token,
+                }
+                retries += 1;
+                logging::log_to_file(&format!("Device flow failed with {}, new code {} of {}", error, retries, max_retries));
+                let message = "The code has expired or was denied. Get a new code? (y/n) ";
+                match conversation::prompt(pam, message, PamMessageStyle::PROMPT_ECHO_ON) {
+                    Ok(answer) if answer.trim().eq_ignore_ascii_case("y") => {}
+                    _ => break token,
+                }
+                device_code = match device_flow(pam, &authenticator, app, args, &failure_policy, errors, &username) {
+                    Ok(code) => code,
+                    Err(code) => return code,
+                };
+            };
+            // Only OAuth app tokens have scopes, GitHub Apps are limited by their permissions
+            if let Ok(token) = &token {
+                let mut needed = required_scopes(args, github_app.is_some());
+                needed.extend(app.scopes.iter().map(|s| s.as_str()));
+                let missing = github::missing_scopes(&token.scopes, &needed);
+                if !token.scopes.is_empty() && !missing.is_empty() {
+                    logging::log_to_file(&format!(
+                        "Token granted scopes {:?}, missing {:?} needed by the configuration",
+                        token.scopes, missing
+                    ));
+                }
+                if let Some(revoker) = token_revoker.as_mut() {
+                    revoker.token = Some(token.token.clone());
+                }
+                user_token = Some(token.token.clone());
+            }
+            token.map(|token| {
+                let access_token = token.token.clone();
+                issued_token = Some(token);
+                access_token
+            })
+            .and_then(from_token)
+        }
+        AuthMode::Pat => {
+            let token = match authtok {
+                Some(token) => token,
+                None => match conversation::prompt(pam, "GitHub personal access token: ", PamMessageStyle::PROMPT_ECHO_OFF) {
+                    Ok(token) => token,
+                    Err(err) => {
+                        logging::log_to_file(&format!("Failed to prompt user: {:?}", err));
+                        return PamReturnCode::SERVICE_ERR;
+                    }
+                },
+            };
+            let token = token.trim().to_string();
+            user_token = Some(token.clone());
+            issued_token = Some(github::UserToken { token: token.clone(), ..Default::default() });
+            from_token(token)
+        }
+        AuthMode::SshKey => {
+            let auth_info = match pam.env("SSH_AUTH_INFO_0") {
+                Some(info) => info,
+                None => {
+                    logging::log_to_file("SSH_AUTH_INFO_0 is not set, is ExposeAuthInfo enabled?");
+                    return PamReturnCode::AUTH_ERR;
+                }
+            };
+            metrics::timed("membership", || authenticator.user_with_key(&github_logins, &auth_info))
+        }
+        AuthMode::ActionsOidc => unreachable!("handled by authenticate_oidc"),
+    };
+    let github_user = match github_user {
+        Ok(user) => {
+            logging::log_to_file(&format!(
+                "User {} authenticated as GitHub user {} in organization {}",
+                username, user.username, user.org()
+            ));
+            event.github_login = Some(user.username.clone());
+            logging::set_field("GITHUB_LOGIN", &user.username);
+            event.github_id = Some(user.id());
+            event.org = Some(user.org().to_string());
+            user
+        },
+        Err(err) => {
+            let failure = Failure::from_error(&err);
+            match &err {
+                github::GithubError::NotFound(_) => {
+                    errors.fail(failure, &format!("User not found in organization: {}", err));
+                }
+                github::GithubError::InvalidUser(info) => {
+                    errors.fail(failure, &format!("Invalid user: {}", info));
+                }
+                github::GithubError::Unauthorized(_) | github::GithubError::DeviceFlow { .. } => {
+                    errors.fail(failure, &format!("Unauthorized access: {}", err));
+                }
+                github::GithubError::SsoRequired(url) => {
+                    let message = format!(
+                        "{}.\n\
+                        Please visit {} to authorize this login, then try again.",
+                        failure.message(),
+                        url
+                    );
+                    errors.fail_with(&message, &format!("Token is not authorized for SAML SSO: {}", url));
+                }
+                _ => {
+                    errors.fail(failure, &format!("Unexpected error: {}", err));
+                }
+            }
+            lockout.record(failure);
+            return unreachable_code(pam, args, &failure_policy, errors, &err, &username);
+        }
+    };
+
+
+    // The account may go by another login than the mapped one, e.g. a bound identity
+    if deny_list.denies(&github_user.username) {
+        errors.fail(Failure::DeniedUser, &format!("GitHub user {} is in the deny list", github_user.username));
+        return failure_policy.code(Failure::DeniedUser);
+    }
+
+    // Invited users show up as members before they accepted the invitation
+    if github_user.state == github::GithubState::Pending && !allow_pending {
+        let github_url = args.get("github_url").map(|url| url.trim_end_matches('/')).unwrap_or("https://github.com");
+        errors.fail_with(
+            &format!(
+                "{}.\nPlease accept the invitation at {}/orgs/{}/invitation, then try again.",
+                Failure::PendingMembership.message(),
+                github_url,
+                github_user.org()
+            ),
+            &format!("Membership of {} in {} is still pending", github_user.username, github_user.org()),
+        );
+        lockout.record(Failure::PendingMembership);
+        return failure_policy.code(Failure::PendingMembership);
+    }
+
+    if let Some(role) = require_role && !github_user.role.satisfies(role) {
+        errors.fail(Failure::MissingRole, &format!(
+            "User role {:?} does not satisfy required role {:?}",
+            github_user.role, role
+        ));
+        lockout.record(Failure::MissingRole);
+        return failure_policy.code(Failure::MissingRole);
+    }
+
+    if let (Some(domains), Some(token)) = (&email_domains, &user_token) {
+        let emails = match client.verified_emails(token) {
+            Ok(emails) => emails,
+            Err(err) => {
+                errors.fail(Failure::from_error(&err), &format!("Failed to fetch email addresses: {}", err));
+                return unreachable_code(pam, args, &failure_policy, errors, &err, &username);
+            }
+        };
+        let matched = emails.iter().any(|email| {
+            domains.iter().any(|domain| email.to_ascii_lowercase().ends_with(&format!("@{}", domain.to_ascii_lowercase())))
+        });
+        if !matched {
+            errors.fail_with(
+                &format!("A verified email address in {} is required on your GitHub account", domains.join(", ")),
+                &format!("GitHub user {} has no verified email address in {:?}", github_user.username, domains),
+            );
+            lockout.record(Failure::EmailDomain);
+            return failure_policy.code(Failure::EmailDomain);
+        }
+    }
+
+    if require_2fa {
+        let token = match (github_app, &owner_token) {
+            (Some(app), _) => app.installation_token(client, github_user.org()),
+            (None, token) => Ok(token.clone().unwrap_or_default()),
+        };
+        match token.and_then(|token| github_user.has_2fa(&token)) {
+            Ok(true) => {}
+            Ok(false) => {
+                errors.fail(Failure::No2fa, &format!("GitHub user {} does not have 2FA enabled", github_user.username));
+                lockout.record(Failure::No2fa);
+                return failure_policy.code(Failure::No2fa);
+            }
+            Err(err) => {
+                errors.fail(Failure::from_error(&err), &format!("Failed to check 2FA status: {}", err));
+                return unreachable_code(pam, args, &failure_policy, errors, &err, &username);
+            }
+        }
+    }
+
+    if let Some(team) = args.get("team") {
+        let teams = match team_slugs(&github_user, &parse_list(team)) {
+            Ok(teams) => teams,
+            Err(err) => {
+                errors.fail(Failure::from_error(&err), &format!("Failed to list organization teams: {}", err));
+                return unreachable_code(pam, args, &failure_policy, errors, &err, &username);
+            }
+        };
+        let teams: Vec<&str> = teams.iter().map(|t| t.as_str()).collect();
+        event.teams = teams.iter().map(|t| t.to_string()).collect();
+        logging::log_to_file(&format!("Checking team membership ({:?}) for teams: {:?}", team_policy, teams));
+        let check = || match include_child_teams {
+            true => github_user.check_team_trees(&teams, team_policy),
+            false => github_user.check_teams(&teams, team_policy),
+        };
+        let team_found = match metrics::timed("teams", check) {
+            Ok(found) => {
+                logging::debug(&format!("Team check returned {}", found));
+                found
+            }
+            Err(err) => {
+                errors.fail(Failure::from_error(&err), &format!("Failed to check team membership: {}", err));
+                return unreachable_code(pam, args, &failure_policy, errors, &err, &username);
+            }
+        };
+        if !team_found {
+            errors.fail(Failure::NotInTeam, "User is not a member of the required team");
+            lockout.record(Failure::NotInTeam);
+            return failure_policy.code(Failure::NotInTeam);
+        }
+    }
+
+    if let Some(source) = &policy_source {
+        match source.allows(client, github_app, user_token.as_deref(), &github_user) {
+            Ok(true) => {}
+            Ok(false) => {
+                errors.fail(Failure::NotInPolicy, &format!(
+                    "GitHub user {} is not allowed on host group {}",
+                    github_user.username, source.host_group
+                ));
+                lockout.record(Failure::NotInPolicy);
+                return failure_policy.code(Failure::NotInPolicy);
+            }
+            Err(err) => {
+                errors.fail(Failure::from_error(&err), &format!("Failed to check access policy: {}", err));
+                return unreachable_code(pam, args, &failure_policy, errors, &err, &username);
+            }
+        }
+    }
+
+    if bind_github_id {
+        match state::bind_identity(&username, github_user.id(), &github_user.username) {
+            Ok(state::IdentityCheck::Recorded) => {
+                logging::log_to_file(&format!("Bound user {} to GitHub ID {}", username, github_user.id()));
+            }
+            Ok(state::IdentityCheck::Matched) => {}
+            Ok(state::IdentityCheck::Mismatch(expected)) => {
+                errors.fail(Failure::IdentityMismatch, &format!(
+                    "GitHub ID {} of {} does not match ID {} bound to user {}",
+                    github_user.id(), github_user.username, expected, username
+                ));
+                lockout.record(Failure::IdentityMismatch);
+                return failure_policy.code(Failure::IdentityMismatch);
+            }
+            Err(err) => {
+                logging::log_to_file(&format!("Failed to check identity binding: {}", err));
+                return PamReturnCode::SERVICE_ERR;
+            }
+        }
+    }
+
+    if let Some(secrets) = &totp_secrets
+        && let Err(failure) = check_totp(pam, errors, secrets, &username)
+    {
+        lockout.record(failure);
+        return failure_policy.code(failure);
+    }
+
+    match conversation::prompt(pam, "Authentication successful", PamMessageStyle::TEXT_INFO) {
+        Ok(_) => {},
+        Err(err) => {
+            logging::log_to_file(&format!("Failed to prompt user: {:?}", err));
+            return PamReturnCode::SERVICE_ERR;
+        }
+    }
+    logging::log_to_file(&format!("Authentication successful for user {}", username));
+    if let Some(banner) = &banner {
+        show_banner(pam, client, github_app, user_token.as_deref(), banner);
+    }
+    if store_token && let Some(token) = &issued_token {
+        store_user_token(&username, token);
+    }
+    failure_policy.record_success(&username, &github_user);
+    lockout.clear();
+    // Hands the GitHub login to the session stack of the same PAM transaction
+    #[cfg(feature = "keys")]
+    if sync_keys_on_session && let Err(code) = pam.set_data(LOGIN_DATA, &github_user.username) {
+        logging::log_to_file(&format!("Failed to store GitHub login for the session: {:?}", code));
+    }
+
+
+    #[cfg(feature = "accounts")]
+    if let Some(setup) = &account_setup {
+        match update_account(setup, &github_user, &username, include_child_teams) {
+            Ok(existed) => {
+                if existed {
+                    logging::log_to_file(&format!("User {} already exists", username));
+                } else if user::is_dry_run() {
+                    // No account was created, so there is nothing to mark, announce or import keys into
+                    logging::log_to_file(&format!("Dry run: user {} was not created", username));
+                    return PamReturnCode::SUCCESS;
+                } else {
+                    logging::log_to_file(&format!("Created user {}", username));
+                    if setup.ephemeral && let Err(err) = state::mark_ephemeral(&username) {
+                        logging::log_to_file(&format!("Failed to mark user {} as ephemeral: {}", username, err));
+                    }
+                    
+                    // Prompt user about account creation
+                    let message = "Your account has been created successfully!\n\
+                                  After this session, you'll need to disconnect and log in again for your new account to be fully recognized.";
+                    let _ = conversation::prompt(pam, message, PamMessageStyle::TEXT_INFO);
+                    
+                    if setup.import_keys && let Err(code) = import_keys(pam, &github_user, &username, &key_policy) {
+                        return code;
+                    }
+
+                    return PamReturnCode::SUCCESS;
+                }
+            },
+            Err(err) => {
+                logging::log_to_file(&err);
+                return PamReturnCode::SERVICE_ERR;
+            }
+        }
+    }
+
+    #[cfg(feature = "keys")]
+    if allow_import_keys {
+        let ans = conversation::prompt(
+            pam,
+            "Do you want to import your SSH keys from GitHub? (y/n) ",
+            PamMessageStyle::PROMPT_ECHO_ON,
+        );
+        if let Err(err) = ans {
+            logging::log_to_file(&format!("Failed to prompt user: {:?}", err));
+            return PamReturnCode::SERVICE_ERR;
+        }
+        let ans = ans.unwrap();
+        let ans = ans.trim().to_lowercase();
+        if ans != "y" {
+            logging::log_to_file("User declined to import keys");
+            return PamReturnCode::SUCCESS;
+        }
+        logging::log_to_file("User accepted to import keys");
+        if let Err(code) = import_keys(pam, &github_user, &username, &key_policy) {
+            return code;
+        }
+    }
+
+    PamReturnCode::SUCCESS
+}
+
+
+
+// Creates the account or brings an existing one in line with the user's GitHub account: groups,
+// role groups, expiry and, with sudoer_teams, sudo. True if the account already existed
+#[cfg(feature = "accounts")]
+fn update_account(
+    setup: &AccountSetup,
+    github_user: &github::GithubUser,
+    username: &str,
+    include_child_teams: bool,
+) -> Result<bool, String> {
+    // With sudoer_teams, sudo follows team membership and is left alone if that cannot be checked
+    let privileged = match setup.sudo_policy.as_ref().and_then(|policy| policy.teams.as_ref()) {
+        Some(teams) => {
+            let teams: Vec<&str> = teams.iter().map(|t| t.as_str()).collect();
+            let found = team_slugs(github_user, &teams).and_then(|teams| {
+                let teams: Vec<&str> = teams.iter().map(|t| t.as_str()).collect();
+                match include_child_teams {
+                    true => github_user.check_team_trees(&teams, github::TeamPolicy::Any),
+                    false => github_user.check_teams(&teams, github::TeamPolicy::Any),
+                }
+            });
+            match found {
+                Ok(found) => Some(found),
+                Err(err) => {
+                    logging::log_to_file(&format!("Failed to check sudoer teams: {}", err));
+                    None
+                }
+            }
+        }
+        None => Some(true),
+    };
+    let sudo_grant = setup.sudo_policy.as_ref().filter(|_| privileged == Some(true)).map(|policy| &policy.grant);
+    let uid = setup.uid_offset.map(|offset| user::uid_for(offset, github_user.id())).transpose()?;
+    let existed = metrics::timed("user_creation", || user::ensure_user_exists(username, sudo_grant, uid, &setup.groups))
+        .map_err(|err| format!("Failed to create user: {}", err))?;
+    if let Some(days) = setup.expiry_days
+        && let Err(err) = user::set_account_expiry(username, days)
+    {
+        logging::log_to_file(&format!("Failed to update account expiry for {}: {}", username, err));
+    }
+    if let Err(err) = setup.role_groups.apply(username, github_user.role, &setup.groups) {
+        logging::log_to_file(&format!("Failed to update role groups for {}: {}", username, err));
+    }
+    if existed
+        && let Some(policy) = setup.sudo_policy.as_ref().filter(|policy| policy.teams.is_some())
+        && let Some(privileged) = privileged
+        && let Err(err) = user::set_sudo(username, &policy.grant, privileged)
+    {
+        logging::log_to_file(&format!("Failed to update sudo for {}: {}", username, err));
+    }
+    Ok(existed)
+}
+
+#[allow(improper_ctypes_definitions)]
+#[unsafe(no_mangle)]
+pub extern "C" fn pam_sm_setcred(
+    _pamh: *mut PamHandle,
+    _flags: PamFlag,
+    _argc: libc::c_int,
+    _argv: *const *const libc::c_char,
+) -> PamReturnCode {
+    PamReturnCode::SUCCESS
+}
+
+#[unsafe(no_mangle)]
+#[allow(improper_ctypes_definitions)]
+pub extern "C" fn pam_sm_acct_mgmt(
+    pamh: *mut PamHandle,
+    _flags: PamFlag,
+    argc: libc::c_int,
+    argv: *const *const libc::c_char,
+) -> PamReturnCode {
+    let pam = pam::Handle(pamh);
+    let args = match service_args(&pam, parse_args(argc, argv)) {
+        Ok(args) => args,
+        Err(code) => return code,
+    };
+    acct_mgmt_with(&pam, &args)
+}
+
+// Everything pam_sm_acct_mgmt does once the arguments are parsed
+pub fn acct_mgmt_with(pam: &dyn Pam, args: &HashMap<String, String>) -> PamReturnCode {
+    let code = traced("pam_sm_acct_mgmt", pam, args, || acct_mgmt(pam, args));
+    write_kernel_audit(pam, args, audit::KernelRecord::UserAcct, code);
+    code
+}
+
+// With `auditd`, every decision is also recorded by the kernel's audit subsystem like pam_unix's.
+// Users this module leaves to the others get no record
+fn write_kernel_audit(pam: &dyn Pam, args: &HashMap<String, String>, record: audit::KernelRecord, code: PamReturnCode) {
+    if !args.contains_key("auditd") || code == PamReturnCode::IGNORE {
+        return;
+    }
+    let account = pam.item(PamItemType::USER).unwrap_or_else(|| "?".to_string());
+    audit::write_kernel(
+        record,
+        &account,
+        pam.item(PamItemType::RHOST).as_deref(),
+        pam.item(PamItemType::TTY).as_deref(),
+        code == PamReturnCode::SUCCESS,
+    );
+}
+
+#[cfg_attr(feature = "trace", tracing::instrument(skip_all))]
+fn acct_mgmt(pam: &dyn Pam, args: &HashMap<String, String>) -> PamReturnCode {
+    for check in [check_deny_list, check_access_hours, update_allowed_user] {
+        let code = check(pam, args);
+        if code != PamReturnCode::SUCCESS {
+            return code;
+        }
+    }
+    check_deprovision(pam, args)
+}
+
+// allow_users left to another module with allow_users_mode=ignore never pass through this
+// module's authentication, so their accounts are brought up to date here, as the GitHub App. A
+// failure is only logged, as for other users whose account can't be updated at login
+#[cfg(feature = "accounts")]
+fn update_allowed_user(pam: &dyn Pam, args: &HashMap<String, String>) -> PamReturnCode {
+    match AllowUsersMode::from_arg(args.get("allow_users_mode").map(|m| m.as_str())) {
+        Some(AllowUsersMode::Ignore) => {}
+        Some(AllowUsersMode::Pat) => return PamReturnCode::SUCCESS,
+        None => {
+            logging::log_to_file(&format!("Invalid allow_users_mode: {:?}", args.get("allow_users_mode")));
+            return PamReturnCode::SERVICE_ERR;
+        }
+    }
+    let setup = match AccountSetup::from_args(args) {
+        Ok(Some(setup)) => setup,
+        Ok(None) => return PamReturnCode::SUCCESS,
+        Err(err) => {
+            logging::log_to_file(&err);
+            return PamReturnCode::SERVICE_ERR;
+        }
+    };
+    let username = match get_username(pam) {
+        Ok(username) => username,
+        Err(code) => return code,
+    };
+    let user_map = match args.get("user_map") {
+        Some(path) => match usermap::UserMap::load(path) {
+            Ok(map) => map,
+            Err(err) => {
+                logging::log_to_file(&err);
+                return PamReturnCode::SERVICE_ERR;
+            }
+        },
+        None => usermap::UserMap::default(),
+    };
+    let github_logins = user_map.github_logins(&username);
+    if !is_allowed_user(args, &username, &github_logins) {
+        return PamReturnCode::SUCCESS;
+    }
+    let orgs: Vec<&str> = args.get("org").map(|org| parse_list(org)).unwrap_or_default();
+    let app = match load_github_app(args) {
+        Ok(Some(app)) if !orgs.is_empty() => app,
+        Ok(_) => {
+            logging::log_to_file("Accounts of allow_users with allow_users_mode=ignore need org, app_id and app_private_key to be updated");
+            return PamReturnCode::SUCCESS;
+        }
+        Err(code) => return code,
+    };
+    let client = match build_client(args) {
+        Ok(client) => client,
+        Err(code) => return code,
+    };
+    let github_user = github_logins
+        .iter()
+        .map(|login| github::GithubUser::from_app(&client, &app, login, &orgs))
+        .find(|user| !matches!(user, Err(github::GithubError::NotFound(_))));
+    let github_user = match github_user {
+        Some(Ok(github_user)) => github_user,
+        Some(Err(err)) => {
+            logging::log_to_file(&format!("Failed to look up GitHub user of {}: {}", username, err));
+            return PamReturnCode::SUCCESS;
+        }
+        None => {
+            logging::log_to_file(&format!("No GitHub login of {} is in the organization, not updating the account", username));
+            return PamReturnCode::SUCCESS;
+        }
+    };
+    let include_child_teams = args.contains_key("include_child_teams");
+    match update_account(&setup, &github_user, &username, include_child_teams) {
+        Ok(true) => logging::log_to_file(&format!("Updated account {} of GitHub user {}", username, github_user.username)),
+        Ok(false) if user::is_dry_run() => logging::log_to_file(&format!("Dry run: user {} was not created", username)),
+        Ok(false) => logging::log_to_file(&format!("Created user {} for GitHub user {}", username, github_user.username)),
+        Err(err) => logging::log_to_file(&err),
+    }
+    PamReturnCode::SUCCESS
+}
+
+// auto_create_user needs the `accounts` feature, the option is rejected without it
+#[cfg(not(feature = "accounts"))]
+fn update_allowed_user(_pam: &dyn Pam, _args: &HashMap<String, String>) -> PamReturnCode {
+    PamReturnCode::SUCCESS
+}
+
+// Key logins skip authentication, so denied users are also refused here. Only users mapped to a
+// GitHub login or who logged in with GitHub are checked, other local accounts are left alone
+fn check_deny_list(pam: &dyn Pam, args: &HashMap<String, String>) -> PamReturnCode {
+    let deny_list = match access::DenyList::from_args(args) {
+        Ok(list) if list.is_empty() => return PamReturnCode::SUCCESS,
+        Ok(list) => list,
+        Err(err) => {
+            logging::log_to_file(&err);
+            return PamReturnCode::SERVICE_ERR;
+        }
+    };
+    let username = match get_username(pam) {
+        Ok(username) => username,
+        Err(code) => return code,
+    };
+    let mapped = match args.get("user_map").map(|path| usermap::UserMap::load(path)) {
+        Some(Ok(map)) => map.mapped_logins(&username),
+        Some(Err(err)) => {
+            logging::log_to_file(&err);
+            return PamReturnCode::SERVICE_ERR;
+        }
+        None => Vec::new(),
+    };
+    let logins = match known_logins(&username, mapped) {
+        Ok(logins) => logins,
+        Err(err) => {
+            logging::log_to_file(&format!("Failed to look up the GitHub login of {}: {}", username, err));
+            return PamReturnCode::SERVICE_ERR;
+        }
+    };
+    let Some(login) = logins.iter().find(|login| deny_list.denies(login)) else {
+        return PamReturnCode::SUCCESS;
+    };
+    logging::log_to_file(&format!("Denied login for user {}, GitHub user {} is in the deny list", username, login));
+    let _ = conversation::prompt(pam, Failure::DeniedUser.message(), PamMessageStyle::TEXT_INFO);
+    PamReturnCode::PERM_DENIED
+}
+
+// Which of `teams` a local user is in, looked up through the bound identity or the user map in
+// every organization. Users outside them are in none, failed lookups follow on_unreachable
+fn member_teams(
+    args: &HashMap<String, String>,
+    failure_policy: &FailurePolicy,
+    username: &str,
+    teams: &[&str],
+) -> Result<Vec<String>, PamReturnCode> {
+    let orgs: Vec<&str> = args.get("org").map(|org| parse_list(org)).unwrap_or_default();
+    let app = load_github_app(args)?;
+    if orgs.is_empty() || (app.is_none() && !args.contains_key("store_token")) {
+        logging::log_to_file("Team access hours require org, and app_id and app_private_key or store_token");
+        return Err(PamReturnCode::SERVICE_ERR);
+    }
+    let client = build_client(args)?;
+    let user_map = match args.get("user_map") {
+        Some(path) => usermap::UserMap::load(path).map_err(|err| {
+            logging::log_to_file(&err);
+            PamReturnCode::SERVICE_ERR
+        })?,
+        None => usermap::UserMap::default(),
+    };
+    // Teams are looked up as the app, or with the token the user left at their last login. Without
+    // either, the user is held to the windows of users in no team
+    let user_token = match &app {
+        Some(_) => None,
+        #[cfg(feature = "cache")]
+        None => match tokens::access_token(&client, github::OAuthApp::from_args(args).ok().flatten().as_ref(), username) {
+            Ok(Some(token)) => Some(token),
+            Ok(None) => {
+                logging::log_to_file(&format!("No stored token of user {}, not looking up teams for access hours", username));
+                return Ok(Vec::new());
+            }
+            Err(err) => {
+                logging::log_to_file(&format!("Failed to load stored token: {}", err));
+                return Err(PamReturnCode::SERVICE_ERR);
+            }
+        },
+        #[cfg(not(feature = "cache"))]
+        None => return Ok(Vec::new()),
+    };
+    let token_for = |org: &str| match (&app, &user_token) {
+        (Some(app), _) => app.installation_token(&client, org),
+        (None, token) => Ok(token.clone().unwrap_or_default()),
+    };
+    let github_user = |login: &str, org: &str| match (&app, &user_token) {
+        (Some(app), _) => github::GithubUser::from_app(&client, app, login, &[org]),
+        (None, token) => github::GithubUser::from_pat(&client, token.as_deref().unwrap_or_default(), login, &[org]),
+    };
+
+    // Bound users are looked up by ID, with the first organization the app is installed in
+    let login = match state::lookup_identity(username) {
+        Ok(Some(identity)) => orgs
+            .iter()
+            .map(|org| token_for(org))
+            .find(|token| !matches!(token, Err(github::GithubError::AppNotInstalled(_))))
+            .unwrap_or_else(|| token_for(orgs[0]))
+            .and_then(|token| client.login_for_id(identity.github_id, &token)),
+        _ => Ok(user_map.github_logins(username).remove(0)),
+    };
+    // The teams of every organization the user is a member of count
+    let include_child_teams = args.contains_key("include_child_teams");
+    let member_of = login.and_then(|login| {
+        let mut member_of: Vec<String> = Vec::new();
+        for org in &orgs {
+            let user = match github_user(&login, org) {
+                Ok(user) => user,
+                Err(github::GithubError::NotFound(_) | github::GithubError::AppNotInstalled(_)) => continue,
+                Err(err) => return Err(err),
+            };
+            for team in member_of_teams(&user, teams, include_child_teams)? {
+                if !member_of.contains(&team) {
+                    member_of.push(team);
+                }
+            }
+        }
+        Ok(member_of)
+    });
+    match member_of {
+        Ok(member_of) => Ok(member_of),
+        Err(github::GithubError::NotFound(_)) => Ok(Vec::new()),
+        Err(err) => {
+            // Falling back to the windows for everyone would hold exempt teams to them
+            logging::log_to_file(&format!("Failed to look up teams for access hours: {}", err));
+            Err(failure_policy.error_code(&err, username))
+        }
+    }
+}
+
+fn check_access_hours(pam: &dyn Pam, args: &HashMap<String, String>) -> PamReturnCode {
+    let hours = match access::AccessHours::from_args(args) {
+        Ok(Some(hours)) => hours,
+        Ok(None) => return PamReturnCode::SUCCESS,
+        Err(err) => {
+            logging::log_to_file(&err);
+            return PamReturnCode::SERVICE_ERR;
+        }
+    };
+    let failure_policy = match FailurePolicy::from_args(args) {
+        Ok(policy) => policy,
+        Err(err) => {
+            logging::log_to_file(&err);
+            return PamReturnCode::SERVICE_ERR;
+        }
+    };
+    let username = match get_username(pam) {
+        Ok(username) => username,
+        Err(code) => return code,
+    };
+    let teams = hours.teams();
+    let member_of = if teams.is_empty() {
+        Vec::new()
+    } else {
+        match member_teams(args, &failure_policy, &username, &teams) {
+            Ok(member_of) => member_of,
+            Err(code) => return code,
+        }
+    };
+    if hours.allows(&member_of, chrono::Local::now().naive_local()) {
+        return PamReturnCode::SUCCESS;
+    }
+    let windows: Vec<String> = hours.windows_for(&member_of).iter().map(|w| w.to_string()).collect();
+    logging::log_to_file(&format!("Denied login for user {} outside access hours", username));
+    let message = if windows.is_empty() {
+        "Logins are not allowed for this account".to_string()
+    } else {
+        format!("Logins are only allowed during {}", windows.join(", "))
+    };
+    let _ = conversation::prompt(pam, &message, PamMessageStyle::TEXT_INFO);
+    PamReturnCode::PERM_DENIED
+}
+
+#[cfg(feature = "accounts")]
+fn check_deprovision(pam: &dyn Pam, args: &HashMap<String, String>) -> PamReturnCode {
+    let mode = match parse_deprovision(args) {
+        Ok(Some(mode)) => mode,
+        Ok(None) => return PamReturnCode::SUCCESS,
+        Err(code) => return code,
+    };
+    // Sudo granted by group membership is taken away with the account
+    let sudo = match user::SudoPolicy::from_args(args) {
+        Ok(policy) => policy.map(|policy| policy.grant),
+        Err(err) => {
+            logging::log_to_file(&err);
+            return PamReturnCode::SERVICE_ERR;
+        }
+    };
+    let orgs: Vec<&str> = args.get("org").map(|org| parse_list(org)).unwrap_or_default();
+    // Membership is checked as the app, or with the token the user left at their last login
+    let app = match load_github_app(args) {
+        Ok(app) if !orgs.is_empty() && (app.is_some() || args.contains_key("store_token")) => app,
+        Ok(_) => {
+            logging::log_to_file("deprovision requires org, and app_id and app_private_key or store_token");
+            return PamReturnCode::SERVICE_ERR;
+        }
+        Err(code) => return code,
+    };
+
+    let username = match get_username(pam) {
+        Ok(username) => username,
+        Err(code) => return code,
+    };
+    let client = match build_client(args) {
+        Ok(client) => client,
+        Err(code) => return code,
+    };
+
+    // Only users bound to a GitHub account are managed by this module
+    let identity = match state::lookup_identity(&username) {
+        Ok(Some(identity)) => identity,
+        Ok(None) => return PamReturnCode::SUCCESS,
+        Err(err) => {
+            logging::log_to_file(&format!("Failed to look up identity: {}", err));
+            return PamReturnCode::SERVICE_ERR;
+        }
+    };
+
+    #[cfg(feature = "cache")]
+    let mut user_token = None;
+    let membership = match &app {
+        Some(app) => app
+            .installation_token(&client, orgs[0])
+            .and_then(|token| client.login_for_id(identity.github_id, &token))
+            .and_then(|login| github::GithubUser::from_app(&client, app, &login, &orgs)),
+        // Expired tokens are refreshed with the OAuth app that issued them
+        #[cfg(feature = "cache")]
+        None => match tokens::access_token(&client, github::OAuthApp::from_args(args).ok().flatten().as_ref(), &username) {
+            Ok(Some(token)) => {
+                let membership = client
+                    .login_for_id(identity.github_id, &token)
+                    .and_then(|login| github::GithubUser::from_pat(&client, &token, &login, &orgs));
+                user_token = Some(token);
+                membership
+            }
+            Ok(None) => return PamReturnCode::SUCCESS,
+            Err(err) => {
+                logging::log_to_file(&format!("Failed to load stored token: {}", err));
+                return PamReturnCode::SUCCESS;
+            }
+        },
+        #[cfg(not(feature = "cache"))]
+        None => return PamReturnCode::SUCCESS,
+    };
+    match membership {
+        Ok(_) => PamReturnCode::SUCCESS,
+        // The user revoked the token, which says nothing about their membership
+        #[cfg(feature = "cache")]
+        Err(github::GithubError::Unauthorized(_)) if app.is_none() => {
+            logging::log_to_file(&format!("Stored token of user {} was rejected, forgetting it", username));
+            if let Some(Err(err)) = user_token.map(|token| tokens::remove_rejected(&username, &token)) {
+                logging::log_to_file(&err);
+            }
+            PamReturnCode::SUCCESS
+        }
+        Err(github::GithubError::NotFound(_)) => {
+            logging::log_to_file(&format!(
+                "GitHub user {} is no longer a member, deprovisioning {}",
+                identity.github_login, username
+            ));
+            if let Err(err) = user::deprovision_user(&username, mode, sudo.as_ref()) {
+                logging::log_to_file(&format!("Failed to deprovision user: {}", err));
+            }
+            PamReturnCode::ACCT_EXPIRED
+        }
+        Err(err) => {
+            // Never lock accounts because GitHub is unreachable
+            logging::log_to_file(&format!("Failed to check membership for deprovisioning: {}", err));
+            PamReturnCode::SUCCESS
+        }
+    }
+}
+
+// deprovision needs the `accounts` feature, the option is rejected without it
+#[cfg(not(feature = "accounts"))]
+fn check_deprovision(_pam: &dyn Pam, _args: &HashMap<String, String>) -> PamReturnCode {
+    PamReturnCode::SUCCESS
+}
+
+#[unsafe(no_mangle)]
+#[allow(improper_ctypes_definitions)]
+pub extern "C" fn pam_sm_open_session(
+    pamh: *mut PamHandle,
+    _flags: PamFlag,
+    argc: libc::c_int,
+    argv: *const *const libc::c_char,
+) -> PamReturnCode {
+    let pam = pam::Handle(pamh);
+    let args = match service_args(&pam, parse_args(argc, argv)) {
+        Ok(args) => args,
+        Err(code) => return code,
+    };
+    traced("pam_sm_open_session", &pam, &args, || open_session(&pam, &args))
+}
+
+#[cfg_attr(feature = "trace", tracing::instrument(skip_all))]
+fn open_session(pam: &dyn Pam, args: &HashMap<String, String>) -> PamReturnCode {
+    let ephemeral = match parse_ephemeral(args) {
+        Ok(ephemeral) => ephemeral.is_some(),
+        Err(code) => return code,
+    };
+    let sync_keys_on_session = args.contains_key("sync_keys_on_session");
+    let sudo_grace = match parse_sudo_grace(args) {
+        Ok(grace) => grace,
+        Err(code) => return code,
+    };
+    if !ephemeral && !sync_keys_on_session && sudo_grace.is_none() {
+        return PamReturnCode::SUCCESS;
+    }
+    let username = match get_username(pam) {
+        Ok(username) => username,
+        Err(code) => return code,
+    };
+    if let Some(grace) = sudo_grace {
+        record_login_session(pam, &username, grace);
+    }
+    if ephemeral && let Err(err) = state::open_ephemeral_session(&username) {
+        logging::log_to_file(&format!("Failed to count session of {}: {}", username, err));
+    }
+    #[cfg(feature = "keys")]
+    if sync_keys_on_session {
+        sync_session_keys(pam, args, &username);
+    }
+    PamReturnCode::SUCCESS
+}
+
+// Lets sudo in this session only confirm while the GitHub authentication before it is within
+// sudo_grace. A failure never keeps the session from opening
+fn record_login_session(pam: &dyn Pam, username: &str, grace: i64) {
+    let Some(session) = login_session(pam) else {
+        logging::debug("No tty or audit session, sudo_grace does not apply to this session");
+        return;
+    };
+    match state::record_login_session(username, &session, grace) {
+        Ok(true) => logging::log_to_file(&format!("Recorded login session {} of user {} for sudo_grace", session, username)),
+        Ok(false) => {}
+        Err(err) => logging::log_to_file(&format!("Failed to record login session of {}: {}", username, err)),
+    }
+}
+
+// The GitHub login of a user in allow_users, from user_map or else their username
+#[cfg(feature = "keys")]
+fn allowed_user_login(args: &HashMap<String, String>, username: &str) -> Option<String> {
+    let user_map = match args.get("user_map") {
+        Some(path) => usermap::UserMap::load(path).map_err(|err| logging::log_to_file(&err)).ok()?,
+        None => usermap::UserMap::default(),
+    };
+    let github_logins = user_map.github_logins(username);
+    is_allowed_user(args, username, &github_logins).then(|| github_logins[0].clone())
+}
+
+// Imports keys without prompting, a failure never keeps the session from opening
+#[cfg(feature = "keys")]
+#[cfg_attr(feature = "trace", tracing::instrument(skip_all, fields(user = username)))]
+fn sync_session_keys(pam: &dyn Pam, args: &HashMap<String, String>, username: &str) {
+    // Only the public list is available here, which can't be checked against the restrictions
+    let policy = match github::KeyPolicy::from_args(args) {
+        Ok(policy) if !policy.is_restricted() => policy,
+        Ok(_) => {
+            logging::log_to_file("key_max_age_days and key_title_pattern need the user's token, not syncing keys on session");
+            return;
+        }
+        Err(err) => {
+            logging::log_to_file(&err);
+            return;
+        }
+    };
+    // sshd may authenticate in another process, so fall back to the bound identity. allow_users
+    // authenticated by another module have neither and go by their mapped login
+    let github_login = match pam.data(LOGIN_DATA) {
+        Some(login) => login,
+        None => match state::lookup_identity(username) {
+            Ok(Some(identity)) => identity.github_login,
+            Ok(None) if let Some(login) = allowed_user_login(args, username) => login,
+            Ok(None) => {
+                logging::log_to_file(&format!("No GitHub login known for {}, not syncing keys", username));
+                return;
+            }
+            Err(err) => {
+                logging::log_to_file(&format!("Failed to look up identity: {}", err));
+                return;
+            }
+        },
+    };
+    let Ok(client) = build_client(args) else {
+        return;
+    };
+    match keys::KeySync::new(username).with_policy(policy).from_login(&client, &github_login) {
+        Ok(keys::KeyChange::Updated) => logging::log_to_file(&format!("Imported keys for user {}", username)),
+        Ok(keys::KeyChange::Unchanged) => logging::log_to_file(&format!("Keys for user {} are unchanged", username)),
+        Err(err) => logging::log_to_file(&format!("Failed to import keys: {}", err)),
+    }
+}
+
+#[unsafe(no_mangle)]
+#[allow(improper_ctypes_definitions)]
+pub extern "C" fn pam_sm_close_session(
+    pamh: *mut PamHandle,
+    _flags: PamFlag,
+    argc: libc::c_int,
+    argv: *const *const libc::c_char,
+) -> PamReturnCode {
+    let pam = pam::Handle(pamh);
+    let args = match service_args(&pam, parse_args(argc, argv)) {
+        Ok(args) => args,
+        Err(code) => return code,
+    };
+    traced("pam_sm_close_session", &pam, &args, || close_session(&pam, &args))
+}
+
+#[cfg(feature = "accounts")]
+#[cfg_attr(feature = "trace", tracing::instrument(skip_all))]
+fn close_session(pam: &dyn Pam, args: &HashMap<String, String>) -> PamReturnCode {
+    let mode = match parse_ephemeral(args) {
+        Ok(Some(mode)) => mode,
+        Ok(None) => return PamReturnCode::SUCCESS,
+        Err(code) => return code,
+    };
+    let username = match get_username(pam) {
+        Ok(username) => username,
+        Err(code) => return code,
+    };
+    match state::close_ephemeral_session(&username) {
+        Ok(Some(0)) => {
+            logging::log_to_file(&format!("Last session of ephemeral user {} closed", username));
+            if let Err(err) = user::remove_user(&username, mode) {
+                logging::log_to_file(&format!("Failed to remove ephemeral user: {}", err));
+            }
+        }
+        Ok(_) => {}
+        Err(err) => {
+            logging::log_to_file(&format!("Failed to count session of {}: {}", username, err));
+        }
+    }
+    PamReturnCode::SUCCESS
+}
+
+// Only ephemeral accounts need anything done when the session closes
+#[cfg(not(feature = "accounts"))]
+fn close_session(_pam: &dyn Pam, _args: &HashMap<String, String>) -> PamReturnCode {
+    PamReturnCode::SUCCESS
+}
+
+#[unsafe(no_mangle)]
+#[allow(improper_ctypes_definitions)]
+pub extern "C" fn pam_sm_chauthtok(
+    _pamh: *mut PamHandle,
+    _flags: PamFlag,
+    _argc: libc::c_int,
+    _argv: *const *const libc::c_char,
+) -> PamReturnCode {
+    PamReturnCode::SUCCESS
+}
@@ -0,0 +1,196 @@
+// Passwd entries of organization members, kept up to date by ghauthd and served to the
+// libnss_github module, so members resolve (e.g. for `ls -l` and sshd) before their first login
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::github::{GithubClient, GithubError};
+use crate::usermap::UserMap;
+use crate::{logging, state, user};
+
+pub const DEFAULT_SOCKET: &str = "/run/ssh_github_auth/nss.sock";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PasswdEntry {
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub gecos: String,
+    pub dir: String,
+    pub shell: String,
+}
+
+impl PasswdEntry {
+    // A line of /etc/passwd, which is also what the socket answers with
+    pub fn to_line(&self) -> String {
+        format!("{}:x:{}:{}:{}:{}:{}", self.name, self.uid, self.gid, self.gecos, self.dir, self.shell)
+    }
+}
+
+// Which members are listed and how their entries look
+#[derive(Debug)]
+pub struct Directory {
+    orgs: Vec<String>,
+    user_map: UserMap,
+    uid_offset: u32,
+    gid: u32,
+    // `{user}` is replaced with the local username
+    home: String,
+    shell: String,
+    pub refresh: Duration,
+}
+
+impl Directory {
+    // From ghauthd's `nss_*` options, `org`, `uid_offset` and `user_map`. None without `nss_socket`
+    pub fn from_args(args: &HashMap<String, String>) -> Result<Option<Self>, String> {
+        if !args.contains_key("nss_socket") {
+            return Ok(None);
+        }
+        let orgs: Vec<String> = args
+            .get("org")
+            .map(|org| org.split(',').map(|o| o.trim().to_string()).filter(|o| !o.is_empty()).collect())
+            .unwrap_or_default();
+        if orgs.is_empty() {
+            return Err("nss_socket requires org".to_string());
+        }
+        // Without a fixed offset, UIDs would not match the ones of the accounts created at login
+        let uid_offset = user::parse_uid_offset(args.get("uid_offset").map(|o| o.as_str()))?
+            .ok_or("nss_socket requires uid_offset")?;
+        let gid = match args.get("nss_gid") {
+            Some(gid) => gid.parse::<u32>().map_err(|_| format!("Invalid nss_gid: {}", gid))?,
+            None => 100,
+        };
+        let refresh = match args.get("nss_refresh") {
+            Some(secs) => match secs.parse::<u64>() {
+                Ok(secs) if secs > 0 => Duration::from_secs(secs),
+                _ => return Err(format!("Invalid nss_refresh: {}", secs)),
+            },
+            None => Duration::from_secs(300),
+        };
+        let user_map = match args.get("user_map") {
+            Some(path) => UserMap::load(path)?,
+            None => UserMap::default(),
+        };
+        Ok(Some(Directory {
+            orgs,
+            user_map,
+            uid_offset,
+            gid,
+            home: args.get("nss_home").cloned().unwrap_or_else(|| "/home/{user}".to_string()),
+            shell: args.get("nss_shell").cloned().unwrap_or_else(|| "/bin/bash".to_string()),
+            refresh,
+        }))
+    }
+
+    // Lists the members of every organization with the token `token_for` returns for it
+    pub fn entries<F>(&self, client: &GithubClient, token_for: F) -> Result<Vec<PasswdEntry>, GithubError>
+    where
+        F: Fn(&str) -> Result<String, GithubError>,
+    {
+        let mut entries: Vec<PasswdEntry> = Vec::new();
+        for org in &self.orgs {
+            for member in client.org_members(org, &token_for(org)?)? {
+                let name = self.user_map.local_user(&member.login);
+                if entries.iter().any(|entry| entry.name == name) {
+                    continue;
+                }
+                let uid = match user::uid_for(self.uid_offset, member.id) {
+                    Ok(uid) => uid,
+                    Err(err) => {
+                        logging::log_to_file(&err);
+                        continue;
+                    }
+                };
+                entries.push(PasswdEntry {
+                    dir: self.home.replace("{user}", &name),
+                    name,
+                    uid,
+                    gid: self.gid,
+                    gecos: member.login,
+                    shell: self.shell.clone(),
+                });
+            }
+        }
+        Ok(entries)
+    }
+}
+
+// Entries of the last refresh, also kept on disk so a restart answers before GitHub does
+pub type Entries = Arc<RwLock<Vec<PasswdEntry>>>;
+
+pub fn load_entries() -> Entries {
+    let entries: Vec<PasswdEntry> = state::load("nss.json").unwrap_or_else(|err| {
+        logging::log_to_file(&err);
+        Vec::new()
+    });
+    Arc::new(RwLock::new(entries))
+}
+
+// Refreshes the entries every `directory.refresh`, keeping the last ones when GitHub fails
+pub fn keep_fresh<F>(directory: &Directory, client: &GithubClient, entries: &Entries, token_for: F)
+where
+    F: Fn(&str) -> Result<String, GithubError>,
+{
+    loop {
+        match directory.entries(client, &token_for) {
+            Ok(fresh) => {
+                logging::debug(&format!("Resolved {} organization members for NSS", fresh.len()));
+                if let Err(err) = state::save("nss.json", &fresh) {
+                    logging::log_to_file(&err);
+                }
+                if let Ok(mut entries) = entries.write() {
+                    *entries = fresh;
+                }
+            }
+            Err(err) => logging::log_to_file(&format!("Failed to list organization members for NSS: {}", err)),
+        }
+        thread::sleep(directory.refresh);
+    }
+}
+
+// Answers `name <name>`, `uid <uid>` and `list` with matching passwd lines and an empty line
+pub fn serve(listener: UnixListener, entries: Entries) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let entries = entries.clone();
+                thread::spawn(move || handle_connection(stream, &entries));
+            }
+            Err(err) => logging::log_to_file(&format!("Failed to accept NSS connection: {}", err)),
+        }
+    }
+}
+
+fn handle_connection(stream: UnixStream, entries: &Entries) {
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(err) => {
+            logging::log_to_file(&format!("Failed to clone NSS connection: {}", err));
+            return;
+        }
+    };
+
+    for line in BufReader::new(stream).lines() {
+        let Ok(line) = line else {
+            return;
+        };
+        let Ok(entries) = entries.read() else {
+            return;
+        };
+        let matches = |entry: &&PasswdEntry| match line.split_once(' ') {
+            Some(("name", name)) => entry.name == name,
+            Some(("uid", uid)) => uid.parse() == Ok(entry.uid),
+            _ => line == "list",
+        };
+        let mut reply: String = entries.iter().filter(matches).map(|entry| entry.to_line() + "\n").collect();
+        reply.push('\n');
+        if writer.write_all(reply.as_bytes()).is_err() {
+            return;
+        }
+    }
+}
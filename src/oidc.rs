@@ -0,0 +1,143 @@
+// Logins of GitHub Actions jobs with the OIDC ID token of their run instead of a long-lived key.
+// The token is checked against the issuer's keys and its audience, then its repository and ref
+// against the configured patterns, and the local user the job logs in as against `oidc_user`
+use std::collections::HashMap;
+
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+
+use crate::github::GithubClient;
+
+// The claims of an Actions ID token that are checked or logged
+#[derive(Debug, Clone, Deserialize)]
+pub struct ActionsClaims {
+    pub repository: String,
+    pub repository_owner: String,
+    #[serde(rename = "ref")]
+    pub git_ref: String,
+    // Who triggered the run
+    pub actor: String,
+    #[serde(default)]
+    pub workflow: Option<String>,
+    #[serde(default)]
+    pub run_id: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OidcPolicy {
+    // None for the issuer of the configured GitHub instance
+    issuer: Option<String>,
+    audience: String,
+    repositories: Vec<String>,
+    // Any ref when empty
+    refs: Vec<String>,
+    // Local users jobs may log in as, each with the repositories allowed to use it, any of
+    // `repositories` when empty
+    users: Vec<(String, Vec<String>)>,
+}
+
+impl OidcPolicy {
+    // From `oidc_audience`, `oidc_repository`, `oidc_ref`, `oidc_user` and `oidc_issuer`
+    pub fn from_args(args: &HashMap<String, String>) -> Result<Self, String> {
+        let list = |key: &str| -> Vec<String> {
+            args.get(key)
+                .map(|value| value.split(',').map(|p| p.trim().to_string()).filter(|p| !p.is_empty()).collect())
+                .unwrap_or_default()
+        };
+        let audience = args
+            .get("oidc_audience")
+            .filter(|audience| !audience.is_empty())
+            .ok_or("auth_mode=actions_oidc requires oidc_audience")?;
+        // Accepting every repository would let any workflow on GitHub log in
+        let repositories = list("oidc_repository");
+        if repositories.is_empty() {
+            return Err("auth_mode=actions_oidc requires oidc_repository".to_string());
+        }
+        // Nor may a token of any of them log in as any local user
+        let mut users: Vec<(String, Vec<String>)> = Vec::new();
+        for entry in list("oidc_user") {
+            let (user, repository) = match entry.split_once(':') {
+                Some((user, repository)) => (user.to_string(), Some(repository.to_string())),
+                None => (entry.clone(), None),
+            };
+            if user.is_empty() || repository.as_deref() == Some("") {
+                return Err(format!("Invalid oidc_user entry: {}", entry));
+            }
+            match users.iter_mut().find(|(known, _)| *known == user) {
+                Some((_, repositories)) => repositories.extend(repository),
+                None => users.push((user, repository.into_iter().collect())),
+            }
+        }
+        if users.is_empty() {
+            return Err("auth_mode=actions_oidc requires oidc_user".to_string());
+        }
+        Ok(OidcPolicy {
+            issuer: args.get("oidc_issuer").map(|issuer| issuer.trim_end_matches('/').to_string()),
+            audience: audience.clone(),
+            repositories,
+            refs: list("oidc_ref"),
+            users,
+        })
+    }
+
+    pub fn issuer(&self, client: &GithubClient) -> String {
+        self.issuer.clone().unwrap_or_else(|| client.actions_issuer())
+    }
+
+    // Checks the signature with the issuer's keys, then the claims and whether they may log in
+    // as `local_user`
+    pub fn verify(&self, token: &str, issuer: &str, keys: &JwkSet, local_user: &str) -> Result<ActionsClaims, String> {
+        let header = decode_header(token).map_err(|err| format!("Invalid ID token: {}", err))?;
+        let kid = header.kid.ok_or("ID token has no key ID")?;
+        let jwk = keys
+            .find(&kid)
+            .ok_or_else(|| format!("ID token is signed with unknown key {}", kid))?;
+        let key = DecodingKey::from_jwk(jwk).map_err(|err| format!("Invalid key {} of {}: {}", kid, issuer, err))?;
+        let mut validation = Validation::new(Algorithm::RS256);
+        validation.set_audience(&[&self.audience]);
+        validation.set_issuer(&[issuer]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud"]);
+        let claims = decode::<ActionsClaims>(token, &key, &validation)
+            .map_err(|err| format!("ID token rejected: {}", err))?
+            .claims;
+
+        // Repository names are case-insensitive on GitHub, refs are not
+        let repository = claims.repository.to_ascii_lowercase();
+        if !self.repositories.iter().any(|p| matches_pattern(&p.to_ascii_lowercase(), &repository)) {
+            return Err(format!("Repository {} is not allowed by oidc_repository", claims.repository));
+        }
+        if !self.refs.is_empty() && !self.refs.iter().any(|p| matches_pattern(p, &claims.git_ref)) {
+            return Err(format!("Ref {} of {} is not allowed by oidc_ref", claims.git_ref, claims.repository));
+        }
+        let Some((_, repositories)) = self.users.iter().find(|(user, _)| user == local_user) else {
+            return Err(format!("User {} is not allowed by oidc_user", local_user));
+        };
+        if !repositories.is_empty()
+            && !repositories.iter().any(|p| matches_pattern(&p.to_ascii_lowercase(), &repository))
+        {
+            return Err(format!("Repository {} may not log in as {}", claims.repository, local_user));
+        }
+        Ok(claims)
+    }
+}
+
+// `*` matches any run of characters, e.g. `acme/*` or `refs/tags/v*`
+pub fn matches_pattern(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = value.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
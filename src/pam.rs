@@ -0,0 +1,84 @@
+use std::ffi::{CStr, CString};
+use std::ptr;
+
+use pam_sys::{wrapped::{get_item, get_user, getenv}, PamHandle, PamItemType, PamMessageStyle, PamReturnCode};
+
+use crate::conversation;
+
+// What the module needs from its PAM handle. The flows only go through this trait,
+// so tests can drive them with a fake handle and a scripted conversation
+pub trait Pam {
+    fn user(&self) -> Result<String, PamReturnCode>;
+    fn item(&self, item_type: PamItemType) -> Option<String>;
+    fn env(&self, name: &str) -> Option<String>;
+    fn has_conversation(&self) -> bool;
+    fn converse(&self, messages: &[(PamMessageStyle, &str)]) -> Result<Vec<Option<String>>, PamReturnCode>;
+    // Data kept for the other stacks of the same PAM transaction
+    fn set_data(&self, name: &CStr, value: &str) -> Result<(), PamReturnCode>;
+    fn data(&self, name: &CStr) -> Option<String>;
+}
+
+// The handle libpam passes to the module
+pub struct Handle(pub *mut PamHandle);
+
+extern "C" fn free_data(_pamh: *mut PamHandle, data: *mut libc::c_void, _error_status: libc::c_int) {
+    drop(unsafe { Box::from_raw(data as *mut CString) });
+}
+
+impl Pam for Handle {
+    fn user(&self) -> Result<String, PamReturnCode> {
+        let mut user = ptr::null();
+        match unsafe { get_user(&*self.0, &mut user, ptr::null()) } {
+            PamReturnCode::SUCCESS if !user.is_null() => {
+                let username_cstr = unsafe { CStr::from_ptr(user) };
+                Ok(username_cstr.to_string_lossy().to_ascii_lowercase())
+            }
+            PamReturnCode::SUCCESS => Err(PamReturnCode::USER_UNKNOWN),
+            code => Err(code),
+        }
+    }
+
+    fn item(&self, item_type: PamItemType) -> Option<String> {
+        let mut item: *const libc::c_void = ptr::null();
+        match unsafe { get_item(&*self.0, item_type, &mut item) } {
+            PamReturnCode::SUCCESS if !item.is_null() => {
+                let value = unsafe { CStr::from_ptr(item as *const libc::c_char) };
+                Some(value.to_string_lossy().into_owned())
+            }
+            _ => None,
+        }
+    }
+
+    fn env(&self, name: &str) -> Option<String> {
+        unsafe { getenv(&mut *self.0, name) }.map(|value| value.to_string())
+    }
+
+    fn has_conversation(&self) -> bool {
+        conversation::available(self.0)
+    }
+
+    fn converse(&self, messages: &[(PamMessageStyle, &str)]) -> Result<Vec<Option<String>>, PamReturnCode> {
+        conversation::converse(self.0, messages)
+    }
+
+    fn set_data(&self, name: &CStr, value: &str) -> Result<(), PamReturnCode> {
+        let value = CString::new(value).map_err(|_| PamReturnCode::BUF_ERR)?;
+        let data = Box::into_raw(Box::new(value)) as *mut libc::c_void;
+        let code = unsafe { pam_sys::raw::pam_set_data(self.0, name.as_ptr(), data, Some(free_data)) };
+        if code != PamReturnCode::SUCCESS as libc::c_int {
+            drop(unsafe { Box::from_raw(data as *mut CString) });
+            return Err(PamReturnCode::from(code));
+        }
+        Ok(())
+    }
+
+    fn data(&self, name: &CStr) -> Option<String> {
+        let mut data: *const libc::c_void = ptr::null();
+        let code = unsafe { pam_sys::raw::pam_get_data(self.0, name.as_ptr(), &mut data) };
+        if code != PamReturnCode::SUCCESS as libc::c_int || data.is_null() {
+            return None;
+        }
+        let value = unsafe { &*(data as *const CString) };
+        Some(value.to_string_lossy().into_owned())
+    }
+}
@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use crate::github::{GithubApp, GithubClient, GithubError, GithubRole, GithubUser, RepoFile, TeamPolicy};
+use crate::{logging, state};
+
+// Who may log in to the hosts of a group
+#[derive(Debug, Default, Deserialize)]
+pub struct HostGroup {
+    #[serde(default)]
+    pub teams: Vec<String>,
+    #[serde(default)]
+    pub users: Vec<String>,
+    // Organization role required on top of being listed, like `require_role`
+    pub role: Option<String>,
+}
+
+// Host groups mapped to the teams and users allowed on them, e.g.
+//
+//     bastion:
+//       teams: [infra, sre]
+//       users: [octocat]
+//     prod-db:
+//       teams: [dba]
+//       role: admin
+#[derive(Debug, Default)]
+pub struct AccessPolicy {
+    groups: HashMap<String, HostGroup>,
+}
+
+impl AccessPolicy {
+    pub fn parse(content: &str) -> Result<Self, String> {
+        let groups = serde_yaml::from_str::<Option<HashMap<String, HostGroup>>>(content)
+            .map_err(|e| format!("Invalid access policy: {}", e))?
+            .unwrap_or_default();
+        for (name, group) in &groups {
+            if let Some(role) = &group.role
+                && GithubRole::from_arg(role).is_none()
+            {
+                return Err(format!("Invalid role {} for host group {} in the access policy", role, name));
+            }
+        }
+        Ok(AccessPolicy { groups })
+    }
+
+    // Hosts of a group missing from the policy allow no one
+    pub fn group(&self, name: &str) -> Option<&HostGroup> {
+        self.groups.get(name)
+    }
+}
+
+// The central policy of this host: the policy file and the group of this host, from
+// `policy_repo`, `policy_path` and `host_group`
+#[derive(Debug)]
+pub struct Policy {
+    pub file: RepoFile,
+    pub host_group: String,
+}
+
+impl Policy {
+    pub fn from_args(args: &HashMap<String, String>) -> Result<Option<Self>, String> {
+        let Some(repo) = args.get("policy_repo") else {
+            return Ok(None);
+        };
+        let path = args.get("policy_path").map(|p| p.as_str()).unwrap_or("policy.yml");
+        let file = RepoFile::parse(&format!("{}:{}", repo, path))?;
+        let host_group = match args.get("host_group").map(|g| g.trim()) {
+            Some(group) if !group.is_empty() => group.to_string(),
+            _ => return Err("policy_repo requires host_group".to_string()),
+        };
+        Ok(Some(Policy { file, host_group }))
+    }
+
+    // Downloads the policy only when it changed, the last copy is used while GitHub is unreachable
+    pub fn load(&self, client: &GithubClient, token: Option<&str>) -> Result<AccessPolicy, GithubError> {
+        let key = format!("{}:{}", self.file.repo, self.file.path);
+        let cached = state::cached_file(&key).unwrap_or_else(|err| {
+            logging::log_to_file(&format!("Failed to read cached access policy: {}", err));
+            None
+        });
+        let etag = cached.as_ref().and_then(|c| c.etag.as_deref());
+        let content = match client.file_contents_since(&self.file, token, etag) {
+            Ok(Some(fetched)) => {
+                let file = state::CachedFile { content: fetched.content, etag: fetched.etag };
+                // Never cache a policy that can't be used
+                AccessPolicy::parse(&file.content).map_err(GithubError::Other)?;
+                if let Err(err) = state::cache_file(&key, &file) {
+                    logging::log_to_file(&format!("Failed to cache access policy: {}", err));
+                }
+                file.content
+            }
+            Ok(None) => cached.map(|c| c.content).unwrap_or_default(),
+            Err(err @ (GithubError::Request { .. } | GithubError::Helper { .. })) => match cached {
+                Some(cached) => {
+                    logging::log_to_file(&format!("Using cached access policy, GitHub is unreachable: {}", err));
+                    cached.content
+                }
+                None => return Err(err),
+            },
+            Err(err) => return Err(err),
+        };
+        AccessPolicy::parse(&content).map_err(GithubError::Other)
+    }
+
+    // Whether the policy lets the user on this host group, by login or team. The policy is read
+    // as the app when there is one, otherwise with the user's token
+    pub fn allows(
+        &self,
+        client: &GithubClient,
+        app: Option<&GithubApp>,
+        user_token: Option<&str>,
+        github_user: &GithubUser,
+    ) -> Result<bool, GithubError> {
+        let app_token = match app {
+            Some(app) => Some(app.repo_installation_token(client, &self.file.repo)?),
+            None => None,
+        };
+        let policy = self.load(client, app_token.as_deref().or(user_token))?;
+        let Some(group) = policy.group(&self.host_group) else {
+            logging::log_to_file(&format!("Host group {} is not in the access policy", self.host_group));
+            return Ok(false);
+        };
+        if let Some(role) = group.role.as_deref().and_then(GithubRole::from_arg)
+            && !github_user.role.satisfies(role)
+        {
+            logging::log_to_file(&format!(
+                "User role {:?} does not satisfy role {:?} of host group {}",
+                github_user.role, role, self.host_group
+            ));
+            return Ok(false);
+        }
+        if group.users.iter().any(|user| user.eq_ignore_ascii_case(&github_user.username)) {
+            return Ok(true);
+        }
+        let teams: Vec<&str> = group.teams.iter().map(|t| t.as_str()).collect();
+        if teams.is_empty() {
+            return Ok(false);
+        }
+        github_user.check_teams(&teams, TeamPolicy::Any)
+    }
+}
@@ -0,0 +1,444 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use serde::{Deserialize, Serialize};
+
+use crate::github::OrgTeam;
+
+pub const STATE_DIR: &str = "/var/lib/ssh_github_auth";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Identity {
+    pub github_id: u64,
+    pub github_login: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum IdentityCheck {
+    Recorded,
+    Matched,
+    Mismatch(u64),
+}
+
+pub(crate) fn load<T: for<'de> Deserialize<'de> + Default>(name: &str) -> Result<T, String> {
+    let path = Path::new(STATE_DIR).join(name);
+    if !path.exists() {
+        return Ok(T::default());
+    }
+    let content = fs::read_to_string(&path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    serde_json::from_str(&content)
+        .map_err(|e| format!("Failed to parse {}: {}", path.display(), e))
+}
+
+static TMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) fn save<T: Serialize>(name: &str, value: &T) -> Result<(), String> {
+    fs::create_dir_all(STATE_DIR)
+        .map_err(|e| format!("Failed to create {}: {}", STATE_DIR, e))?;
+    let path = Path::new(STATE_DIR).join(name);
+    let content = serde_json::to_string_pretty(value)
+        .map_err(|e| format!("Failed to serialize {}: {}", name, e))?;
+
+    // Write to a temporary file first so a crash never leaves a truncated file behind. Each
+    // writer has its own, so one can't rename a file another is still writing
+    let tmp_path = path.with_extension(format!(
+        "{}.{}.tmp",
+        std::process::id(),
+        TMP_FILES.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::write(&tmp_path, content)
+        .map_err(|e| format!("Failed to write {}: {}", tmp_path.display(), e))?;
+    fs::rename(&tmp_path, &path)
+        .map_err(|e| format!("Failed to replace {}: {}", path.display(), e))
+}
+
+// Everything known about a local user logging in with GitHub, in users.json. The PAM module,
+// ghauthd and ghauth all read and update the same records
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UserRecord {
+    // GitHub account that last logged in as the user
+    pub github_id: Option<u64>,
+    pub github_login: Option<String>,
+    // With bind_github_id only this account may log in as the user
+    pub bound: bool,
+    // Unix timestamp of the last successful authentication
+    pub last_login: Option<i64>,
+    pub token: Option<TokenInfo>,
+    pub key_etag: Option<String>,
+    // Fingerprints of the keys imported into the managed block
+    pub keys: Vec<String>,
+    // Login sessions opened after a GitHub authentication, by tty and audit session ID, with the
+    // time of that authentication. sudo_grace only applies within them
+    pub sessions: BTreeMap<String, i64>,
+    // Groups role_group_map added the user to, the only ones it removes them from again
+    pub role_groups: Vec<String>,
+}
+
+impl UserRecord {
+    fn identity(&self) -> Option<Identity> {
+        match (self.bound, self.github_id, &self.github_login) {
+            (true, Some(github_id), Some(github_login)) => Some(Identity {
+                github_id,
+                github_login: github_login.clone(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+// The stored token itself stays encrypted in the token store
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TokenInfo {
+    pub stored: i64,
+    pub expires_at: Option<i64>,
+}
+
+type UserRecords = BTreeMap<String, UserRecord>;
+
+// Older versions kept bindings, last logins and key ETags in separate files, which are read
+// until users.json is first written
+fn migrated_users() -> Result<UserRecords, String> {
+    let mut users = UserRecords::new();
+    let identities: HashMap<String, Identity> = load("identities.json")?;
+    for (local_user, identity) in identities {
+        let record = users.entry(local_user).or_default();
+        record.github_id = Some(identity.github_id);
+        record.github_login = Some(identity.github_login);
+        record.bound = true;
+    }
+    let successes: HashMap<String, i64> = load("successes.json")?;
+    for (local_user, at) in successes {
+        users.entry(local_user).or_default().last_login = Some(at);
+    }
+    let etags: HashMap<String, String> = load("key_etags.json")?;
+    for (local_user, etag) in etags {
+        users.entry(local_user).or_default().key_etag = Some(etag);
+    }
+    Ok(users)
+}
+
+pub fn users() -> Result<UserRecords, String> {
+    if !Path::new(STATE_DIR).join("users.json").exists() {
+        return migrated_users();
+    }
+    load("users.json")
+}
+
+pub fn user_record(local_user: &str) -> Result<Option<UserRecord>, String> {
+    Ok(users()?.remove(local_user))
+}
+
+// Changes the user's record while holding a lock, so concurrent logins don't lose each other's
+// changes. Returns what `change` returned
+fn update_user<T>(local_user: &str, change: impl FnOnce(&mut UserRecord) -> T) -> Result<T, String> {
+    let _lock = lock("users.lock")?;
+    let mut users = users()?;
+    let record = users.entry(local_user.to_string()).or_default();
+    let result = change(record);
+    if *record == UserRecord::default() {
+        users.remove(local_user);
+    }
+    save("users.json", &users)?;
+    Ok(result)
+}
+
+// Exclusive lock on a file in the state directory, released when dropped
+pub(crate) fn lock(name: &str) -> Result<File, String> {
+    fs::create_dir_all(STATE_DIR)
+        .map_err(|e| format!("Failed to create {}: {}", STATE_DIR, e))?;
+    let path = Path::new(STATE_DIR).join(name);
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .mode(0o600)
+        .open(&path)
+        .map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(format!("Failed to lock {}: {}", path.display(), std::io::Error::last_os_error()));
+    }
+    Ok(file)
+}
+
+// Users bound to a GitHub account
+pub fn identities() -> Result<HashMap<String, Identity>, String> {
+    Ok(users()?
+        .into_iter()
+        .filter_map(|(local_user, record)| Some((local_user, record.identity()?)))
+        .collect())
+}
+
+pub fn lookup_identity(local_user: &str) -> Result<Option<Identity>, String> {
+    Ok(user_record(local_user)?.and_then(|record| record.identity()))
+}
+
+// Binds a local user to a GitHub account ID on first login, and checks the binding afterwards
+pub fn bind_identity(local_user: &str, github_id: u64, github_login: &str) -> Result<IdentityCheck, String> {
+    update_user(local_user, |record| match record.identity() {
+        Some(identity) if identity.github_id != github_id => IdentityCheck::Mismatch(identity.github_id),
+        Some(_) => {
+            // Keep the recorded login current after a GitHub rename
+            record.github_login = Some(github_login.to_string());
+            IdentityCheck::Matched
+        }
+        None => {
+            record.github_id = Some(github_id);
+            record.github_login = Some(github_login.to_string());
+            record.bound = true;
+            IdentityCheck::Recorded
+        }
+    })
+}
+
+pub fn key_etag(local_user: &str) -> Result<Option<String>, String> {
+    Ok(user_record(local_user)?.and_then(|record| record.key_etag))
+}
+
+// Local users whose keys were imported from GitHub
+pub fn key_etag_users() -> Result<Vec<String>, String> {
+    Ok(users()?
+        .into_iter()
+        .filter(|(_, record)| record.key_etag.is_some())
+        .map(|(local_user, _)| local_user)
+        .collect())
+}
+
+pub fn set_key_etag(local_user: &str, etag: Option<&str>) -> Result<(), String> {
+    update_user(local_user, |record| record.key_etag = etag.map(|etag| etag.to_string()))
+}
+
+pub fn set_imported_keys(local_user: &str, fingerprints: Vec<String>) -> Result<(), String> {
+    update_user(local_user, |record| record.keys = fingerprints)
+}
+
+pub fn role_groups(local_user: &str) -> Result<Vec<String>, String> {
+    Ok(user_record(local_user)?.map(|record| record.role_groups).unwrap_or_default())
+}
+
+pub fn set_role_groups(local_user: &str, groups: Vec<String>) -> Result<(), String> {
+    update_user(local_user, |record| record.role_groups = groups)
+}
+
+pub fn set_token_info(local_user: &str, token: Option<TokenInfo>) -> Result<(), String> {
+    update_user(local_user, |record| record.token = token)
+}
+
+// Unix timestamp of the user's last successful authentication
+pub fn last_success(local_user: &str) -> Result<Option<i64>, String> {
+    Ok(user_record(local_user)?.and_then(|record| record.last_login))
+}
+
+// Also remembers the GitHub account, which unbound users may change between logins
+pub fn record_success(local_user: &str, github_id: u64, github_login: &str) -> Result<(), String> {
+    update_user(local_user, |record| {
+        record.last_login = Some(chrono::Utc::now().timestamp());
+        if !record.bound {
+            record.github_id = Some(github_id);
+            record.github_login = Some(github_login.to_string());
+        }
+    })
+}
+
+// Ties a login session to the user's last authentication if it was within `grace` seconds,
+// returns false if there was none. Sessions past the grace are dropped
+pub fn record_login_session(local_user: &str, session: &str, grace: i64) -> Result<bool, String> {
+    let since = chrono::Utc::now().timestamp() - grace;
+    update_user(local_user, |record| {
+        record.sessions.retain(|_, at| *at >= since);
+        match record.last_login.filter(|at| *at >= since) {
+            Some(at) => {
+                record.sessions.insert(session.to_string(), at);
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+// Unix timestamp of the authentication the login session was opened after
+pub fn session_login(local_user: &str, session: &str) -> Result<Option<i64>, String> {
+    Ok(user_record(local_user)?.and_then(|mut record| record.sessions.remove(session)))
+}
+
+// Number of failed attempts within the last `window` seconds
+pub fn recent_failures(key: &str, window: i64) -> Result<usize, String> {
+    let failures: HashMap<String, Vec<i64>> = load("failures.json")?;
+    let since = chrono::Utc::now().timestamp() - window;
+    Ok(failures
+        .get(key)
+        .map(|times| times.iter().filter(|t| **t > since).count())
+        .unwrap_or(0))
+}
+
+// Updated under the lock of users.json, so concurrent failures are all counted
+pub fn record_failure(key: &str, window: i64) -> Result<(), String> {
+    let _lock = lock("users.lock")?;
+    let mut failures: HashMap<String, Vec<i64>> = load("failures.json")?;
+    let now = chrono::Utc::now().timestamp();
+    let times = failures.entry(key.to_string()).or_default();
+    times.retain(|t| *t > now - window);
+    times.push(now);
+    save("failures.json", &failures)
+}
+
+pub fn clear_failures(key: &str) -> Result<(), String> {
+    let _lock = lock("users.lock")?;
+    let mut failures: HashMap<String, Vec<i64>> = load("failures.json")?;
+    if failures.remove(key).is_some() {
+        save("failures.json", &failures)?;
+    }
+    Ok(())
+}
+
+// Records the time step of an accepted TOTP code, returns false if a code of this step
+// or a later one was already used, so an observed code can't be replayed. Held under the lock
+// of users.json, so two logins can't both use the same code
+pub fn use_totp_step(local_user: &str, step: u64) -> Result<bool, String> {
+    let _lock = lock("users.lock")?;
+    let mut steps: HashMap<String, u64> = load("totp_steps.json")?;
+    if steps.get(local_user).is_some_and(|last| *last >= step) {
+        return Ok(false);
+    }
+    steps.insert(local_user.to_string(), step);
+    save("totp_steps.json", &steps)?;
+    Ok(true)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedPrincipals {
+    principals: Vec<String>,
+    fetched: i64,
+}
+
+// Certificate principals of a user looked up at most `max_age` seconds ago
+pub fn cached_principals(local_user: &str, max_age: i64) -> Result<Option<Vec<String>>, String> {
+    let mut cache: HashMap<String, CachedPrincipals> = load("principals.json")?;
+    let since = chrono::Utc::now().timestamp() - max_age;
+    Ok(cache
+        .remove(local_user)
+        .filter(|cached| cached.fetched > since)
+        .map(|cached| cached.principals))
+}
+
+// Under the lock of users.json, so concurrent lookups don't drop each other's entries
+pub fn cache_principals(local_user: &str, principals: &[String]) -> Result<(), String> {
+    let _lock = lock("users.lock")?;
+    let mut cache: HashMap<String, CachedPrincipals> = load("principals.json")?;
+    cache.insert(
+        local_user.to_string(),
+        CachedPrincipals {
+            principals: principals.to_vec(),
+            fetched: chrono::Utc::now().timestamp(),
+        },
+    );
+    save("principals.json", &cache)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedTeams {
+    teams: Vec<OrgTeam>,
+    fetched: i64,
+}
+
+// Teams of an organization listed at most `max_age` seconds ago, to resolve team names
+pub fn cached_teams(org: &str, max_age: i64) -> Result<Option<Vec<OrgTeam>>, String> {
+    let mut cache: HashMap<String, CachedTeams> = load("teams.json")?;
+    let since = chrono::Utc::now().timestamp() - max_age;
+    Ok(cache
+        .remove(&org.to_ascii_lowercase())
+        .filter(|cached| cached.fetched > since)
+        .map(|cached| cached.teams))
+}
+
+// Under the lock of users.json, like the other caches
+pub fn cache_teams(org: &str, teams: &[OrgTeam]) -> Result<(), String> {
+    let _lock = lock("users.lock")?;
+    let mut cache: HashMap<String, CachedTeams> = load("teams.json")?;
+    cache.insert(
+        org.to_ascii_lowercase(),
+        CachedTeams {
+            teams: teams.to_vec(),
+            fetched: chrono::Utc::now().timestamp(),
+        },
+    );
+    save("teams.json", &cache)
+}
+
+// Repository files kept to check for changes with their ETag, and to use while GitHub is unreachable
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedFile {
+    pub content: String,
+    pub etag: Option<String>,
+}
+
+pub fn cached_file(key: &str) -> Result<Option<CachedFile>, String> {
+    let mut files: HashMap<String, CachedFile> = load("files.json")?;
+    Ok(files.remove(key))
+}
+
+// Under the lock of users.json, like the other caches
+pub fn cache_file(key: &str, file: &CachedFile) -> Result<(), String> {
+    let _lock = lock("users.lock")?;
+    let mut files: HashMap<String, CachedFile> = load("files.json")?;
+    files.insert(key.to_string(), file.clone());
+    save("files.json", &files)
+}
+
+// Open sessions of each ephemeral account, the account is removed when the last one closes.
+// Counted under the lock of users.json, as sessions open and close at the same time
+pub fn mark_ephemeral(local_user: &str) -> Result<(), String> {
+    let _lock = lock("users.lock")?;
+    let mut sessions: HashMap<String, usize> = load("ephemeral.json")?;
+    sessions.entry(local_user.to_string()).or_insert(0);
+    save("ephemeral.json", &sessions)
+}
+
+// Counts a new session of an ephemeral account, returns false for other accounts
+pub fn open_ephemeral_session(local_user: &str) -> Result<bool, String> {
+    let _lock = lock("users.lock")?;
+    let mut sessions: HashMap<String, usize> = load("ephemeral.json")?;
+    match sessions.get_mut(local_user) {
+        Some(count) => *count += 1,
+        None => return Ok(false),
+    }
+    save("ephemeral.json", &sessions)?;
+    Ok(true)
+}
+
+// Sessions still open after this one closes, or None if the account is not ephemeral
+pub fn close_ephemeral_session(local_user: &str) -> Result<Option<usize>, String> {
+    let _lock = lock("users.lock")?;
+    let mut sessions: HashMap<String, usize> = load("ephemeral.json")?;
+    let remaining = match sessions.get_mut(local_user) {
+        Some(count) => {
+            *count = count.saturating_sub(1);
+            *count
+        }
+        None => return Ok(None),
+    };
+    save("ephemeral.json", &sessions)?;
+    Ok(Some(remaining))
+}
+
+// Drops everything remembered about a removed account
+pub fn forget_user(local_user: &str) -> Result<(), String> {
+    // Before taking the lock, which removing the token takes itself
+    #[cfg(feature = "cache")]
+    crate::tokens::remove(local_user)?;
+    let _lock = lock("users.lock")?;
+    let mut sessions: HashMap<String, usize> = load("ephemeral.json")?;
+    if sessions.remove(local_user).is_some() {
+        save("ephemeral.json", &sessions)?;
+    }
+    let mut users = users()?;
+    if users.remove(local_user).is_some() {
+        save("users.json", &users)?;
+    }
+    Ok(())
+}
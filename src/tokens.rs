@@ -0,0 +1,260 @@
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+
+use crate::github::{GithubClient, GithubError, OAuthApp, UserToken};
+use crate::logging;
+use crate::state::{self, STATE_DIR};
+
+// Users' GitHub tokens, kept so later checks can act on their behalf without a new device flow.
+// Each file is encrypted with a key generated on the host and bound to its local user, so a
+// copied file is useless elsewhere and can't be swapped for another user's
+const KEY_FILE: &str = "token.key";
+const TOKEN_DIR: &str = "tokens";
+// Tokens this close to expiring are refreshed before use
+const EXPIRY_MARGIN: i64 = 60;
+
+// Expiry times are Unix timestamps, None for tokens that don't expire
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StoredToken {
+    pub access_token: String,
+    pub expires_at: Option<i64>,
+    pub refresh_token: Option<String>,
+    pub refresh_expires_at: Option<i64>,
+}
+
+impl StoredToken {
+    pub fn issued(token: &UserToken, now: i64) -> Self {
+        StoredToken {
+            access_token: token.token.clone(),
+            expires_at: token.expires_in.map(|s| now + s),
+            refresh_token: token.refresh_token.clone(),
+            refresh_expires_at: token.refresh_token_expires_in.map(|s| now + s),
+        }
+    }
+
+    fn is_expired(&self, now: i64) -> bool {
+        self.expires_at.is_some_and(|at| at - EXPIRY_MARGIN <= now)
+    }
+
+    fn refresh_token(&self, now: i64) -> Option<&str> {
+        match self.refresh_expires_at {
+            Some(at) if at <= now => None,
+            _ => self.refresh_token.as_deref(),
+        }
+    }
+}
+
+fn token_path(local_user: &str) -> Result<PathBuf, String> {
+    if local_user.is_empty() || local_user.contains('/') || local_user.starts_with('.') {
+        return Err(format!("Invalid user name for the token store: {}", local_user));
+    }
+    Ok(Path::new(STATE_DIR).join(TOKEN_DIR).join(local_user))
+}
+
+static TMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+// Writes a new file under a hidden name of its own next to `path`, which no other writer and no
+// user name can have. The caller renames or links it into place
+fn write_tmp(path: &Path, content: &[&[u8]]) -> Result<PathBuf, String> {
+    let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+    let tmp_path = path.with_file_name(format!(
+        ".{}.{}.{}.tmp",
+        name,
+        std::process::id(),
+        TMP_FILES.fetch_add(1, Ordering::Relaxed)
+    ));
+    let written = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&tmp_path)
+        .and_then(|mut file| {
+            content.iter().try_for_each(|part| file.write_all(part))?;
+            file.sync_all()
+        });
+    match written {
+        Ok(()) => Ok(tmp_path),
+        Err(err) => {
+            let _ = fs::remove_file(&tmp_path);
+            Err(format!("Failed to write {}: {}", tmp_path.display(), err))
+        }
+    }
+}
+
+// Created on first use, only readable by root
+fn host_key() -> Result<LessSafeKey, String> {
+    let path = Path::new(STATE_DIR).join(KEY_FILE);
+    let key = match fs::read(&path) {
+        Ok(key) => key,
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(STATE_DIR)
+                .map_err(|e| format!("Failed to create {}: {}", STATE_DIR, e))?;
+            let mut key = [0u8; 32];
+            SystemRandom::new()
+                .fill(&mut key)
+                .map_err(|_| "Failed to generate the token store key".to_string())?;
+            // Linked into place only once it's complete, so nobody reads half a key
+            let tmp_path = write_tmp(&path, &[&key])?;
+            let linked = fs::hard_link(&tmp_path, &path);
+            let _ = fs::remove_file(&tmp_path);
+            match linked {
+                Ok(()) => {}
+                // Another login created it first
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => {}
+                Err(err) => return Err(format!("Failed to create {}: {}", path.display(), err)),
+            }
+            fs::read(&path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?
+        }
+        Err(err) => return Err(format!("Failed to read {}: {}", path.display(), err)),
+    };
+    let key = UnboundKey::new(&CHACHA20_POLY1305, &key)
+        .map_err(|_| format!("Invalid token store key in {}", path.display()))?;
+    Ok(LessSafeKey::new(key))
+}
+
+fn token_dir() -> Result<PathBuf, String> {
+    let dir = Path::new(STATE_DIR).join(TOKEN_DIR);
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&dir)
+        .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    Ok(dir)
+}
+
+// Held while a user's token is read and replaced. GitHub rotates refresh tokens, so only one
+// process may refresh at a time
+fn lock(local_user: &str) -> Result<fs::File, String> {
+    token_path(local_user)?;
+    token_dir()?;
+    state::lock(&format!("{}/.{}.lock", TOKEN_DIR, local_user))
+}
+
+pub fn store(local_user: &str, token: &StoredToken) -> Result<(), String> {
+    let _lock = lock(local_user)?;
+    store_locked(local_user, token)
+}
+
+fn store_locked(local_user: &str, token: &StoredToken) -> Result<(), String> {
+    let path = token_path(local_user)?;
+    let key = host_key()?;
+    let mut nonce = [0u8; NONCE_LEN];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| "Failed to generate a nonce".to_string())?;
+    let mut sealed = serde_json::to_vec(token).map_err(|e| format!("Failed to serialize token: {}", e))?;
+    key.seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::from(local_user.as_bytes()),
+        &mut sealed,
+    )
+    .map_err(|_| "Failed to encrypt token".to_string())?;
+
+    token_dir()?;
+    let tmp_path = write_tmp(&path, &[&nonce, &sealed])?;
+    if let Err(err) = fs::rename(&tmp_path, &path) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(format!("Failed to replace {}: {}", path.display(), err));
+    }
+    state::set_token_info(local_user, Some(state::TokenInfo {
+        stored: chrono::Utc::now().timestamp(),
+        expires_at: token.expires_at,
+    }))
+}
+
+pub fn load(local_user: &str) -> Result<Option<StoredToken>, String> {
+    let path = token_path(local_user)?;
+    let mut data = match fs::read(&path) {
+        Ok(data) => data,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(format!("Failed to read {}: {}", path.display(), err)),
+    };
+    if data.len() < NONCE_LEN {
+        return Err(format!("Token file {} is truncated", path.display()));
+    }
+    let key = host_key()?;
+    let (nonce, sealed) = data.split_at_mut(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| "Invalid nonce".to_string())?;
+    let token = key
+        .open_in_place(nonce, Aad::from(local_user.as_bytes()), sealed)
+        .map_err(|_| format!("Failed to decrypt token {}", path.display()))?;
+    serde_json::from_slice(token)
+        .map(Some)
+        .map_err(|e| format!("Failed to parse token {}: {}", path.display(), e))
+}
+
+// A usable access token of the user, refreshed with the OAuth app when it expired. Tokens that
+// expired for good or were refused a refresh are forgotten
+pub fn access_token(client: &GithubClient, app: Option<&OAuthApp>, local_user: &str) -> Result<Option<String>, String> {
+    let Some(token) = load(local_user)? else {
+        return Ok(None);
+    };
+    if !token.is_expired(chrono::Utc::now().timestamp()) {
+        return Ok(Some(token.access_token));
+    }
+
+    // Another process may have refreshed it while we waited for the lock
+    let _lock = lock(local_user)?;
+    let Some(token) = load(local_user)? else {
+        return Ok(None);
+    };
+    let now = chrono::Utc::now().timestamp();
+    if !token.is_expired(now) {
+        return Ok(Some(token.access_token));
+    }
+    let (Some(refresh_token), Some(app)) = (token.refresh_token(now), app) else {
+        logging::log_to_file(&format!("Stored token of user {} expired", local_user));
+        remove_locked(local_user)?;
+        return Ok(None);
+    };
+    match client.refresh_user_token(refresh_token, app) {
+        Ok(refreshed) => {
+            let refreshed = StoredToken::issued(&refreshed, now);
+            store_locked(local_user, &refreshed)?;
+            logging::debug(&format!("Refreshed stored token of user {}", local_user));
+            Ok(Some(refreshed.access_token))
+        }
+        // Still the token that failed, nobody else can replace it while the lock is held
+        Err(err @ (GithubError::DeviceFlow { .. } | GithubError::Unauthorized(_))) => {
+            logging::log_to_file(&format!("Refreshing the token of user {} failed: {}", local_user, err));
+            remove_locked(local_user)?;
+            Ok(None)
+        }
+        Err(err) => Err(format!("Failed to refresh token: {}", err)),
+    }
+}
+
+// Forgets a token, e.g. once the user's account is removed
+pub fn remove(local_user: &str) -> Result<(), String> {
+    let _lock = lock(local_user)?;
+    remove_locked(local_user)
+}
+
+// Forgets a token GitHub rejected, unless it was replaced in the meantime
+pub fn remove_rejected(local_user: &str, access_token: &str) -> Result<(), String> {
+    let _lock = lock(local_user)?;
+    match load(local_user)? {
+        Some(token) if token.access_token == access_token => remove_locked(local_user),
+        _ => Ok(()),
+    }
+}
+
+fn remove_locked(local_user: &str) -> Result<(), String> {
+    let path = token_path(local_user)?;
+    match fs::remove_file(&path) {
+        Ok(()) => {}
+        Err(err) if err.kind() == ErrorKind::NotFound => {}
+        Err(err) => return Err(format!("Failed to remove {}: {}", path.display(), err)),
+    }
+    state::set_token_info(local_user, None)
+}
@@ -0,0 +1,65 @@
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+
+use totp_rs::{Algorithm, Secret, TOTP};
+
+const STEP: u64 = 30;
+
+// TOTP secrets of local users, one `local_user base32_secret` pair per line, as shown by
+// authenticator apps when enrolling. Codes are 6 digits with SHA-1 and 30 second steps
+#[derive(Debug, Default)]
+pub struct TotpSecrets {
+    entries: Vec<(String, TOTP)>,
+}
+
+impl TotpSecrets {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let metadata = fs::metadata(path).map_err(|e| format!("Failed to read TOTP secrets {}: {}", path, e))?;
+        // Anyone able to read or replace the file can generate the codes
+        if metadata.uid() != unsafe { libc::geteuid() } || metadata.mode() & 0o077 != 0 {
+            return Err(format!("TOTP secrets {} must be owned by root and not accessible by group or others", path));
+        }
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read TOTP secrets {}: {}", path, e))?;
+
+        let mut entries = Vec::new();
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.split_whitespace();
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some(local), Some(secret), None) => {
+                    let totp = parse_secret(secret)
+                        .map_err(|e| format!("Invalid TOTP secret at {}:{}: {}", path, i + 1, e))?;
+                    entries.push((local.to_ascii_lowercase(), totp));
+                }
+                _ => return Err(format!("Invalid TOTP secret entry at {}:{}", path, i + 1)),
+            }
+        }
+
+        Ok(TotpSecrets { entries })
+    }
+
+    pub fn has_secret(&self, local_user: &str) -> bool {
+        self.entries.iter().any(|(local, _)| local == local_user)
+    }
+
+    // The time step the code belongs to, one step of clock drift is accepted either way
+    pub fn verify(&self, local_user: &str, code: &str, now: u64) -> Option<u64> {
+        let (_, totp) = self.entries.iter().find(|(local, _)| local == local_user)?;
+        let code = code.trim();
+        let current = now / STEP;
+        [current, current.saturating_sub(1), current + 1]
+            .into_iter()
+            .find(|step| totp.check(code, step * STEP))
+    }
+}
+
+fn parse_secret(secret: &str) -> Result<TOTP, String> {
+    let secret = Secret::Encoded(secret.to_ascii_uppercase())
+        .to_bytes()
+        .map_err(|_| "not valid base32".to_string())?;
+    TOTP::new(Algorithm::SHA1, 6, 0, STEP, secret).map_err(|e| e.to_string())
+}
@@ -1,9 +1,53 @@
-use std::process::Command;
-use std::path::Path;
+use std::collections::HashMap;
+use std::fs;
+#[cfg(feature = "keys")]
+use std::fs::{File, OpenOptions};
+#[cfg(feature = "keys")]
+use std::io::{ErrorKind, Read, Write};
+#[cfg(feature = "keys")]
+use std::ffi::CString;
+#[cfg(feature = "keys")]
+use std::os::fd::{AsRawFd, FromRawFd};
+#[cfg(feature = "keys")]
+use std::os::unix::ffi::OsStrExt;
+#[cfg(feature = "keys")]
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+#[cfg(feature = "keys")]
+use std::path::{Path, PathBuf};
+#[cfg(any(feature = "accounts", feature = "keys"))]
+use std::sync::RwLock;
+#[cfg(any(feature = "accounts", feature = "keys"))]
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
 
+#[cfg(feature = "accounts")]
+use nix::unistd::Group;
+#[cfg(feature = "accounts")]
+use nix::unistd::geteuid;
+use nix::unistd::User;
+
+use crate::github::GithubRole;
+
+#[cfg(any(feature = "accounts", feature = "keys"))]
 use crate::logging;
+#[cfg(feature = "keys")]
+use crate::github::FixPermissions;
+#[cfg(feature = "keys")]
+use crate::{github, state};
 
-pub fn ensure_user_exists(username: &str, add_sudo: bool) -> Result<bool, String> {
+// Creates the user if needed, with a fixed UID when given, and grants sudo when requested. Existing
+// users are put back into `groups` too, in case they were removed
+#[cfg(feature = "accounts")]
+#[cfg_attr(feature = "trace", tracing::instrument(skip_all, fields(user = username)))]
+pub fn ensure_user_exists(
+    username: &str,
+    sudo: Option<&SudoGrant>,
+    uid: Option<u32>,
+    groups: &[String],
+) -> Result<bool, String> {
+    let _lock = lock_account(username)?;
     // Check if user exists
     let user_exists = Command::new("id")
         .arg(username)
@@ -12,166 +56,1186 @@ pub fn ensure_user_exists(username: &str, add_sudo: bool) -> Result<bool, String
         .unwrap_or(false);
 
     if user_exists {
+        // Group membership is also granted to existing (e.g. mapped) accounts, sudoers files are only
+        // created with the account and then kept up to date with the template
+        match sudo {
+            Some(grant @ SudoGrant::Group(_)) => grant_sudo(username, grant),
+            Some(grant @ SudoGrant::Sudoers(_)) if Path::new(&sudoers_path(username)).exists() => grant_sudo(username, grant),
+            _ => {}
+        }
+        join_groups(username, groups);
         return Ok(true);
     }
 
     logging::log_to_file(&format!("Creating user: {}", username));
     
-    let output = Command::new("sudo")
-        .args([
-            "useradd",
-            "-m",
-            "-s", "/bin/bash",
-            username
-        ])
-        .output()
-        .map_err(|e| format!("Failed to execute useradd: {}", e))?;
+    let uid = uid.map(|uid| uid.to_string());
+    let mut useradd = vec!["useradd", "-m", "-s", "/bin/bash"];
+    if let Some(uid) = &uid {
+        useradd.extend(["-u", uid.as_str()]);
+    }
+    useradd.push(username);
+    run_privileged(&useradd, "create user")?;
 
-    if !output.status.success() {
-        let error = String::from_utf8_lossy(&output.stderr);
-        return Err(format!("Failed to create user: {}", error));
+    if is_dry_run() {
+        // Nothing else can be worked out for an account that was never created
+        logging::log_to_file(&format!(
+            "Dry run: would lock the password and set up ~/.ssh of user {}, then grant {:?} and add to groups {:?}",
+            username, sudo, groups
+        ));
+        return Ok(false);
+    }
+
+    // A half set up account would be taken as ready on the next login, so it is removed again
+    if let Err(err) = set_up_new_user(username, sudo) {
+        logging::log_to_file(&format!("Failed to set up new user {}, removing it: {}", username, err));
+        roll_back_new_user(username);
+        return Err(err);
     }
+    join_groups(username, groups);
 
-    // Create .ssh directory and authorized_keys file using sudo
-    let home_dir = format!("/home/{}", username);
+    Ok(false)
+}
+
+#[cfg(feature = "accounts")]
+fn set_up_new_user(username: &str, sudo: Option<&SudoGrant>) -> Result<(), String> {
+    // GitHub stays the only way in, whatever useradd defaults to on this distro
+    run_privileged(&["usermod", "-L", username], "lock password")?;
+
+    // Create .ssh directory and authorized_keys file
+    let account = lookup_user(username)?;
+    let home_dir = account.dir.to_string_lossy().into_owned();
     let ssh_dir = format!("{}/.ssh", home_dir);
-    
-    // Create .ssh directory with sudo if it doesn't exist
     if !Path::new(&ssh_dir).exists() {
-        let mkdir_output = Command::new("sudo")
-            .args(["mkdir", "-p", &ssh_dir])
-            .output()
-            .map_err(|e| format!("Failed to create .ssh directory: {}", e))?;
-            
-        if !mkdir_output.status.success() {
-            let error = String::from_utf8_lossy(&mkdir_output.stderr);
-            return Err(format!("Failed to create .ssh directory: {}", error));
-        }
+        run_privileged(&["mkdir", "-p", &ssh_dir], "create .ssh directory")?;
     }
-
-    // Create empty authorized_keys file if it doesn't exist
     let auth_keys_path = format!("{}/authorized_keys", ssh_dir);
     if !Path::new(&auth_keys_path).exists() {
-        let touch_output = Command::new("sudo")
-            .args(["touch", &auth_keys_path])
-            .output()
-            .map_err(|e| format!("Failed to create authorized_keys file: {}", e))?;
-            
-        if !touch_output.status.success() {
-            let error = String::from_utf8_lossy(&touch_output.stderr);
-            return Err(format!("Failed to create authorized_keys file: {}", error));
+        run_privileged(&["touch", &auth_keys_path], "create authorized_keys file")?;
+    }
+    run_privileged(&["chmod", "700", &ssh_dir], "set permissions on .ssh directory")?;
+    run_privileged(&["chmod", "600", &auth_keys_path], "set permissions on authorized_keys file")?;
+
+    // Change ownership of home directory and contents to the new user
+    let owner = format!("{}:{}", account.uid, account.gid);
+    run_privileged(&["chown", "-R", &owner, &home_dir], "set ownership")?;
+
+    // Add user to sudoers if requested
+    match sudo {
+        Some(grant) => apply_sudo_grant(username, grant),
+        None => Ok(()),
+    }
+}
+
+// Undoes whatever set_up_new_user got to, the account and its home go last
+#[cfg(feature = "accounts")]
+fn roll_back_new_user(username: &str) {
+    let result = remove_user_from_sudoers(username)
+        .and_then(|_| run_privileged(&["userdel", "-r", username], "remove user"));
+    match result {
+        Ok(()) => logging::log_to_file(&format!("Removed half created user {}", username)),
+        Err(err) => logging::log_to_file(&format!("Failed to roll back creating user {}: {}", username, err)),
+    }
+}
+
+pub fn parse_expiry_days(value: Option<&str>) -> Result<Option<u32>, String> {
+    value
+        .map(|days| match days.parse::<u32>() {
+            Ok(days) if days > 0 => Ok(days),
+            _ => Err(format!("Invalid account_expiry_days: {}", days)),
+        })
+        .transpose()
+}
+
+// Moves the account expiry date `days` ahead, so accounts nobody logs in to expire on their own
+#[cfg(feature = "accounts")]
+pub fn set_account_expiry(username: &str, days: u32) -> Result<(), String> {
+    let expires = chrono::Local::now().date_naive() + chrono::Days::new(u64::from(days));
+    run_privileged(
+        &["usermod", "-e", &expires.format("%Y-%m-%d").to_string(), username],
+        "set account expiry",
+    )
+}
+
+pub fn parse_uid_offset(value: Option<&str>) -> Result<Option<u32>, String> {
+    value
+        .map(|offset| offset.parse::<u32>().map_err(|_| format!("Invalid uid_offset: {}", offset)))
+        .transpose()
+}
+
+// UIDs derived from the GitHub user ID are the same on every host
+pub fn uid_for(offset: u32, github_id: u64) -> Result<u32, String> {
+    u64::from(offset)
+        .checked_add(github_id)
+        .and_then(|uid| u32::try_from(uid).ok())
+        // (uid_t)-1 means "no change" to chown and setuid
+        .filter(|uid| *uid != u32::MAX)
+        .ok_or_else(|| format!("UID for GitHub ID {} is out of range with offset {}", github_id, offset))
+}
+
+// How users created with `auto_create_user=sudoer` or in `sudoer_teams` get sudo
+#[derive(Debug, Clone, PartialEq)]
+pub enum SudoGrant {
+    // A file in /etc/sudoers.d rendered from the template
+    Sudoers(String),
+    // Membership of an existing group that sudo already trusts
+    Group(String),
+}
+
+impl SudoGrant {
+    pub fn from_args(args: &HashMap<String, String>) -> Result<Self, String> {
+        match (args.get("sudo_group"), args.get("sudoers_template")) {
+            (Some(_), Some(_)) => Err("sudo_group and sudoers_template cannot be used together".to_string()),
+            (Some(group), None) if group.is_empty() => Err("sudo_group must not be empty".to_string()),
+            (Some(group), None) => Ok(SudoGrant::Group(group.clone())),
+            (None, template) => Ok(SudoGrant::Sudoers(load_sudoers_template(template.map(|t| t.as_str()))?)),
         }
     }
+}
 
-    // Set proper permissions using sudo
-    let chmod_ssh_output = Command::new("sudo")
-        .args(["chmod", "700", &ssh_dir])
-        .output()
-        .map_err(|e| format!("Failed to set permissions on .ssh directory: {}", e))?;
-        
-    if !chmod_ssh_output.status.success() {
-        let error = String::from_utf8_lossy(&chmod_ssh_output.stderr);
-        return Err(format!("Failed to set permissions on .ssh directory: {}", error));
+// Who gets sudo, either every created user or only members of `sudoer_teams`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SudoPolicy {
+    pub grant: SudoGrant,
+    pub teams: Option<Vec<String>>,
+}
+
+impl SudoPolicy {
+    pub fn from_args(args: &HashMap<String, String>) -> Result<Option<Self>, String> {
+        let teams = args.get("sudoer_teams").map(|teams| {
+            teams
+                .split(',')
+                .map(|t| t.trim().to_string())
+                .filter(|t| !t.is_empty())
+                .collect::<Vec<_>>()
+        });
+        if teams.as_ref().is_some_and(|teams| teams.is_empty()) {
+            return Err("sudoer_teams must list at least one team".to_string());
+        }
+        let sudoer = args.get("auto_create_user").is_some_and(|v| v == "sudoer");
+        if !sudoer && teams.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(SudoPolicy { grant: SudoGrant::from_args(args)?, teams }))
     }
+}
 
-    let chmod_keys_output = Command::new("sudo")
-        .args(["chmod", "600", &auth_keys_path])
-        .output()
-        .map_err(|e| format!("Failed to set permissions on authorized_keys file: {}", e))?;
-        
-    if !chmod_keys_output.status.success() {
-        let error = String::from_utf8_lossy(&chmod_keys_output.stderr);
-        return Err(format!("Failed to set permissions on authorized_keys file: {}", error));
+// Local groups that follow the organization role, from `role_group_map=admin:wheel,member:users`.
+// A role may be listed more than once to get several groups
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RoleGroups {
+    groups: Vec<(GithubRole, String)>,
+}
+
+impl RoleGroups {
+    pub fn parse(value: Option<&str>) -> Result<Self, String> {
+        let mut groups = Vec::new();
+        for pair in value.unwrap_or_default().split(',').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+            let (role, group) = pair
+                .split_once(':')
+                .ok_or_else(|| format!("Invalid role_group_map entry, expected role:group: {}", pair))?;
+            let role = GithubRole::from_arg(role.trim()).ok_or_else(|| format!("Unknown role in role_group_map: {}", role))?;
+            if group.trim().is_empty() {
+                return Err(format!("Missing group in role_group_map entry: {}", pair));
+            }
+            groups.push((role, group.trim().to_string()));
+        }
+        Ok(RoleGroups { groups })
     }
 
-    // Change ownership of home directory and contents to the new user
-    let chown_output = Command::new("sudo")
-        .args([
-            "chown",
-            "-R",
-            &format!("{}:{}", username, username),
-            &home_dir
-        ])
-        .output()
-        .map_err(|e| format!("Failed to execute chown: {}", e))?;
+    // Joins the groups of the role and leaves those it granted for another role, e.g. after a
+    // demotion. Groups the user was already in, or is kept in by `groups`, are never taken away
+    #[cfg(feature = "accounts")]
+    pub fn apply(&self, username: &str, role: GithubRole, keep: &[String]) -> Result<(), String> {
+        let joined: Vec<&String> = self.groups.iter().filter(|(r, _)| *r == role).map(|(_, g)| g).collect();
+        let mut granted = state::role_groups(username)?;
+        // The groups that did change are remembered even when another one fails
+        let mut error = None;
+        for group in &joined {
+            match in_group(username, group).and_then(|member| match member {
+                true => Ok(false),
+                false => add_user_to_group(username, group).map(|_| true),
+            }) {
+                Ok(true) if !granted.contains(group) => granted.push(group.to_string()),
+                Ok(_) => {}
+                Err(err) => {
+                    error.get_or_insert(err);
+                }
+            }
+        }
+        granted.retain(|group| {
+            if joined.contains(&group) {
+                return true;
+            }
+            if keep.contains(group) {
+                return false;
+            }
+            match remove_user_from_group(username, group) {
+                Ok(()) => false,
+                Err(err) => {
+                    error.get_or_insert(err);
+                    true
+                }
+            }
+        });
+        // Nothing was changed to remember
+        if !is_dry_run() {
+            state::set_role_groups(username, granted)?;
+        }
+        error.map_or(Ok(()), Err)
+    }
+}
 
-    if !chown_output.status.success() {
-        let error = String::from_utf8_lossy(&chown_output.stderr);
-        return Err(format!("Failed to set ownership: {}", error));
+// Grants or revokes sudo for an existing user whose privileges follow team membership
+#[cfg(feature = "accounts")]
+#[cfg_attr(feature = "trace", tracing::instrument(skip_all, fields(user = username)))]
+pub fn set_sudo(username: &str, grant: &SudoGrant, privileged: bool) -> Result<(), String> {
+    match (grant, privileged) {
+        (SudoGrant::Sudoers(template), true) => add_user_to_sudoers(username, template),
+        (SudoGrant::Group(group), true) => add_user_to_group(username, group),
+        (SudoGrant::Sudoers(_), false) => remove_user_from_sudoers(username),
+        (SudoGrant::Group(group), false) => remove_user_from_group(username, group),
     }
+}
 
-    // Add user to sudoers if requested
-    if add_sudo {
-        if let Err(err) = add_user_to_sudoers(username) {
-            logging::log_to_file(&format!("Warning: Failed to add user to sudoers: {}", err));
-        } else {
-            logging::log_to_file(&format!("Added user {} to sudoers", username));
+#[cfg(feature = "accounts")]
+fn grant_sudo(username: &str, grant: &SudoGrant) {
+    if let Err(err) = apply_sudo_grant(username, grant) {
+        logging::log_to_file(&format!("Warning: Failed to add user to sudoers: {}", err));
+    }
+}
+
+#[cfg(feature = "accounts")]
+fn apply_sudo_grant(username: &str, grant: &SudoGrant) -> Result<(), String> {
+    match grant {
+        SudoGrant::Sudoers(template) => add_user_to_sudoers(username, template),
+        SudoGrant::Group(group) => add_user_to_group(username, group),
+    }
+}
+
+// A group missing from one login doesn't keep the user out
+#[cfg(feature = "accounts")]
+fn join_groups(username: &str, groups: &[String]) {
+    for group in groups {
+        if let Err(err) = add_user_to_group(username, group) {
+            logging::log_to_file(&format!("Failed to add user {} to group {}: {}", username, group, err));
         }
     }
+}
 
-    Ok(false)
+// Looked up through NSS like the rest of the system does, the primary group counts too
+#[cfg(feature = "accounts")]
+fn in_group(username: &str, group: &str) -> Result<bool, String> {
+    let group = Group::from_name(group)
+        .map_err(|e| format!("Failed to look up group {}: {}", group, e))?
+        .ok_or_else(|| format!("Group {} does not exist", group))?;
+    if group.mem.iter().any(|member| member == username) {
+        return Ok(true);
+    }
+    Ok(lookup_user(username)?.gid == group.gid)
 }
 
-fn add_user_to_sudoers(username: &str) -> Result<(), String> {
+#[cfg(feature = "accounts")]
+fn add_user_to_group(username: &str, group: &str) -> Result<(), String> {
+    if in_group(username, group)? {
+        return Ok(());
+    }
 
-    let sudoers_file = format!("/etc/sudoers.d/{}", username);
-    
+    run_privileged(&["usermod", "-aG", group, username], &format!("add user to group {}", group))?;
+    logging::log_to_file(&format!("Added user {} to group {}", username, group));
+    Ok(())
+}
 
-    if Path::new(&sudoers_file).exists() {
+#[cfg(feature = "accounts")]
+fn remove_user_from_group(username: &str, group: &str) -> Result<(), String> {
+    if !in_group(username, group)? {
         return Ok(());
     }
-    
-	// change this if you would like to use a different sudoers permission
-    let sudoers_content = format!("{}  ALL=(ALL) NOPASSWD:ALL", username);
-    
 
-    let output = Command::new("sudo")
-        .args([
-            "bash", "-c", 
-            &format!("echo '{}' > {} && chmod 0440 {}", 
-                sudoers_content, sudoers_file, sudoers_file)
-        ])
+    run_privileged(&["gpasswd", "-d", username, group], &format!("remove user from group {}", group))?;
+    logging::log_to_file(&format!("Removed user {} from group {}", username, group));
+    Ok(())
+}
+
+#[cfg(feature = "accounts")]
+fn remove_user_from_sudoers(username: &str) -> Result<(), String> {
+    let sudoers_file = sudoers_path(username);
+    if !Path::new(&sudoers_file).exists() {
+        return Ok(());
+    }
+
+    run_privileged(&["rm", "-f", &sudoers_file], "remove sudoers file")?;
+    logging::log_to_file(&format!("Removed user {} from sudoers", username));
+    Ok(())
+}
+
+const DEFAULT_SUDOERS_TEMPLATE: &str = "{user}  ALL=(ALL) NOPASSWD:ALL";
+
+// Reads `sudoers_template`, given inline or as the path of a file, `{user}` is replaced with the username
+pub fn load_sudoers_template(value: Option<&str>) -> Result<String, String> {
+    let template = match value {
+        None => return Ok(DEFAULT_SUDOERS_TEMPLATE.to_string()),
+        Some(path) if path.starts_with('/') => fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read sudoers template {}: {}", path, e))?,
+        Some(inline) => inline.to_string(),
+    };
+    if !template.contains("{user}") {
+        return Err("sudoers_template must contain {user}".to_string());
+    }
+    Ok(template)
+}
+
+// How privileged commands are run when the module is not already root, e.g. from `ghauth` run by
+// an admin. `doas` is for systems without sudo like OpenBSD
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PrivHelper {
+    Sudo,
+    Doas,
+}
+
+impl PrivHelper {
+    pub fn from_arg(value: Option<&str>) -> Result<Self, String> {
+        match value {
+            Some("sudo") | None => Ok(PrivHelper::Sudo),
+            Some("doas") => Ok(PrivHelper::Doas),
+            Some(other) => Err(format!("Invalid priv_helper: {}", other)),
+        }
+    }
+
+    #[cfg(feature = "accounts")]
+    fn program(&self) -> &'static str {
+        match self {
+            PrivHelper::Sudo => "sudo",
+            PrivHelper::Doas => "doas",
+        }
+    }
+}
+
+#[cfg(feature = "accounts")]
+static PRIV_HELPER: RwLock<PrivHelper> = RwLock::new(PrivHelper::Sudo);
+
+#[cfg(feature = "accounts")]
+pub fn set_priv_helper(value: Option<&str>) -> Result<(), String> {
+    let helper = PrivHelper::from_arg(value)?;
+    if let Ok(mut current) = PRIV_HELPER.write() {
+        *current = helper;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "keys")]
+const LOCK_DIR: &str = "/run/ssh_github_auth/locks";
+
+// Exclusive lock on one account across processes, released when dropped, so e.g. two first
+// logins of the same user don't race useradd or overwrite each other's authorized_keys. Without
+// the lock nothing is changed
+#[cfg(feature = "keys")]
+fn lock_account(username: &str) -> Result<File, String> {
+    if username.is_empty() || username.contains('/') || username.starts_with('.') {
+        return Err(format!("Invalid username: {}", username));
+    }
+    let path = format!("{}/{}.lock", LOCK_DIR, username);
+    let file = fs::create_dir_all(LOCK_DIR).and_then(|_| {
+        OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .mode(0o600)
+            .open(&path)
+    });
+    let file = file.map_err(|e| format!("Failed to open account lock {}: {}", path, e))?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(format!("Failed to lock {}: {}", path, std::io::Error::last_os_error()));
+    }
+    Ok(file)
+}
+
+// With `dry_run`, changes to accounts, groups, sudoers and keys are only logged
+#[cfg(any(feature = "accounts", feature = "keys"))]
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+#[cfg(any(feature = "accounts", feature = "keys"))]
+pub fn set_dry_run(enabled: bool) {
+    DRY_RUN.store(enabled, Ordering::Relaxed);
+}
+
+#[cfg(any(feature = "accounts", feature = "keys"))]
+pub fn is_dry_run() -> bool {
+    DRY_RUN.load(Ordering::Relaxed)
+}
+
+// Runs the program directly when already root, the usual case under sshd, and through the
+// helper otherwise
+#[cfg(feature = "accounts")]
+fn privileged(program: &str) -> Command {
+    if geteuid().is_root() {
+        return Command::new(program);
+    }
+    let helper = PRIV_HELPER.read().map(|helper| *helper).unwrap_or(PrivHelper::Sudo);
+    let mut command = Command::new(helper.program());
+    command.arg(program);
+    command
+}
+
+#[cfg(feature = "accounts")]
+fn run_privileged(args: &[&str], what: &str) -> Result<(), String> {
+    if is_dry_run() {
+        logging::log_to_file(&format!("Dry run: would {} with `{}`", what, args.join(" ")));
+        return Ok(());
+    }
+    let (program, args) = args.split_first().ok_or("No command to run")?;
+    let output = privileged(program)
+        .args(args)
         .output()
-        .map_err(|e| format!("Failed to create sudoers file: {}", e))?;
-        
+        .map_err(|e| format!("Failed to {}: {}", what, e))?;
     if !output.status.success() {
         let error = String::from_utf8_lossy(&output.stderr);
-        return Err(format!("Failed to create sudoers file: {}", error));
+        return Err(format!("Failed to {}: {}", what, error));
     }
-    
-    // Verify the sudoers file syntax
-    let visudo_check = Command::new("sudo")
-        .args(["visudo", "-c", "-f", &sudoers_file])
+    Ok(())
+}
+
+#[cfg(feature = "accounts")]
+fn sudoers_path(username: &str) -> String {
+    format!("/etc/sudoers.d/{}", username)
+}
+
+// Writes the user's sudoers file, or replaces it when it was written from another template
+#[cfg(feature = "accounts")]
+fn add_user_to_sudoers(username: &str, template: &str) -> Result<(), String> {
+    let sudoers_file = sudoers_path(username);
+    let mut content = template.replace("{user}", username);
+    if !content.ends_with('\n') {
+        content.push('\n');
+    }
+    let existing = match Path::new(&sudoers_file).exists() {
+        true => Some(read_privileged(&sudoers_file, "read sudoers file")?),
+        false => None,
+    };
+    if existing.as_deref() == Some(content.as_str()) {
+        return Ok(());
+    }
+
+    // Stage and verify the file first, so an invalid template never becomes active.
+    // sudo skips files in sudoers.d whose name contains a dot
+    let staged_file = format!("/etc/sudoers.d/.{}.tmp", username);
+    write_privileged(&staged_file, &content, "write sudoers file")?;
+    let result = run_privileged(&["chmod", "0440", &staged_file], "set permissions on sudoers file")
+        .and_then(|_| run_privileged(&["visudo", "-c", "-f", &staged_file], "verify sudoers file"))
+        .and_then(|_| run_privileged(&["mv", &staged_file, &sudoers_file], "install sudoers file"));
+    if let Err(err) = result {
+        let _ = run_privileged(&["rm", "-f", &staged_file], "remove staged sudoers file");
+        return Err(err);
+    }
+
+    match existing {
+        Some(_) => logging::log_to_file(&format!("Updated sudoers file of user {}", username)),
+        None => logging::log_to_file(&format!("Added user {} to sudoers", username)),
+    }
+    Ok(())
+}
+
+#[cfg(feature = "keys")]
+const MANAGED_BEGIN: &str = "# BEGIN ssh_github_auth managed keys";
+#[cfg(feature = "keys")]
+const MANAGED_END: &str = "# END ssh_github_auth managed keys";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeprovisionMode {
+    Lock,
+    Expire,
+}
+
+impl DeprovisionMode {
+    pub fn from_arg(mode: &str) -> Option<Self> {
+        match mode {
+            "lock" | "" => Some(DeprovisionMode::Lock),
+            "expire" => Some(DeprovisionMode::Expire),
+            _ => None,
+        }
+    }
+}
+
+// What happens to ephemeral accounts when their last session closes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EphemeralMode {
+    Delete,
+    // Keep a tarball of the home directory in the state directory before deleting
+    Archive,
+}
+
+impl EphemeralMode {
+    pub fn from_arg(mode: &str) -> Option<Self> {
+        match mode {
+            "delete" | "" => Some(EphemeralMode::Delete),
+            "archive" => Some(EphemeralMode::Archive),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "accounts")]
+#[cfg_attr(feature = "trace", tracing::instrument(skip_all, fields(user = username)))]
+pub fn remove_user(username: &str, mode: EphemeralMode) -> Result<(), String> {
+    let account = lookup_user(username)?;
+    if mode == EphemeralMode::Archive {
+        let archive_dir = format!("{}/archive", state::STATE_DIR);
+        let archive = format!(
+            "{}/{}-{}.tar.gz",
+            archive_dir,
+            username,
+            chrono::Utc::now().format("%Y%m%dT%H%M%SZ")
+        );
+        run_privileged(&["mkdir", "-p", &archive_dir], "create archive directory")?;
+        run_privileged(
+            &["tar", "-czf", &archive, "-C", &account.dir.to_string_lossy(), "."],
+            "archive home directory",
+        )?;
+        logging::log_to_file(&format!("Archived home directory of {} to {}", username, archive));
+    }
+
+    // Leftover processes would keep userdel from removing the account
+    let _ = run_privileged(&["pkill", "-KILL", "-u", username], "stop user processes");
+    run_privileged(&["userdel", "-r", username], "remove user")?;
+    remove_user_from_sudoers(username)?;
+    state::forget_user(username)?;
+
+    logging::log_to_file(&format!("Removed ephemeral user {} ({:?})", username, mode));
+    Ok(())
+}
+
+// Opens a URL in the desktop session of a user logging in at the console, running as the user.
+// `display` is the X display when the session has one, Wayland sessions are reached over D-Bus
+pub fn open_url(username: &str, url: &str, display: Option<&str>) -> Result<(), String> {
+    let account = lookup_user(username)?;
+    let runtime_dir = format!("/run/user/{}", account.uid);
+    let mut failures = Vec::new();
+    for (program, args) in [("xdg-open", &[][..]), ("gio", &["open"][..])] {
+        let mut command = Command::new(program);
+        // Supplementary groups are dropped along with root's
+        command
+            .args(args)
+            .arg(url)
+            .uid(account.uid.as_raw())
+            .gid(account.gid.as_raw())
+            .current_dir(&account.dir)
+            .env_clear()
+            .env("HOME", &account.dir)
+            .env("USER", username)
+            .env("PATH", "/usr/local/bin:/usr/bin:/bin")
+            .env("XDG_RUNTIME_DIR", &runtime_dir)
+            .env("DBUS_SESSION_BUS_ADDRESS", format!("unix:path={}/bus", runtime_dir))
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        if let Some(display) = display {
+            command.env("DISPLAY", display);
+        }
+        match command.spawn() {
+            Ok(mut child) => {
+                // Reaped in the background, the browser may outlive the login
+                thread::spawn(move || child.wait());
+                return Ok(());
+            }
+            Err(err) => failures.push(format!("{}: {}", program, err)),
+        }
+    }
+    Err(format!("Failed to open {}: {}", url, failures.join(", ")))
+}
+
+// Looks the user up in the passwd database, so NSS users (LDAP, NIS) and nonstandard homes work
+fn lookup_user(username: &str) -> Result<User, String> {
+    User::from_name(username)
+        .map_err(|e| format!("Failed to look up user {}: {}", username, e))?
+        .ok_or_else(|| format!("User {} not found in the passwd database", username))
+}
+
+// `authorized_keys_path` of the calling service, set once per PAM call like the log settings
+#[cfg(feature = "keys")]
+static KEYS_PATH: RwLock<Option<String>> = RwLock::new(None);
+
+#[cfg(feature = "keys")]
+pub fn set_authorized_keys_path(pattern: Option<&str>) {
+    if let Ok(mut path) = KEYS_PATH.write() {
+        *path = pattern.map(|p| p.to_string());
+    }
+}
+
+// Expands the tokens of sshd's AuthorizedKeysFile: %u the username, %h the home directory, %U
+// the UID and %% a percent sign. Relative paths are in the home directory, like for sshd
+#[cfg(feature = "keys")]
+pub fn expand_keys_path(pattern: &str, username: &str, home: &str, uid: u32) -> Result<String, String> {
+    let mut path = String::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            path.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('u') => path.push_str(username),
+            Some('h') => path.push_str(home),
+            Some('U') => path.push_str(&uid.to_string()),
+            Some('%') => path.push('%'),
+            other => {
+                return Err(format!(
+                    "Invalid authorized_keys_path {}: unknown token %{}",
+                    pattern,
+                    other.map(String::from).unwrap_or_default()
+                ));
+            }
+        }
+    }
+    if path.starts_with('/') {
+        Ok(path)
+    } else {
+        Ok(format!("{}/{}", home.trim_end_matches('/'), path))
+    }
+}
+
+#[cfg(feature = "keys")]
+fn authorized_keys_path(username: &str) -> Result<(User, String), String> {
+    let account = lookup_user(username)?;
+    let pattern = KEYS_PATH.read().ok().and_then(|path| path.clone());
+    let path = expand_keys_path(
+        pattern.as_deref().unwrap_or("%h/.ssh/authorized_keys"),
+        username,
+        &account.dir.to_string_lossy(),
+        account.uid.as_raw(),
+    )?;
+    Ok((account, path))
+}
+
+// sshd's StrictModes ignores authorized_keys when it or a directory above it, up to the home
+// directory, is writable by group or others or owned by someone else than the user or root.
+// Checked before keys are written
+#[cfg(feature = "keys")]
+pub fn check_strict_modes(username: &str, fix: FixPermissions) -> Result<(), String> {
+    if fix == FixPermissions::Ignore {
+        return Ok(());
+    }
+    let (account, keys_path) = authorized_keys_path(username)?;
+    check_strict_modes_at(&account.dir, &keys_path, account.uid.as_raw(), username, fix)
+}
+
+// check_strict_modes for the keys file of the user with this UID and home directory
+#[cfg(feature = "keys")]
+pub fn check_strict_modes_at(
+    home: &Path,
+    keys_path: &str,
+    uid: u32,
+    username: &str,
+    fix: FixPermissions,
+) -> Result<(), String> {
+    // Only the directory holding the file when it is outside the home directory
+    let mut paths = vec![PathBuf::from(keys_path)];
+    for dir in Path::new(keys_path).ancestors().skip(1) {
+        paths.push(dir.to_path_buf());
+        if !dir.starts_with(home) || dir == home {
+            break;
+        }
+    }
+    for path in paths {
+        // The user controls everything below the home directory, so nothing there is followed:
+        // a link to another user's file would otherwise be checked and handed over in its place
+        let below_home = path.starts_with(home) && path != home;
+        let opened = if below_home {
+            open_below(home, &path)
+        } else {
+            open_nofollow(&path)
+        };
+        let file = match opened {
+            Ok(file) => file,
+            // Created with the right owner and mode when keys are first imported
+            Err(err) if err.kind() == ErrorKind::NotFound => continue,
+            Err(err) if err.raw_os_error() == Some(libc::ELOOP) => {
+                return Err(format!(
+                    "{} is or goes through a symbolic link, refusing to write keys there",
+                    path.display()
+                ));
+            }
+            Err(err) => {
+                logging::debug(&format!("Can't check the permissions of {}: {}", path.display(), err));
+                continue;
+            }
+        };
+        // Checked and fixed on the opened file, which can't be swapped in between
+        let metadata = file
+            .metadata()
+            .map_err(|e| format!("Failed to check {}: {}", path.display(), e))?;
+        let foreign_owner = metadata.uid() != uid && metadata.uid() != 0;
+        let writable = metadata.mode() & 0o022 != 0;
+        let problem = match (foreign_owner, writable) {
+            (false, false) => continue,
+            (true, false) => format!("{} is owned by UID {}", path.display(), metadata.uid()),
+            (false, true) => format!("{} is writable by group or others", path.display()),
+            (true, true) => format!(
+                "{} is owned by UID {} and writable by group or others",
+                path.display(),
+                metadata.uid()
+            ),
+        };
+        if fix == FixPermissions::Refuse {
+            return Err(format!(
+                "{}, sshd's StrictModes would ignore the keys. Fix it or set fix_permissions=fix",
+                problem
+            ));
+        }
+        // Files outside the home directory are never given to the user
+        if foreign_owner && !path.starts_with(home) {
+            return Err(format!("{}, not changing owners outside the home directory of {}", problem, username));
+        }
+        if is_dry_run() {
+            logging::log_to_file(&format!("Dry run: would fix for StrictModes: {}", problem));
+            continue;
+        }
+        if foreign_owner {
+            std::os::unix::fs::fchown(&file, Some(uid), None)
+                .map_err(|e| format!("Failed to fix ownership of {}: {}", path.display(), e))?;
+        }
+        if writable {
+            file.set_permissions(fs::Permissions::from_mode(metadata.mode() & 0o7755))
+                .map_err(|e| format!("Failed to fix permissions of {}: {}", path.display(), e))?;
+        }
+        logging::log_to_file(&format!("Fixed for StrictModes: {}", problem));
+    }
+    Ok(())
+}
+
+// Opens a file or directory for checking its owner and mode, never through a symbolic link
+#[cfg(feature = "keys")]
+fn open_nofollow(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK | libc::O_CLOEXEC)
+        .open(path)
+}
+
+// Opens a path below the home directory one component at a time, none of them may be a
+// symbolic link. Fails with ELOOP when one is
+#[cfg(feature = "keys")]
+fn open_below(home: &Path, path: &Path) -> std::io::Result<File> {
+    let relative = path
+        .strip_prefix(home)
+        .map_err(|_| std::io::Error::from_raw_os_error(libc::EINVAL))?;
+    let mut current = File::open(home)?;
+    let mut components = relative.components().peekable();
+    while let Some(component) = components.next() {
+        let name = CString::new(component.as_os_str().as_bytes())
+            .map_err(|_| std::io::Error::from_raw_os_error(libc::EINVAL))?;
+        // Everything but the last part has to be a directory
+        let directory = if components.peek().is_some() { libc::O_DIRECTORY } else { 0 };
+        let fd = unsafe {
+            libc::openat(
+                current.as_raw_fd(),
+                name.as_ptr(),
+                libc::O_RDONLY | libc::O_NOFOLLOW | libc::O_NONBLOCK | libc::O_CLOEXEC | directory,
+            )
+        };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        current = unsafe { File::from_raw_fd(fd) };
+    }
+    Ok(current)
+}
+
+#[cfg(feature = "keys")]
+fn read_authorized_keys(username: &str) -> Result<String, String> {
+    let (account, auth_keys_path) = authorized_keys_path(username)?;
+    read_keys_at(&account.dir, &auth_keys_path)
+}
+
+// Files only root may read, e.g. under /etc/sudoers.d
+#[cfg(feature = "accounts")]
+fn read_privileged(path: &str, what: &str) -> Result<String, String> {
+    let output = privileged("cat")
+        .args([path])
         .output()
-        .map_err(|e| format!("Failed to verify sudoers file: {}", e))?;
-    
-    if !visudo_check.status.success() {
-        // If the syntax check failed, remove the file and return an error
-        let _ = Command::new("sudo")
-            .args(["rm", "-f", &sudoers_file])
-            .status();
-        let error = String::from_utf8_lossy(&visudo_check.stderr);
-        return Err(format!("Invalid sudoers syntax: {}", error));
+        .map_err(|e| format!("Failed to {}: {}", what, e))?;
+
+    if !output.status.success() {
+        let error = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("Failed to {}: {}", what, error));
     }
-    
-    logging::log_to_file(&format!("Created sudoers file for {}", username));
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(feature = "keys")]
+fn write_authorized_keys(username: &str, content: &str) -> Result<(), String> {
+    let (account, auth_keys_path) = authorized_keys_path(username)?;
+    write_keys_at(&account.dir, &auth_keys_path, content, account.uid.as_raw(), account.gid.as_raw())
+}
+
+// Writes a file as root through `tee`, only for directories the user can't change
+#[cfg(feature = "accounts")]
+#[cfg_attr(feature = "trace", tracing::instrument(skip_all, fields(path = path)))]
+fn write_privileged(path: &str, content: &str, what: &str) -> Result<(), String> {
+    if is_dry_run() {
+        logging::log_to_file(&format!("Dry run: would {} {}:\n{}", what, path, content.trim_end()));
+        return Ok(());
+    }
+    let mut child = privileged("tee")
+        .args([path])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to {}: {}", what, e))?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(content.as_bytes())
+            .map_err(|e| format!("Failed to {}: {}", what, e))?;
+    }
+
+    let output = child
+        .wait_with_output()
+        .map_err(|e| format!("Failed to {}: {}", what, e))?;
+
+    if !output.status.success() {
+        let error = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("Failed to {}: {}", what, error));
+    }
+
     Ok(())
 }
 
-pub fn add_authorized_key(username: &str, key: &str) -> Result<(), String> {
-	let ssh_dir = format!("/home/{}/.ssh", username);
-	let auth_keys_path = format!("{}/authorized_keys", ssh_dir);
+// Opens the directory holding the keys file and splits off the file name, None when the
+// directory is missing. Below the home directory nothing is followed, the user could swap any
+// part for a link after it was checked
+#[cfg(feature = "keys")]
+fn open_keys_dir(home: &Path, keys_path: &str) -> Result<Option<(File, CString)>, String> {
+    let path = Path::new(keys_path);
+    let (dir, name) = match (path.parent(), path.file_name()) {
+        (Some(dir), Some(name)) => (dir, name),
+        _ => return Err(format!("Invalid authorized_keys path {}", keys_path)),
+    };
+    let opened = match dir.starts_with(home) && dir != home {
+        true => open_below(home, dir),
+        false => File::open(dir),
+    };
+    let dir_file = match opened {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) if err.raw_os_error() == Some(libc::ELOOP) => {
+            return Err(format!(
+                "{} is or goes through a symbolic link, refusing to use it for keys",
+                dir.display()
+            ));
+        }
+        Err(err) if err.raw_os_error() == Some(libc::ENOTDIR) => {
+            return Err(format!("{} is not a directory, refusing to use it for keys", dir.display()));
+        }
+        Err(err) => return Err(format!("Failed to open {}: {}", dir.display(), err)),
+    };
+    let name = CString::new(name.as_bytes()).map_err(|_| format!("Invalid authorized_keys path {}", keys_path))?;
+    Ok(Some((dir_file, name)))
+}
+
+#[cfg(feature = "keys")]
+fn openat(dir: &File, name: &CString, flags: libc::c_int, mode: libc::mode_t) -> std::io::Result<File> {
+    let fd = unsafe { libc::openat(dir.as_raw_fd(), name.as_ptr(), flags | libc::O_NOFOLLOW | libc::O_CLOEXEC, mode) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
 
-	// Append the key to the authorized_keys file
-	let output = Command::new("sudo")
-		.args(["bash", "-c", &format!("echo '{}' >> {}", key, auth_keys_path)])
-		.output()
-		.map_err(|e| format!("Failed to add key to authorized_keys: {}", e))?;
+// Reads the keys file at `keys_path` of the user with this home directory, through the opened
+// directory so no link is followed. A missing file has no keys
+#[cfg(feature = "keys")]
+pub fn read_keys_at(home: &Path, keys_path: &str) -> Result<String, String> {
+    let Some((dir, name)) = open_keys_dir(home, keys_path)? else {
+        return Ok(String::new());
+    };
+    let mut file = match openat(&dir, &name, libc::O_RDONLY | libc::O_NONBLOCK, 0) {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(String::new()),
+        Err(err) if err.raw_os_error() == Some(libc::ELOOP) => {
+            return Err(format!("{} is a symbolic link, refusing to use it for keys", keys_path));
+        }
+        Err(err) => return Err(format!("Failed to read {}: {}", keys_path, err)),
+    };
+    let metadata = file.metadata().map_err(|e| format!("Failed to read {}: {}", keys_path, e))?;
+    if !metadata.is_file() {
+        return Err(format!("{} is not a regular file, refusing to use it for keys", keys_path));
+    }
+    let mut content = Vec::new();
+    file.read_to_end(&mut content)
+        .map_err(|e| format!("Failed to read {}: {}", keys_path, e))?;
+    Ok(String::from_utf8_lossy(&content).into_owned())
+}
 
-	if !output.status.success() {
-		let error = String::from_utf8_lossy(&output.stderr);
-		return Err(format!("Failed to add key to authorized_keys: {}", error));
-	}
+// Replaces the keys file at `keys_path` through the opened directory: the new content goes to a
+// fresh file next to it, which is then renamed over it. The owner and mode of the old file are
+// kept, a new file below the home directory belongs to `uid` and `gid`
+#[cfg(feature = "keys")]
+#[cfg_attr(feature = "trace", tracing::instrument(skip_all, fields(path = keys_path)))]
+pub fn write_keys_at(home: &Path, keys_path: &str, content: &str, uid: u32, gid: u32) -> Result<(), String> {
+    if is_dry_run() {
+        logging::log_to_file(&format!("Dry run: would write authorized_keys {}:\n{}", keys_path, content.trim_end()));
+        return Ok(());
+    }
+    let (dir, name) = open_keys_dir(home, keys_path)?
+        .ok_or_else(|| format!("Failed to write {}: the directory does not exist", keys_path))?;
+    let (owner, group, mode) = match openat(&dir, &name, libc::O_RDONLY | libc::O_NONBLOCK, 0) {
+        Ok(file) => {
+            let metadata = file.metadata().map_err(|e| format!("Failed to check {}: {}", keys_path, e))?;
+            if !metadata.is_file() {
+                return Err(format!("{} is not a regular file, refusing to use it for keys", keys_path));
+            }
+            (metadata.uid(), metadata.gid(), metadata.mode() & 0o7777)
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => match Path::new(keys_path).starts_with(home) {
+            true => (uid, gid, 0o600),
+            false => (0, 0, 0o644),
+        },
+        Err(err) if err.raw_os_error() == Some(libc::ELOOP) => {
+            return Err(format!("{} is a symbolic link, refusing to use it for keys", keys_path));
+        }
+        Err(err) => return Err(format!("Failed to open {}: {}", keys_path, err)),
+    };
+
+    let mut staged_name = b".".to_vec();
+    staged_name.extend_from_slice(name.as_bytes());
+    staged_name.extend_from_slice(format!(".{}.tmp", std::process::id()).as_bytes());
+    let staged_name = CString::new(staged_name).map_err(|_| format!("Invalid authorized_keys path {}", keys_path))?;
+    let mut staged = openat(&dir, &staged_name, libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL, 0o600)
+        .map_err(|e| format!("Failed to write {}: {}", keys_path, e))?;
+    let result = staged
+        .write_all(content.as_bytes())
+        .and_then(|_| std::os::unix::fs::fchown(&staged, Some(owner), Some(group)))
+        .and_then(|_| staged.set_permissions(fs::Permissions::from_mode(mode)))
+        .and_then(|_| staged.sync_all())
+        .and_then(|_| {
+            let renamed = unsafe {
+                libc::renameat(dir.as_raw_fd(), staged_name.as_ptr(), dir.as_raw_fd(), name.as_ptr())
+            };
+            match renamed {
+                0 => Ok(()),
+                _ => Err(std::io::Error::last_os_error()),
+            }
+        });
+    if let Err(err) = result {
+        unsafe { libc::unlinkat(dir.as_raw_fd(), staged_name.as_ptr(), 0) };
+        return Err(format!("Failed to write {}: {}", keys_path, err));
+    }
+    Ok(())
+}
+
+// Replaces the block of keys managed by this module, leaving other keys untouched
+#[cfg(feature = "keys")]
+fn replace_managed_block(content: &str, keys: &str) -> String {
+    let mut result = String::new();
+    let mut in_block = false;
+    for line in content.lines() {
+        if line == MANAGED_BEGIN {
+            in_block = true;
+        } else if line == MANAGED_END {
+            in_block = false;
+        } else if !in_block {
+            result.push_str(line);
+            result.push('\n');
+        }
+    }
+
+    let keys: Vec<&str> = keys.lines().map(|k| k.trim()).filter(|k| !k.is_empty()).collect();
+    if !keys.is_empty() {
+        result.push_str(MANAGED_BEGIN);
+        result.push('\n');
+        for key in keys {
+            result.push_str(key);
+            result.push('\n');
+        }
+        result.push_str(MANAGED_END);
+        result.push('\n');
+    }
+
+    result
+}
+
+// Lines of the managed block, the keys last imported from GitHub
+#[cfg(feature = "keys")]
+pub fn managed_keys(username: &str) -> Result<Vec<String>, String> {
+    let content = read_authorized_keys(username)?;
+    Ok(content
+        .lines()
+        .skip_while(|line| *line != MANAGED_BEGIN)
+        .skip(1)
+        .take_while(|line| *line != MANAGED_END)
+        .map(|line| line.to_string())
+        .collect())
+}
+
+#[cfg(feature = "keys")]
+pub fn has_managed_keys(username: &str) -> Result<bool, String> {
+    let content = read_authorized_keys(username)?;
+    Ok(content.lines().any(|line| line == MANAGED_BEGIN))
+}
 
-	Ok(())
-}
\ No newline at end of file
+#[cfg(feature = "keys")]
+#[cfg_attr(feature = "trace", tracing::instrument(skip_all, fields(user = username)))]
+pub fn set_managed_keys(username: &str, keys: &str) -> Result<(), String> {
+    let _lock = lock_account(username)?;
+    let content = read_authorized_keys(username)?;
+    write_authorized_keys(username, &replace_managed_block(&content, keys))?;
+    state::set_imported_keys(username, keys.lines().filter_map(github::key_fingerprint).collect())
+}
+
+// Like set_managed_keys, also removing the copies of imported keys outside the block, e.g. keys
+// older versions of the module appended without one. Keys in the block now or imported before
+// are removed there, so a key deleted on GitHub goes everywhere. None keeps the block as it is.
+// Returns how many lines were removed
+#[cfg(feature = "keys")]
+#[cfg_attr(feature = "trace", tracing::instrument(skip_all, fields(user = username)))]
+pub fn set_managed_keys_pruned(username: &str, keys: Option<&str>) -> Result<usize, String> {
+    let _lock = lock_account(username)?;
+    let content = read_authorized_keys(username)?;
+    let block = match keys {
+        Some(keys) => keys.to_string(),
+        None => managed_keys(username)?.join("\n"),
+    };
+    let mut known: Vec<String> = block.lines().filter_map(github::key_fingerprint).collect();
+    known.extend(state::user_record(username)?.map(|record| record.keys).unwrap_or_default());
+    let (outside, pruned) = without_keys(&content, &known);
+    if keys.is_none() && pruned == 0 {
+        return Ok(0);
+    }
+    write_authorized_keys(username, &replace_managed_block(&outside, &block))?;
+    if pruned > 0 {
+        logging::log_to_file(&format!("Pruned {} stale keys from authorized_keys of {}", pruned, username));
+    }
+    state::set_imported_keys(username, block.lines().filter_map(github::key_fingerprint).collect())?;
+    Ok(pruned)
+}
+
+// Drops the lines outside the managed block holding one of the keys with these fingerprints,
+// with the number of lines dropped
+#[cfg(feature = "keys")]
+pub fn without_keys(content: &str, fingerprints: &[String]) -> (String, usize) {
+    let mut result = String::new();
+    let mut in_block = false;
+    let mut dropped = 0;
+    for line in content.lines() {
+        if line == MANAGED_BEGIN {
+            in_block = true;
+        } else if line == MANAGED_END {
+            in_block = false;
+        } else if !in_block && github::key_fingerprint(line).is_some_and(|fp| fingerprints.contains(&fp)) {
+            dropped += 1;
+            continue;
+        }
+        result.push_str(line);
+        result.push('\n');
+    }
+    (result, dropped)
+}
+
+// Locks or expires an account whose GitHub membership was revoked and removes its managed keys.
+// Sudo and the role groups the module granted go too, so sessions still open and keys outside
+// the managed block keep no privileges
+#[cfg(feature = "accounts")]
+#[cfg_attr(feature = "trace", tracing::instrument(skip_all, fields(user = username)))]
+pub fn deprovision_user(username: &str, mode: DeprovisionMode, sudo: Option<&SudoGrant>) -> Result<(), String> {
+    // A locked password alone doesn't stop key logins, the expired account does
+    let mut usermod = vec!["usermod", "-e", "1"];
+    if mode == DeprovisionMode::Lock {
+        usermod.extend(["-L", "-s", nologin_shell()]);
+    }
+    usermod.push(username);
+    run_privileged(&usermod, "deprovision user")?;
+
+    set_managed_keys(username, "")?;
+    remove_user_from_sudoers(username)?;
+    if let Some(SudoGrant::Group(group)) = sudo {
+        remove_user_from_group(username, group)?;
+    }
+    revoke_role_groups(username)?;
+
+    logging::log_to_file(&format!("Deprovisioned user {} ({:?})", username, mode));
+    Ok(())
+}
+
+#[cfg(feature = "accounts")]
+fn nologin_shell() -> &'static str {
+    ["/usr/sbin/nologin", "/sbin/nologin"]
+        .into_iter()
+        .find(|shell| Path::new(shell).exists())
+        .unwrap_or("/usr/sbin/nologin")
+}
+
+// Leaves every group role_group_map granted, those that fail are remembered for the next attempt
+#[cfg(feature = "accounts")]
+fn revoke_role_groups(username: &str) -> Result<(), String> {
+    let mut error = None;
+    let mut granted = state::role_groups(username)?;
+    granted.retain(|group| match remove_user_from_group(username, group) {
+        Ok(()) => false,
+        Err(err) => {
+            error.get_or_insert(err);
+            true
+        }
+    });
+    if !is_dry_run() {
+        state::set_role_groups(username, granted)?;
+    }
+    error.map_or(Ok(()), Err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn role_groups_parse_pairs() {
+        let groups = RoleGroups::parse(Some(" admin:wheel, member:users,admin:docker ,")).unwrap();
+        assert_eq!(groups.groups, [
+            (GithubRole::Admin, "wheel".to_string()),
+            (GithubRole::Member, "users".to_string()),
+            (GithubRole::Admin, "docker".to_string()),
+        ]);
+        assert_eq!(RoleGroups::parse(None).unwrap(), RoleGroups::default());
+        assert_eq!(RoleGroups::parse(Some("")).unwrap(), RoleGroups::default());
+    }
+
+    #[test]
+    fn invalid_role_groups_are_rejected() {
+        let error = |value: &str| RoleGroups::parse(Some(value)).unwrap_err();
+        assert_eq!(error("admin"), "Invalid role_group_map entry, expected role:group: admin");
+        assert_eq!(error("owner:wheel"), "Unknown role in role_group_map: owner");
+        assert_eq!(error("member:users,admin: "), "Missing group in role_group_map entry: admin:");
+    }
+
+    #[cfg(feature = "keys")]
+    #[test]
+    fn keys_paths_expand_tokens() {
+        let expand = |pattern: &str| expand_keys_path(pattern, "alice", "/home/alice", 1001);
+        assert_eq!(expand("/etc/ssh/keys/%u").unwrap(), "/etc/ssh/keys/alice");
+        assert_eq!(expand("%h/.ssh/authorized_keys").unwrap(), "/home/alice/.ssh/authorized_keys");
+        assert_eq!(expand("/var/keys/%U/%u%%").unwrap(), "/var/keys/1001/alice%");
+        assert_eq!(expand("/var/keys/%%u").unwrap(), "/var/keys/%u");
+    }
+
+    #[cfg(feature = "keys")]
+    #[test]
+    fn relative_keys_paths_are_in_the_home_directory() {
+        assert_eq!(expand_keys_path(".ssh/keys_%u", "bob", "/home/bob", 7).unwrap(), "/home/bob/.ssh/keys_bob");
+        assert_eq!(expand_keys_path(".ssh/keys", "bob", "/home/bob/", 7).unwrap(), "/home/bob/.ssh/keys");
+        assert_eq!(expand_keys_path(".ssh/keys", "root", "/", 0).unwrap(), "/.ssh/keys");
+    }
+
+    #[cfg(feature = "keys")]
+    #[test]
+    fn unknown_keys_path_tokens_are_rejected() {
+        assert_eq!(
+            expand_keys_path("/keys/%d", "bob", "/home/bob", 7),
+            Err("Invalid authorized_keys_path /keys/%d: unknown token %d".to_string())
+        );
+        assert_eq!(
+            expand_keys_path("/keys/%", "bob", "/home/bob", 7),
+            Err("Invalid authorized_keys_path /keys/%: unknown token %".to_string())
+        );
+    }
+}
@@ -0,0 +1,60 @@
+use std::fs;
+
+// Maps local usernames to GitHub logins, one `local_user github_login` pair per line.
+// A local user may be listed several times to accept more than one GitHub login.
+#[derive(Debug, Default)]
+pub struct UserMap {
+    entries: Vec<(String, String)>,
+}
+
+impl UserMap {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read user map {}: {}", path, e))?;
+
+        let mut entries = Vec::new();
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.split_whitespace();
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some(local), Some(github), None) => {
+                    entries.push((local.to_ascii_lowercase(), github.to_ascii_lowercase()));
+                }
+                _ => return Err(format!("Invalid user map entry at {}:{}", path, i + 1)),
+            }
+        }
+
+        Ok(UserMap { entries })
+    }
+
+    // Unmapped local users are expected to have the same GitHub login
+    pub fn github_logins(&self, local_user: &str) -> Vec<String> {
+        let logins = self.mapped_logins(local_user);
+        if logins.is_empty() {
+            vec![local_user.to_string()]
+        } else {
+            logins
+        }
+    }
+
+    // Only the logins the map lists for the user
+    pub fn mapped_logins(&self, local_user: &str) -> Vec<String> {
+        self.entries
+            .iter()
+            .filter(|(local, _)| local == local_user)
+            .map(|(_, github)| github.clone())
+            .collect()
+    }
+
+    pub fn local_user(&self, github_login: &str) -> String {
+        let github_login = github_login.to_ascii_lowercase();
+        self.entries
+            .iter()
+            .find(|(_, github)| *github == github_login)
+            .map(|(local, _)| local.clone())
+            .unwrap_or(github_login)
+    }
+}